@@ -1,11 +1,11 @@
-use std::{fmt::Display, time::Instant};
+use std::{fmt::Display, time::{Duration, Instant}};
 
 use log::{debug, error, info, trace};
 use regex::Regex;
-use teloxide::{prelude::Requester, repls::CommandReplExt, types::{ChatId, Message, ParseMode, User, UserId}, utils::{command::BotCommands, markdown}, Bot};
-use teloxide::payloads::SendMessageSetters;
+use teloxide::{dispatching::{Dispatcher, HandlerExt, UpdateFilterExt}, dptree, prelude::Requester, types::{CallbackQuery, ChatId, InlineKeyboardButton, InlineKeyboardMarkup, InlineQuery, InlineQueryResult, InlineQueryResultArticle, InputFile, InputMessageContent, InputMessageContentText, Message, MessageId, ParseMode, Update, User, UserId}, utils::{command::BotCommands, markdown}, Bot};
+use teloxide::payloads::{AnswerCallbackQuerySetters, AnswerInlineQuerySetters, EditMessageTextSetters, SendMessageSetters, SendVoiceSetters};
 use power_pizza_bot::{bot::strings::HELP_MESSAGE, config::CONFIG};
-use power_pizza_bot::{bot::{BotError, BotUser}, db::DB};
+use power_pizza_bot::{bot::{clip::episode_clip, pages::{self, Callback, Entry, StoredQuery, StoredRanking}, format::{episode_link, episode_url, format_range, format_time, parse_range}, check_user_regex, first_mentions, text_query_pattern, BotError, Filters, Query, QueryError, BotUser, EpisodeOffsetMatch, RankedResult, SearchError, SearchMode}, db::DB, spreaker::Episode, transcript::{EpisodeSummary, EpisodeTranscript, ExportFormat}};
 
 #[tokio::main]
 async fn main() {
@@ -15,7 +15,47 @@ async fn main() {
 
     let bot = Bot::new(CONFIG.tg.token.clone());
     log::info!("bot created, startring...");
-    Command::repl(bot, reply).await;
+    let handler = dptree::entry()
+        .branch(Update::filter_message().filter_command::<Command>().endpoint(reply))
+        .branch(Update::filter_inline_query().endpoint(inline_query))
+        .branch(Update::filter_callback_query().endpoint(callback_query));
+    Dispatcher::builder(bot, handler)
+        .enable_ctrlc_handler()
+        .build()
+        .dispatch()
+        .await;
+}
+
+/// Seconds telegram may cache an inline answer for. Answers depend on the beta whitelist, so they
+/// are cached per user.
+static INLINE_CACHE_TIME: u32 = 300;
+
+async fn inline_query(bot: Bot, q: InlineQuery) -> Result<(), teloxide::RequestError> {
+    info!("answering inline query {:?} from {}", q.query, represent_user(&Some(q.from.clone())));
+    let results = match DB.whitelisted(q.from.id.0 as i64).await {
+        Ok(true) if q.query.trim().len() >= 3 => match DB.inline_results(&q.query).await {
+            Ok(r) => r,
+            Err(e) => {
+                error!("failed to answer inline query {:?}: {:?}", q.query, e);
+                vec![]
+            }
+        },
+        Ok(true) => vec![],
+        Ok(false) => vec![InlineQueryResult::Article(InlineQueryResultArticle::new(
+            "beta",
+            "Bot in beta",
+            InputMessageContent::Text(InputMessageContentText::new("Il bot è in beta, usa /beta in chat privata per richiedere l'accesso.")),
+        ))],
+        Err(e) => {
+            error!("failed to check whitelist for inline query: {:?}", e);
+            vec![]
+        }
+    };
+    bot.answer_inline_query(q.id, results)
+        .cache_time(INLINE_CACHE_TIME)
+        .is_personal(true)
+        .await?;
+    Ok(())
 }
 
 fn represent_user(u: &Option<User>) -> String {
@@ -36,6 +76,18 @@ enum Command {
     SearchAdvanced(String),
     #[command(rename = "sae", aliases = ["searchAdvancedEpisode", "cercaAvanzatoEpisodio", "cae"])]
     SearchAdvancedEpisode(String),
+    #[command(rename = "sem", aliases = ["semantic", "argomento"])]
+    Semantic(String),
+    #[command(rename = "mentions", aliases = ["mention", "menzioni", "gioco"])]
+    Mentions(String),
+    #[command(rename = "episode", aliases = ["ep", "episodio", "puntata"])]
+    Episode(String),
+    #[command(rename = "summary", aliases = ["riassunto"])]
+    Summary(String),
+    #[command(rename = "sub", aliases = ["subtitles", "sottotitoli"])]
+    Subtitles(String),
+    #[command(rename = "clip", aliases = ["audio"])]
+    Clip(String),
     #[command(rename = "beta")]
     Beta,
     #[command(rename = "betalist")]
@@ -63,6 +115,12 @@ impl Display for Command {
             Command::Search(q) => write!(f, "search {}", q),
             Command::SearchAdvanced(q) => write!(f, "searchAdvanced {}", q),
             Command::SearchAdvancedEpisode(q) => write!(f, "searchAdvancedEpisode {}", q),
+            Command::Semantic(q) => write!(f, "semantic {}", q),
+            Command::Mentions(q) => write!(f, "mentions {}", q),
+            Command::Episode(q) => write!(f, "episode {}", q),
+            Command::Summary(q) => write!(f, "summary {}", q),
+            Command::Subtitles(q) => write!(f, "subtitles {}", q),
+            Command::Clip(q) => write!(f, "clip {}", q),
             Command::Beta => write!(f, "beta"),
             Command::BetaList => write!(f, "betaList"),
             Command::BetaWaitList => write!(f, "betaWaitList"),
@@ -83,37 +141,465 @@ async fn reply(bot: Bot, msg: Message, cmd: Command) -> Result<(), teloxide::Req
     Ok(())
 }
 
-async fn paginate_response(bot: &Bot, chat_id: ChatId, response: String) -> Result<(), BotError> {
-    let mut message = String::with_capacity(4096);
-    for chunk in response.split("\n\n") {
-        if message.len() + chunk.len() + 2 > 4096 {
-            bot.send_message(chat_id, &message).parse_mode(ParseMode::MarkdownV2).await?;
-            message.clear();
-        } else {
-            message.push_str("\n\n");
+/// Send **markdown** `entries` in pages, with buttons to move between them if they don't fit in one.
+async fn send_paged(bot: &Bot, chat_id: ChatId, header: &str, entries: Vec<Entry>, separator: &str) -> Result<(), BotError> {
+    let paged = pages::paginate(header, entries, separator);
+    let total = paged.len();
+    let text = pages::render(&paged[0], 0, total);
+    if total == 1 && paged[0].buttons.is_empty() {
+        bot.send_message(chat_id, text).parse_mode(ParseMode::MarkdownV2).await?;
+        return Ok(());
+    }
+    let first = paged[0].clone();
+    let key = pages::store(paged).await;
+    let keyboard = pages::keyboard(&first, 0, total, |n| Callback::Page(key, n));
+    bot.send_message(chat_id, text)
+        .parse_mode(ParseMode::MarkdownV2)
+        .reply_markup(keyboard)
+        .await?;
+    Ok(())
+}
+
+/// **Markdown** for an episode found by `/sa`: its link, score and number of matches, then a line
+/// for each snippet.
+fn ranked_entry(r: &RankedResult, mode: SearchMode) -> String {
+    let mut text = format!(
+        "{}: {} {}",
+        markdown::escape(&r.episode.id.to_string()),
+        episode_link(&r.episode, None),
+        markdown::escape(&format!("({} risultati, punteggio {:.2})", r.count, r.score)),
+    );
+    for m in r.snippets.iter() {
+        let at = m.start.unwrap_or(m.time.from);
+        text.push_str(&format!(
+            "\n{} {}{}",
+            markdown::link(&episode_url(r.episode.id, Some(at)), &markdown::escape(&format_time(at))),
+            variant(m, mode),
+            markdown::italic(&markdown::escape(&format!("...{}...", m.hint.replace('\n', " ")))),
+        ));
+    }
+    text
+}
+
+/// Send page `n` of the `/sa` ranking stored under `key`, fetching the transcripts of its
+/// episodes, or edit `message` into it. Returns false if the ranking expired.
+async fn send_ranking_page(bot: &Bot, chat_id: ChatId, message: Option<MessageId>, key: u64, n: usize) -> Result<bool, BotError> {
+    let Some((ranking, total)) = pages::get_ranking(key, n).await else {
+        return Ok(false);
+    };
+    let results = DB.search_transcript_results(&ranking.text, &ranking.filters, &ranking.ranking).await?;
+    let entries = results
+        .iter()
+        .map(|r| Entry {
+            text: ranked_entry(r, SearchMode::Exact),
+            buttons: episode_buttons(ranking.query, r.episode.id),
+        })
+        .collect::<Vec<_>>();
+    let page = pages::fit(&ranking.header, entries, "\n\n");
+    let text = pages::render(&page, n, total);
+    let keyboard = pages::keyboard(&page, n, total, |n| Callback::Ranking(key, n));
+    match message {
+        Some(id) => {
+            bot.edit_message_text(chat_id, id, text)
+                .parse_mode(ParseMode::MarkdownV2)
+                .reply_markup(keyboard)
+                .await?;
+        }
+        None => {
+            bot.send_message(chat_id, text)
+                .parse_mode(ParseMode::MarkdownV2)
+                .reply_markup(keyboard)
+                .await?;
+        }
+    }
+    Ok(true)
+}
+
+/// Most segments shown for a `/sa` query answered by the embedded index.
+#[cfg(feature = "index")]
+const MAX_INDEX_HITS: usize = 50;
+
+/// Segments fetched from the index when filters on the episodes may discard most of them.
+#[cfg(feature = "index")]
+const MAX_FILTERED_INDEX_HITS: usize = 1000;
+
+/// Answer `/sa` with the segments found by the embedded index, each with its highlighted text.
+#[cfg(feature = "index")]
+async fn send_index_search(bot: &Bot, chat_id: ChatId, query_key: u64, index: &power_pizza_bot::index::TranscriptIndex, query: &str, filters: &Filters) -> Result<(), BotError> {
+    // the index knows nothing of episodes, their filters are applied to the hits
+    let filtered = !Filters { speaker: None, ..filters.clone() }.is_empty();
+    let limit = if filtered { MAX_FILTERED_INDEX_HITS } else { MAX_INDEX_HITS };
+    let hits = index.search(query, filters.speaker.as_deref(), limit)?;
+    let mut episodes = std::collections::HashMap::new();
+    let mut entries = vec![];
+    // the index may have more hits than fetched
+    let mut truncated = hits.len() == limit;
+    for h in hits.iter() {
+        if let std::collections::hash_map::Entry::Vacant(v) = episodes.entry(h.episode_id) {
+            v.insert(DB.get::<Episode>(h.episode_id).await?);
+        }
+        let title = match &episodes[&h.episode_id] {
+            Some(e) if !filters.matches(e) => continue,
+            Some(e) => episode_link(e, Some(h.time.from)),
+            None => markdown::link(&episode_url(h.episode_id, Some(h.time.from)), &h.episode_id.to_string()),
+        };
+        let text = h.pieces()
+            .into_iter()
+            .map(|(t, hit)| if hit { markdown::bold(&markdown::escape(t)) } else { markdown::escape(t) })
+            .collect::<String>();
+        entries.push(Entry {
+            text: format!(
+                "{} {}{}\n{}",
+                title,
+                markdown::escape(&format!("({})", format_time(h.time.from))),
+                h.speaker.as_ref().map(|s| format!(" {}", markdown::bold(&markdown::escape(s)))).unwrap_or_default(),
+                markdown::italic(&text),
+            ),
+            buttons: episode_buttons(query_key, h.episode_id),
+        });
+        if entries.len() == MAX_INDEX_HITS {
+            truncated = true;
+            break;
         }
-        message.push_str(chunk);
     }
-    if !message.is_empty() {
-        bot.send_message(chat_id, message).parse_mode(ParseMode::MarkdownV2).await?;
+    if entries.is_empty() {
+        return Err(SearchError::NoResults.into());
+    }
+    let header = match truncated {
+        true => format!("Primi {} segmenti trovati, aggiungi termini o filtri per restringere la ricerca:", entries.len()),
+        false => format!("Segmenti trovati ({}):", entries.len()),
+    };
+    send_paged(bot, chat_id, &markdown::escape(&header), entries, "\n\n").await
+}
+
+/// Buttons attached to an episode found by `/s` or `/sa`.
+fn episode_buttons(query_key: u64, id: u32) -> Vec<InlineKeyboardButton> {
+    vec![
+        Callback::SearchEpisode(query_key, id).button(format!("🔍 Cerca in {}", id)),
+        Callback::Details(id).button(format!("ℹ️ Dettagli {}", id)),
+    ]
+}
+
+/// **Markdown** for the words a fuzzy search matched, followed by a space. Empty for exact
+/// searches, where they are what was searched.
+fn variant(m: &EpisodeOffsetMatch, mode: SearchMode) -> String {
+    match mode {
+        SearchMode::Exact | SearchMode::Regex => String::new(),
+        SearchMode::Fuzzy => format!("{} ", markdown::bold(&markdown::escape(&format!("≈ \"{}\"", m.matched.trim())))),
+    }
+}
+
+/// Remove the `~` marking a fuzzy query, see `SearchMode::Fuzzy`.
+fn take_fuzzy_flag(query: &str) -> (String, SearchMode) {
+    match query.trim().strip_prefix('~') {
+        Some(q) => (q.trim().to_owned(), SearchMode::Fuzzy),
+        None => (query.to_owned(), SearchMode::Exact),
+    }
+}
+
+/// Like `take_fuzzy_flag`, also telling a regex written as `/{regex}/` from a literal query.
+fn take_query_mode(query: &str) -> (String, SearchMode) {
+    let (query, mode) = take_fuzzy_flag(query);
+    match query.strip_prefix('/').and_then(|q| q.strip_suffix('/')) {
+        Some(r) if mode == SearchMode::Exact && !r.is_empty() => (r.to_owned(), SearchMode::Regex),
+        _ => (query, mode),
+    }
+}
+
+/// **Markdown** for chapter `i` of `e`, linked to its start when known, either from the
+/// description or from `mention`, when it is first said in the transcript.
+fn chapter_link(e: &Episode, i: usize, mention: Option<Duration>) -> String {
+    let title = markdown::escape(&e.chapters[i].title);
+    match (e.chapter_time(i), mention) {
+        (Some(t), _) => format!("{} {}", markdown::link(&episode_url(e.id, Some(t.from)), &title), markdown::escape(&format!("({})", format_range(&t)))),
+        (None, Some(m)) => format!("{} {}", markdown::link(&episode_url(e.id, Some(m)), &title), markdown::escape(&format!("(citato a {})", format_time(m)))),
+        (None, None) => title,
+    }
+}
+
+/// One button per episode of an ambiguous query, see `SearchError::Ambiguous`.
+fn candidate_keyboard(candidates: Vec<(u32, String)>, callback: impl Fn(u32) -> Callback) -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(candidates
+        .into_iter()
+        .map(|(id, title)| vec![callback(id).button(title)]))
+}
+
+/// Send the transcript of episode `id` as a `format` file.
+async fn send_subtitles(bot: &Bot, chat_id: ChatId, id: u32, format: ExportFormat) -> Result<(), BotError> {
+    let transcript = DB.get::<EpisodeTranscript>(id).await?.ok_or(BotError::TranscriptMissing)?;
+    let episode = DB.get::<Episode>(id).await?;
+    let name = match &episode {
+        Some(e) => format!("{} - {}.{}", e.id, e.title, format.extension()),
+        None => format!("{}.{}", id, format.extension()),
+    };
+    info!("sending {} transcript for episode {}", format, id);
+    let file = InputFile::memory(format.render(&transcript, episode.as_ref()).into_bytes()).file_name(name);
+    bot.send_document(chat_id, file).await?;
+    Ok(())
+}
+
+/// Send a clip of episode `id` as a voice message. `args` are those of `/clip` after the episode:
+/// a time range, or a query optionally followed by the number of the match.
+async fn send_clip(bot: &Bot, chat_id: ChatId, id: u32, args: &[String]) -> Result<(), BotError> {
+    let what = args.first().ok_or(BotError::MalformedQuery)?;
+    let range = match parse_range(what) {
+        Some(r) => r,
+        None => {
+            let n = match args.get(1) {
+                Some(n) => n.parse::<usize>().map_err(|_| BotError::MalformedQuery)?,
+                None => 1,
+            };
+            let results = DB.search_transcript_one(id, what.to_string(), &Filters::default(), SearchMode::Exact).await?;
+            results.matches
+                .get(n.checked_sub(1).ok_or(BotError::MalformedQuery)?)
+                .ok_or(BotError::MalformedQuery)?
+                .span()
+        }
+    };
+    info!("sending clip {} of episode {}", format_range(&range), id);
+    let clip = episode_clip(id, &range).await?;
+    bot.send_voice(chat_id, InputFile::file(clip))
+        .caption(format_range(&range))
+        .await?;
+    Ok(())
+}
+
+async fn send_episode_search(bot: &Bot, chat_id: ChatId, id: u32, query: String, filters: &Filters, mode: SearchMode) -> Result<(), BotError> {
+    info!("searching episode {} for {:?}, filters: {:?}, mode: {:?}", id, query, filters, mode);
+    let results = DB.search_transcript_one(id, query, filters, mode).await?;
+    if results.matches.is_empty() {
+        bot.send_message(chat_id, "No matches found").await?;
+        return Ok(());
+    }
+    let header = format!("{}{}{}",
+        markdown::escape("Risultati per "),
+        episode_link(&results.episode, None),
+        markdown::escape(&format!(" ({}):", results.len())),
+    );
+    let entries = results.matches
+        .iter()
+        .map(|m| (m, m.start.unwrap_or(m.time.from)))
+        .map(|(m, at)| Entry::from(format!(
+            "{}{} {}{}\n{}",
+            m.speaker.as_ref().map(|s| format!("{} ", markdown::bold(&markdown::escape(s)))).unwrap_or_default(),
+            markdown::link(&episode_url(results.episode.id, Some(at)), &markdown::escape(&format_time(at))),
+            variant(m, mode),
+            markdown::escape(&format!("({})", format_range(&m.span()))),
+            markdown::blockquote(&markdown::escape(&format!("...{}...", m.hint)))
+        )))
+        .collect::<Vec<_>>();
+    send_paged(bot, chat_id, &header, entries, "\n\n").await
+}
+
+async fn send_episode_details(bot: &Bot, chat_id: ChatId, id: u32) -> Result<(), BotError> {
+    const MAX_DESCRIPTION: usize = 1000;
+    const MAX_DETAIL_LINKS: usize = 10;
+    const MAX_DETAIL_CHAPTERS: usize = 30;
+    let e = DB.get::<Episode>(id).await?.ok_or(SearchError::EpisodeNotFound(id))?;
+    let mut description = e.description.chars().take(MAX_DESCRIPTION).collect::<String>();
+    if e.description.chars().nth(MAX_DESCRIPTION).is_some() {
+        description.push('…');
+    }
+    let mut info = vec![];
+    if let Some(n) = e.number {
+        info.push(format!("Puntata {}", n));
+    }
+    if let Some(s) = &e.series {
+        info.push(format!("Serie: {}", s));
+    }
+    info.push(format!(
+        "Pubblicato il {} - durata {}",
+        e.published_at.with_timezone(&chrono::Local).format("%d/%m/%Y"),
+        format_time(Duration::from_millis(e.duration as u64)),
+    ));
+    if !e.guests.is_empty() {
+        info.push(format!("Ospiti: {}", e.guests.join(", ")));
+    }
+    // chapters without a time in the description are looked for in the transcript
+    let transcript = match e.chapters.iter().take(MAX_DETAIL_CHAPTERS).any(|c| c.start.is_none()) {
+        true => DB.get::<EpisodeTranscript>(id).await?,
+        false => None,
+    };
+    let titles = e.chapters.iter().take(MAX_DETAIL_CHAPTERS).map(|c| c.title.as_str()).collect::<Vec<_>>();
+    let mentions = match &transcript {
+        Some(t) => first_mentions(t, &titles),
+        None => vec![None; titles.len()],
+    };
+    let chapters = mentions
+        .into_iter()
+        .enumerate()
+        .map(|(i, mention)| Entry::from(format!("- {}", chapter_link(&e, i, mention))))
+        .collect::<Vec<_>>();
+    let links = e.links
+        .iter()
+        .take(MAX_DETAIL_LINKS)
+        .map(|l| Entry::from(format!("- {}", markdown::escape(l))))
+        .collect::<Vec<_>>();
+    bot.send_message(chat_id, format!(
+        "{}\n{}\n\n{}",
+        markdown::bold(&episode_link(&e, None)),
+        markdown::escape(&info.join("\n")),
+        markdown::escape(&description),
+    ))
+        .parse_mode(ParseMode::MarkdownV2)
+        .await?;
+    // chapters and links may not fit in the same message as the description
+    if !chapters.is_empty() {
+        send_paged(bot, chat_id, &markdown::escape("Capitoli:"), chapters, "\n").await?;
+    }
+    if !links.is_empty() {
+        send_paged(bot, chat_id, &markdown::escape("Link:"), links, "\n").await?;
     }
     Ok(())
 }
 
+/// Summary and chapters written from the transcript, marked as generated along with the model
+/// that wrote them.
+async fn send_episode_summary(bot: &Bot, chat_id: ChatId, id: u32) -> Result<(), BotError> {
+    const MAX_SUMMARY: usize = 1500;
+    let e = DB.get::<Episode>(id).await?.ok_or(SearchError::EpisodeNotFound(id))?;
+    let s = DB.get::<EpisodeSummary>(id).await?.ok_or(BotError::SummaryMissing)?;
+    let chapters = s.chapters
+        .iter()
+        .map(|c| Entry::from(format!(
+            "- {} {}",
+            markdown::link(&episode_url(id, Some(c.time.from)), &markdown::escape(&format_time(c.time.from))),
+            markdown::escape(&c.title),
+        )))
+        .collect::<Vec<_>>();
+    let provenance = format!(
+        "Generato automaticamente il {} ({}{})",
+        s.provenance.created_at.with_timezone(&chrono::Local).format("%d/%m/%Y"),
+        s.provenance.backend,
+        s.provenance.model.as_ref().map(|m| format!(", {}", m)).unwrap_or_default(),
+    );
+    let mut summary = s.summary.chars().take(MAX_SUMMARY).collect::<String>();
+    if s.summary.chars().nth(MAX_SUMMARY).is_some() {
+        summary.push('…');
+    }
+    bot.send_message(chat_id, format!(
+        "{}\n\n{}\n\n{}",
+        markdown::bold(&episode_link(&e, None)),
+        markdown::escape(&summary),
+        markdown::italic(&markdown::escape(&provenance)),
+    ))
+        .parse_mode(ParseMode::MarkdownV2)
+        .reply_markup(InlineKeyboardMarkup::new(vec![vec![Callback::Details(id).button(format!("ℹ️ Dettagli {}", id))]]))
+        .await?;
+    if !chapters.is_empty() {
+        send_paged(bot, chat_id, &markdown::escape("Capitoli:"), chapters, "\n").await?;
+    }
+    Ok(())
+}
+
+async fn callback_query(bot: Bot, q: CallbackQuery) -> Result<(), teloxide::RequestError> {
+    let chat_id = q.regular_message().map(|m| m.chat.id);
+    match callback_query_inner(&bot, &q).await {
+        Ok(_) => {}
+        Err(e) => {
+            error!("failed to answer callback query {:?} from {}: {:?}", q.data, represent_user(&Some(q.from.clone())), e);
+            if let Some(chat_id) = chat_id {
+                bot.send_message(chat_id, e.respond_client()).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn callback_query_inner(bot: &Bot, q: &CallbackQuery) -> Result<(), BotError> {
+    let callback = q.data.as_deref().and_then(Callback::parse);
+    let msg = match (&callback, q.regular_message()) {
+        (Some(_), Some(msg)) => msg,
+        _ => {
+            debug!("ignoring unknown callback data {:?}", q.data);
+            bot.answer_callback_query(q.id.clone()).await?;
+            return Ok(());
+        }
+    };
+    if !DB.whitelisted(q.from.id.0 as i64).await? {
+        bot.answer_callback_query(q.id.clone()).text("Non hai accesso alla beta").await?;
+        return Ok(());
+    }
+    // unwrap safe: checked above
+    match callback.unwrap() {
+        Callback::Page(key, n) => match pages::get(key, n).await {
+            Some((page, total)) => {
+                bot.edit_message_text(msg.chat.id, msg.id, pages::render(&page, n, total))
+                    .parse_mode(ParseMode::MarkdownV2)
+                    .reply_markup(pages::keyboard(&page, n, total, |n| Callback::Page(key, n)))
+                    .await?;
+                bot.answer_callback_query(q.id.clone()).await?;
+            }
+            None => {
+                bot.answer_callback_query(q.id.clone()).text(EXPIRED_RESULTS).await?;
+            }
+        },
+        Callback::Ranking(key, n) => {
+            match send_ranking_page(bot, msg.chat.id, Some(msg.id), key, n).await? {
+                true => bot.answer_callback_query(q.id.clone()).await?,
+                false => bot.answer_callback_query(q.id.clone()).text(EXPIRED_RESULTS).await?,
+            };
+        }
+        Callback::SearchEpisode(key, id) => match pages::get_query(key).await {
+            Some(query) => {
+                bot.answer_callback_query(q.id.clone()).await?;
+                send_episode_search(bot, msg.chat.id, id, query.pattern, &query.filters, query.mode).await?;
+            }
+            None => {
+                bot.answer_callback_query(q.id.clone()).text(EXPIRED_RESULTS).await?;
+            }
+        },
+        Callback::Details(id) => {
+            bot.answer_callback_query(q.id.clone()).await?;
+            send_episode_details(bot, msg.chat.id, id).await?;
+        }
+        Callback::Summary(id) => {
+            bot.answer_callback_query(q.id.clone()).await?;
+            send_episode_summary(bot, msg.chat.id, id).await?;
+        }
+        Callback::Subtitles(id, format) => {
+            bot.answer_callback_query(q.id.clone()).await?;
+            send_subtitles(bot, msg.chat.id, id, format).await?;
+        }
+        Callback::Clip(key, id) => match pages::get_clip(key).await {
+            Some(args) => {
+                bot.answer_callback_query(q.id.clone()).await?;
+                send_clip(bot, msg.chat.id, id, &args).await?;
+            }
+            None => {
+                bot.answer_callback_query(q.id.clone()).text(EXPIRED_RESULTS).await?;
+            }
+        },
+    }
+    Ok(())
+}
+
+/// Times linked for each episode of a `/mentions` query.
+const MAX_MENTION_TIMES: usize = 8;
+
+/// Hits shown for a `/sem` query.
+const MAX_SEMANTIC_RESULTS: usize = 10;
+
+static EXPIRED_RESULTS: &str = "Risultati scaduti, ripeti la ricerca";
+
 fn split_quoted_args(s: &str) -> Option<Vec<String>> {
     let mut args = vec![];
     let r = Regex::new(r#"("([^"]+)"|(\S+)")|(\S+)"#).unwrap();
     for cap in r.captures_iter(s) {
-        let cap = match cap.get(2).or(cap.get(4)) {
-            Some(c) => c,
-            None => return None,
-        };
+        let cap = cap.get(2).or(cap.get(4))?;
         args.push(cap.as_str().to_string());
     }
     Some(args)
 }
 
-static MAX_RESULTS: usize = 50;
+/// Split the first argument of `s`, possibly quoted, from the rest.
+fn take_first_arg(s: &str) -> Option<(String, &str)> {
+    let s = s.trim_start();
+    match s.strip_prefix('"') {
+        Some(q) => q.split_once('"').map(|(a, rest)| (a.to_owned(), rest)),
+        None => s.split_once(char::is_whitespace).map(|(a, rest)| (a.to_owned(), rest)),
+    }
+}
 
 fn is_admin(u: &Option<User>) -> bool {
     if let Some(u) = u {
@@ -144,91 +630,194 @@ async fn reply_inner(bot: &Bot, msg: &Message, cmd: Command) -> Result<(), BotEr
                 .await?;
         }
         Command::Search(query) => {
-            if query.len() < 3 {
+            let q = Query::parse(&query)?;
+            // titles and descriptions aren't said by anyone
+            if q.filters.speaker.is_some() {
+                return Err(QueryError::UnsupportedFilter("speaker".to_owned()).into());
+            }
+            let (query, filters) = (q.plain(), q.filters);
+            // filters alone are enough, e.g. to list the episodes of a year
+            if query.len() < 3 && filters.is_empty() {
                 bot.send_message(msg.chat.id, "La query deve essere di almeno 3 caratteri").await?;
             } else {
-                let results = DB.search_meta(query).await?;
-                if results.len() > MAX_RESULTS {
-                    bot.send_message(msg.chat.id, format!("Troppi risultati trovati ({}), per favore affina la ricerca", results.len())).await?;
-                    return Ok(());
-                }
-                let response = results
+                let key = pages::store_query(StoredQuery { pattern: query.clone(), filters: filters.clone(), mode: SearchMode::Exact }).await;
+                let results = DB.search_meta(query, &filters).await?;
+                let entries = results
                     .iter()
-                    .map(|r| format!(
-                            "{}: {}", 
+                    .map(|r| Entry {
+                        text: format!(
+                            "{}: {}{}", 
                             markdown::escape(&r.episode.id.to_string()),
-                            markdown::link(&format!("https://www.spreaker.com/episode/{}", r.episode.id), &markdown::escape(&r.episode.title))
-                    ))
-                    .collect::<Vec<_>>()
-                    .join("\n");
-                paginate_response(bot, msg.chat.id, response).await?;
+                            episode_link(&r.episode, None),
+                            r.chapter.map(|i| format!("\n  ↳ {}", chapter_link(&r.episode, i, None))).unwrap_or_default(),
+                        ),
+                        buttons: episode_buttons(key, r.episode.id),
+                    })
+                    .collect::<Vec<_>>();
+                send_paged(bot, msg.chat.id, "", entries, "\n").await?;
             }
         }
         Command::SearchAdvanced(query) => {
             info!("received search query: {}", query);
             bot.send_message(msg.chat.id, "Searching...").await?;
             debug!("querying db");
-            let results = DB.search_transcript_all(query).await?;
-            debug!("found {} results", results.len());
-            if results.len() > MAX_RESULTS {
-                bot.send_message(msg.chat.id, format!("Troppi risultati trovati ({}), per favore affina la ricerca", results.len())).await?;
-                return Ok(());
+            let (query, mode) = take_fuzzy_flag(&query);
+            let q = Query::parse(&query)?;
+            let query = q.text();
+            // single transcripts have no text index, look for any of the terms instead
+            let stored = match mode {
+                SearchMode::Fuzzy => StoredQuery { pattern: query.clone(), filters: q.filters.clone(), mode },
+                _ => StoredQuery { pattern: text_query_pattern(&query), filters: q.filters.clone(), mode: SearchMode::Regex },
+            };
+            let key = pages::store_query(stored).await;
+            // the index has no fuzzy matching of its own
+            #[cfg(feature = "index")]
+            if let (Some(index), SearchMode::Exact) = (&*power_pizza_bot::index::INDEX, mode) {
+                return send_index_search(bot, msg.chat.id, key, index, &query, &q.filters).await;
             }
-            let response = format!(
-                "{}\n{}",
-                markdown::escape("Found episodes:"),
-                results
+            // fuzzy searches have all their transcripts at hand, exact ones fetch them a page at a time
+            if mode == SearchMode::Fuzzy {
+                let results = DB.search_transcript_fuzzy(&query, &q.filters).await?;
+                debug!("found {} results", results.len());
+                let entries = results
                     .iter()
-                    .map(|r| format!(
-                            "{}: {}", 
-                            markdown::escape(&r.episode.id.to_string()),
-                            markdown::link(&format!("https://www.spreaker.com/episode/{}", r.episode.id), &markdown::escape(&r.episode.title))
-                    ))
-                    .collect::<Vec<_>>()
-                    .join("\n")
-            );
-            paginate_response(bot, msg.chat.id, response).await?;
+                    .map(|r| Entry {
+                        text: ranked_entry(r, mode),
+                        buttons: episode_buttons(key, r.episode.id),
+                    })
+                    .collect::<Vec<_>>();
+                send_paged(bot, msg.chat.id, &markdown::escape(&format!("Found episodes ({}):", results.len())), entries, "\n\n").await?;
+            } else {
+                let ranking = DB.search_transcript_ranking(&query, &q.filters).await?;
+                debug!("found {} results", ranking.len());
+                let header = markdown::escape(&format!("Found episodes ({}):", ranking.len()));
+                let ranking = pages::store_ranking(StoredRanking { header, text: query, filters: q.filters, ranking, query: key }).await;
+                send_ranking_page(bot, msg.chat.id, None, ranking, 0).await?;
+            }
         }
         Command::SearchAdvancedEpisode(query) => {
             bot.send_message(msg.chat.id, "searching episode transcripts...").await?;
-            let args = split_quoted_args(&query).ok_or(BotError::MalformedQuery)?;
-            let id = DB.magic_episode_search(args
-                .first()
-                .ok_or(BotError::MalformedQuery)?.to_string()).await?;
-            let query = args
-                .get(1)
-                .ok_or(BotError::MalformedQuery)?
-                .to_string();
+            let (episode, rest) = take_first_arg(&query).ok_or(BotError::MalformedQuery)?;
+            let q = Query::parse(rest)?;
+            if q.terms.is_empty() {
+                return Err(BotError::MalformedQuery);
+            }
+            // the episode is searched for a single pattern, there is nothing to exclude from
+            if let Some(t) = q.terms.iter().find(|t| t.negated) {
+                return Err(QueryError::NegatedTerm(t.text.clone()).into());
+            }
+            let (query, mode) = take_query_mode(&q.plain());
+            if mode == SearchMode::Regex {
+                check_user_regex(&query)?;
+            }
+            let id = match DB.magic_episode_search(episode).await {
+                // let the user pick the episode, the buttons run the search in it
+                Err(SearchError::Ambiguous(candidates)) => {
+                    let key = pages::store_query(StoredQuery { pattern: query, filters: q.filters, mode }).await;
+                    bot.send_message(msg.chat.id, "Più episodi corrispondono, in quale vuoi cercare?")
+                        .reply_markup(candidate_keyboard(candidates, |id| Callback::SearchEpisode(key, id)))
+                        .await?;
+                    return Ok(());
+                }
+                r => r?,
+            };
 
-            info!("parsed arguments: id: {}, query: {}", id, query.as_str());
-            let results = DB.search_transcript_one(id, query.as_str().to_string()).await?;
-            if results.len() > MAX_RESULTS {
-                bot.send_message(msg.chat.id, format!("Troppi risultati trovati ({}), per favore affina la ricerca", results.len())).await?;
+            info!("parsed arguments: id: {}, query: {}, filters: {:?}", id, query.as_str(), q.filters);
+            send_episode_search(bot, msg.chat.id, id, query, &q.filters, mode).await?;
+        }
+        Command::Semantic(query) => {
+            if query.trim().len() < 3 {
+                bot.send_message(msg.chat.id, "La query deve essere di almeno 3 caratteri").await?;
                 return Ok(());
             }
-            if results.matches.is_empty() {
-                bot.send_message(msg.chat.id, "No matches found").await?;
-            } else {
-                let response = format!("{}{}\n{}",
-                    markdown::escape("Risultati per "),
-                    markdown::link(&format!("https://www.spreaker.com/episode/{}", results.episode.id), &markdown::escape(&results.episode.title)),
-                    results.matches
-                        .iter()
-                        .map(|m| format!(
-                            "{}\n{}",
-                            markdown::escape(&format!("{:02}:{:02} - {:02}:{:02}",
-                                m.time.from.as_secs() / 60,
-                                m.time.from.as_secs() % 60,
-                                m.time.to.as_secs() / 60,
-                                m.time.to.as_secs() % 60
-                            )),
-                            markdown::blockquote(&markdown::escape(&format!("...{}...", m.hint)))
-                        ))
-                        .collect::<Vec<_>>()
-                        .join("\n\n")
-                );
-                paginate_response(bot, msg.chat.id, response).await?;
-            } 
+            bot.send_message(msg.chat.id, "Searching...").await?;
+            let hits = DB.search_semantic(&query, MAX_SEMANTIC_RESULTS).await?;
+            let entries = hits
+                .iter()
+                .map(|h| Entry {
+                    text: format!(
+                        "{} {}\n{}",
+                        episode_link(&h.episode, Some(h.time.from)),
+                        markdown::escape(&format!("({}, {:.0}%)", format_range(&h.time), h.similarity * 100.0)),
+                        markdown::italic(&markdown::escape(&h.text)),
+                    ),
+                    buttons: vec![Callback::Details(h.episode.id).button(format!("ℹ️ Dettagli {}", h.episode.id))],
+                })
+                .collect::<Vec<_>>();
+            send_paged(bot, msg.chat.id, &markdown::escape(&format!("Momenti trovati ({}):", hits.len())), entries, "\n\n").await?;
+        }
+        Command::Mentions(query) => {
+            let hits = DB.search_mentions(&query).await?;
+            let entries = hits
+                .iter()
+                .map(|h| Entry {
+                    text: format!(
+                        "{} {}\n{}",
+                        episode_link(&h.episode, h.mention.times.first().copied()),
+                        markdown::escape(&format!("- {} ({} volte)", h.mention.title, h.mention.count)),
+                        h.mention.times
+                            .iter()
+                            .take(MAX_MENTION_TIMES)
+                            .map(|t| markdown::link(&episode_url(h.episode.id, Some(*t)), &markdown::escape(&format_time(*t))))
+                            .collect::<Vec<_>>()
+                            .join(" "),
+                    ),
+                    buttons: vec![Callback::Details(h.episode.id).button(format!("ℹ️ Dettagli {}", h.episode.id))],
+                })
+                .collect::<Vec<_>>();
+            send_paged(bot, msg.chat.id, &markdown::escape(&format!("Episodi in cui se ne parla ({}):", hits.len())), entries, "\n\n").await?;
+        }
+        Command::Episode(query) => {
+            match DB.magic_episode_search(query).await {
+                Err(SearchError::Ambiguous(candidates)) => {
+                    bot.send_message(msg.chat.id, "Più episodi corrispondono, quale cercavi?")
+                        .reply_markup(candidate_keyboard(candidates, Callback::Details))
+                        .await?;
+                }
+                r => send_episode_details(bot, msg.chat.id, r?).await?,
+            }
+        }
+        Command::Summary(query) => {
+            match DB.magic_episode_search(query).await {
+                Err(SearchError::Ambiguous(candidates)) => {
+                    bot.send_message(msg.chat.id, "Più episodi corrispondono, quale cercavi?")
+                        .reply_markup(candidate_keyboard(candidates, Callback::Summary))
+                        .await?;
+                }
+                r => send_episode_summary(bot, msg.chat.id, r?).await?,
+            }
+        }
+        Command::Subtitles(query) => {
+            let args = split_quoted_args(&query).ok_or(BotError::MalformedQuery)?;
+            let episode = args.first().ok_or(BotError::MalformedQuery)?;
+            let format = match args.get(1) {
+                Some(f) => f.parse::<ExportFormat>().map_err(|_| BotError::MalformedQuery)?,
+                None => ExportFormat::Srt,
+            };
+            match DB.magic_episode_search(episode.to_string()).await {
+                Err(SearchError::Ambiguous(candidates)) => {
+                    bot.send_message(msg.chat.id, "Più episodi corrispondono, di quale vuoi la trascrizione?")
+                        .reply_markup(candidate_keyboard(candidates, |id| Callback::Subtitles(id, format)))
+                        .await?;
+                }
+                r => send_subtitles(bot, msg.chat.id, r?, format).await?,
+            }
+        }
+        Command::Clip(query) => {
+            let mut args = split_quoted_args(&query).ok_or(BotError::MalformedQuery)?;
+            if args.len() < 2 {
+                return Err(BotError::MalformedQuery);
+            }
+            let episode = args.remove(0);
+            match DB.magic_episode_search(episode).await {
+                Err(SearchError::Ambiguous(candidates)) => {
+                    let key = pages::store_clip(args).await;
+                    bot.send_message(msg.chat.id, "Più episodi corrispondono, da quale vuoi la clip?")
+                        .reply_markup(candidate_keyboard(candidates, |id| Callback::Clip(key, id)))
+                        .await?;
+                }
+                r => send_clip(bot, msg.chat.id, r?, &args).await?,
+            }
         }
         Command::Beta => {
             info!("user {} requested beta access", represent_user(&msg.from));
@@ -0,0 +1,112 @@
+use std::{path::{Path, PathBuf}, time::Duration};
+#[allow(unused_imports)]
+use log::{debug, info, warn, error};
+
+use crate::{config::CONFIG, transcript::FromTo};
+use super::BotError;
+
+/// Seconds of audio added before and after the requested range.
+pub const CLIP_PADDING: Duration = Duration::from_secs(3);
+/// Longest clip we agree to cut, padding included.
+pub const MAX_CLIP_LENGTH: Duration = Duration::from_secs(90);
+
+/// Local audio of an episode: the wav used for transcription, or the mp3 if it wasn't converted.
+fn episode_audio(id: u32) -> Option<PathBuf> {
+    [
+        PathBuf::from(format!("{}/{}.wav", CONFIG.import.wav_dir, id)),
+        PathBuf::from(format!("{}/{}.mp3", CONFIG.import.download_dir, id)),
+    ]
+        .into_iter()
+        .find(|p| p.is_file())
+}
+
+/// Cut `range` (plus padding) out of the episode audio as OGG/Opus, suitable for a Telegram
+/// voice message. Clips are cached in `clip_dir`, so asking twice for the same range is free.
+pub async fn episode_clip(id: u32, range: &FromTo) -> Result<PathBuf, BotError> {
+    let from = range.from.saturating_sub(CLIP_PADDING);
+    let to = range.to + CLIP_PADDING;
+    if to <= from || to - from > MAX_CLIP_LENGTH {
+        return Err(BotError::ClipTooLong);
+    }
+    let out = Path::new(&CONFIG.tg.clip_dir).join(format!("{}_{}_{}.ogg", id, from.as_millis(), to.as_millis()));
+    if out.is_file() {
+        debug!("clip cache hit: {:?}", out);
+        return Ok(out);
+    }
+    let src = episode_audio(id).ok_or(BotError::AudioMissing)?;
+    tokio::fs::create_dir_all(&CONFIG.tg.clip_dir).await?;
+    // a temp file of its own for each request, renamed once complete: the same clip can be asked
+    // for twice at once. ffmpeg picks the container from the extension, so keep it at the end
+    let tmp = tempfile::Builder::new().prefix(TMP_PREFIX).suffix(".ogg").tempfile_in(&CONFIG.tg.clip_dir)?.into_temp_path();
+    info!("cutting clip {:?} from {:?}", out, src);
+    let status = tokio::process::Command::new("ffmpeg")
+        .args(["-y", "-loglevel", "error", "-ss", &format!("{:.3}", from.as_secs_f64()), "-t", &format!("{:.3}", (to - from).as_secs_f64()), "-i"])
+        .arg(&src)
+        .args(["-vn", "-ac", "1", "-c:a", "libopus", "-b:a", "48k"])
+        .arg(&tmp)
+        .status()
+        .await?;
+    if !status.success() {
+        error!("ffmpeg failed cutting clip for episode {}: {}", id, status);
+        return Err(BotError::ClipFailed);
+    }
+    tmp.persist(&out).map_err(|e| e.error)?;
+    if let Err(e) = prune_clips(Path::new(&CONFIG.tg.clip_dir), CONFIG.tg.clip_cache_size, &out).await {
+        warn!("couldn't prune the clip cache: {}", e);
+    }
+    Ok(out)
+}
+
+/// Prefix of the clips being cut, left out of the cache size.
+const TMP_PREFIX: &str = ".tmp";
+
+/// Remove the oldest clips in `dir` until they take at most `max_size` bytes, sparing `keep`.
+async fn prune_clips(dir: &Path, max_size: u64, keep: &Path) -> std::io::Result<()> {
+    let mut clips = vec![];
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(e) = entries.next_entry().await? {
+        let path = e.path();
+        let metadata = e.metadata().await?;
+        if metadata.is_file() && path.extension().is_some_and(|ext| ext == "ogg") && !e.file_name().to_string_lossy().starts_with(TMP_PREFIX) {
+            clips.push((metadata.modified()?, metadata.len(), path));
+        }
+    }
+    let mut size = clips.iter().map(|(_, len, _)| len).sum::<u64>();
+    clips.sort();
+    for (_, len, path) in clips {
+        if size <= max_size {
+            break;
+        }
+        if path == keep {
+            continue;
+        }
+        debug!("removing clip {:?} from the cache", path);
+        tokio::fs::remove_file(&path).await?;
+        size -= len;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn prune_removes_the_oldest_clips() {
+        let dir = tempfile::tempdir().unwrap();
+        let clip = |name: &str| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, [0; 100]).unwrap();
+            // modification times are coarse on some filesystems
+            std::thread::sleep(Duration::from_millis(20));
+            path
+        };
+        let (oldest, old, new) = (clip("1_0_1.ogg"), clip("1_1_2.ogg"), clip("1_2_3.ogg"));
+        let tmp = clip(".tmpabc.ogg");
+        prune_clips(dir.path(), 150, &old).await.unwrap();
+        assert!(!oldest.exists());
+        assert!(old.exists());
+        assert!(!new.exists());
+        assert!(tmp.exists());
+    }
+}
@@ -0,0 +1,164 @@
+use std::time::Instant;
+use futures_util::TryStreamExt;
+use lazy_static::lazy_static;
+#[allow(unused_imports)]
+use log::{debug, trace};
+use mongodb::bson::doc;
+use regex::Regex;
+use serde::Deserialize;
+use unidecode::unidecode;
+
+use crate::{db::PPPDatabase, spreaker::Episode};
+use super::{fuzzy::{levenshtein, max_distance}, search::SearchError};
+
+/// Numbers above this are Spreaker ids rather than episode numbers.
+const MIN_EPISODE_ID: u32 = 10000;
+/// Least title score of a match, see `title_score`.
+const MIN_TITLE_SCORE: f32 = 0.5;
+/// Lead over the runner-up that makes the best title match unambiguous.
+const MIN_LEAD: f32 = 0.2;
+/// Most episodes offered to pick from when the query is ambiguous.
+const MAX_CANDIDATES: usize = 5;
+
+lazy_static! {
+    /// `12`, `#12`, `ppp 12`, `puntata 12`...
+    static ref NUMBER_QUERY: Regex = Regex::new(r"(?i)^(?:(?:ppp|puntata|episodio|ep\.?)\s*)?#?\s*(\d+)$").unwrap();
+}
+
+/// The fields of an episode needed to find it.
+#[derive(Deserialize, Debug)]
+struct EpisodeTitle {
+    id: u32,
+    title: String,
+    #[serde(default)]
+    series: Option<String>,
+}
+
+impl PPPDatabase {
+    /// Find an episode by its Spreaker id, its number or its title, possibly misspelled.
+    ///
+    /// Numbers up to `MIN_EPISODE_ID` are episode numbers, never those of specials, whose titles
+    /// have numbers of their own. Queries naming a special (`speciale`, `pgdr`) only look among
+    /// specials. When several episodes fit about as well, `SearchError::Ambiguous` lists them.
+    pub async fn magic_episode_search(&self, query: String) -> Result<u32, SearchError> {
+        let _t = Instant::now();
+        let query = query.trim();
+        let number = NUMBER_QUERY.captures(query).and_then(|c| c[1].parse::<u32>().ok());
+        if let Some(id) = number.filter(|n| *n > MIN_EPISODE_ID) {
+            debug!("assuming {} is an episode id", id);
+            return Ok(id);
+        }
+        let filter = match number {
+            Some(n) => doc!{"number": n},
+            None => doc!{},
+        };
+        let episodes = self.db
+            .collection::<EpisodeTitle>("episodes")
+            .find(filter)
+            .projection(doc!{"_id": 0, "id": 1, "title": 1, "series": 1})
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        let mut found = match number {
+            Some(n) => {
+                debug!("assuming {} is an episode number", n);
+                episodes.into_iter().map(|e| (1.0, e)).collect::<Vec<_>>()
+            }
+            None => {
+                let query_words = words(query);
+                let special = Episode::is_special_title(query);
+                let mut found = episodes
+                    .into_iter()
+                    .filter(|e| !special || e.series.is_some())
+                    .map(|e| (title_score(&query_words, &words(&e.title)), e))
+                    .filter(|(s, _)| *s >= MIN_TITLE_SCORE)
+                    .collect::<Vec<_>>();
+                found.sort_by(|a, b| b.0.total_cmp(&a.0));
+                found
+            }
+        };
+        trace!("timings: magic_episode_search: {:?}", _t.elapsed());
+        let best = found.first().ok_or(SearchError::NoResults)?.0;
+        found.retain(|(s, _)| best - s < MIN_LEAD);
+        match found.len() {
+            1 => Ok(found[0].1.id),
+            _ => Err(SearchError::Ambiguous(found
+                .into_iter()
+                .take(MAX_CANDIDATES)
+                .map(|(_, e)| (e.id, e.title))
+                .collect())),
+        }
+    }
+}
+
+fn words(s: &str) -> Vec<String> {
+    // unidecode spells these out, gluing them to the word before: "PGdR™" would be "pgdrtm"
+    unidecode(&s.replace(['™', '®', '©'], " "))
+        .to_lowercase()
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// How well `title` fits `query`, from 0 to 1: the mean over the query words of how well the
+/// closest title word fits, 1 if equal, less if the query word is a prefix of it or a few edits
+/// away.
+fn title_score(query: &[String], title: &[String]) -> f32 {
+    if query.is_empty() {
+        return 0.0;
+    }
+    let word_score = |q: &String| title
+        .iter()
+        .map(|t| {
+            if t == q {
+                1.0
+            } else if q.len() >= 3 && t.starts_with(q.as_str()) {
+                0.8
+            } else if levenshtein(q, t) <= max_distance(q.len()) {
+                0.6
+            } else {
+                0.0
+            }
+        })
+        .fold(0.0, f32::max);
+    query.iter().map(word_score).sum::<f32>() / query.len() as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn score(query: &str, title: &str) -> f32 {
+        title_score(&words(query), &words(title))
+    }
+
+    #[test]
+    fn number_queries() {
+        for q in ["123", "#123", "ppp 123", "PPP123", "puntata 123", "Ep. 123"] {
+            assert_eq!(NUMBER_QUERY.captures(q).map(|c| c[1].to_owned()).as_deref(), Some("123"), "{}", q);
+        }
+        for q in ["123 stelle", "ppp", "pgdr 3"] {
+            assert!(NUMBER_QUERY.captures(q).is_none(), "{}", q);
+        }
+    }
+
+    #[test]
+    fn title_scores() {
+        let title = "PPP 123 - Perché Hollow Knight è così difficile?";
+        assert_eq!(score("hollow knight", title), 1.0);
+        assert_eq!(score("perche", title), 1.0);
+        assert_eq!(score("hollow", title), 1.0);
+        // a prefix, then a typo
+        assert_eq!(score("holl", title), 0.8);
+        assert_eq!(score("hollw", title), 0.6);
+        assert_eq!(score("hollow zelda", title), 0.5);
+        assert_eq!(score("zelda", title), 0.0);
+        assert_eq!(score("", title), 0.0);
+        let special = "Speciale: PGdR™ - La Campagna, Sessione 3";
+        assert_eq!(score("pgdr sessione", special), 1.0);
+        assert!(score("pgdr campagna", special) > score("pgdr campagna", title));
+        assert!(score("speciale", special) >= MIN_TITLE_SCORE);
+    }
+}
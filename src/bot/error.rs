@@ -1,6 +1,6 @@
 use std::fmt::{self, Display, Formatter};
 
-use super::search::SearchError;
+use super::{query::QueryError, search::SearchError};
 
 #[derive(Debug)]
 pub enum BotError {
@@ -10,16 +10,33 @@ pub enum BotError {
     NotImplemented,
     SearchError(SearchError),
     MalformedQuery,
+    InvalidQuery(QueryError),
+    TranscriptMissing,
+    SummaryMissing,
+    AudioMissing,
+    ClipTooLong,
+    ClipFailed,
+    Io(std::io::Error),
+    #[cfg(feature = "index")]
+    Index(crate::index::IndexError),
 }
 
 impl BotError {
     pub fn respond_client(&self) -> String {
+        if let BotError::SearchError(e @ SearchError::Ambiguous(candidates)) = self {
+            return format!(
+                "{}:\n{}",
+                e.respond_client(),
+                candidates.iter().map(|(id, title)| format!("{}: {}", id, title)).collect::<Vec<_>>().join("\n"),
+            );
+        }
         if matches!(self, BotError::SearchError(_)) {
             match self {
                 BotError::SearchError(e) => return e.respond_client().to_string(),
                 _ => unreachable!(),
             }
         }
+        let invalid_query;
         let r = format!(
             "c'è stato un problema nel generare la risposta: {}",
             match self {
@@ -29,6 +46,18 @@ impl BotError {
                 BotError::NotImplemented => "questa funzionalità non è implementata",
                 BotError::SearchError(e) => e.respond_client(),
                 BotError::MalformedQuery => "query malformata",
+                BotError::InvalidQuery(e) => {
+                    invalid_query = format!("query malformata: {}", e.respond_client());
+                    &invalid_query
+                }
+                BotError::TranscriptMissing => "la trascrizione di questo episodio non è disponibile",
+                BotError::SummaryMissing => "il riassunto di questo episodio non è disponibile",
+                BotError::AudioMissing => "l'audio di questo episodio non è disponibile",
+                BotError::ClipTooLong => "la clip richiesta è troppo lunga",
+                BotError::ClipFailed => "errore nel generare la clip",
+                BotError::Io(_) => "errore interno",
+                #[cfg(feature = "index")]
+                BotError::Index(_) => "errore nell'indice di ricerca",
             },
         );
         #[cfg(debug_assertions)]
@@ -69,3 +98,21 @@ impl From<SearchError> for BotError {
     }
 }
 
+impl From<QueryError> for BotError {
+    fn from(e: QueryError) -> Self {
+        BotError::InvalidQuery(e)
+    }
+}
+
+impl From<std::io::Error> for BotError {
+    fn from(e: std::io::Error) -> Self {
+        BotError::Io(e)
+    }
+}
+
+#[cfg(feature = "index")]
+impl From<crate::index::IndexError> for BotError {
+    fn from(e: crate::index::IndexError) -> Self {
+        BotError::Index(e)
+    }
+}
@@ -0,0 +1,92 @@
+use std::time::Duration;
+use teloxide::utils::markdown;
+
+use crate::{spreaker::Episode, transcript::FromTo};
+
+pub static EPISODE_URL: &str = "https://www.spreaker.com/episode";
+
+/// Link to an episode page, optionally starting playback at `at`.
+pub fn episode_url(id: u32, at: Option<Duration>) -> String {
+    match at {
+        Some(at) => format!("{}/{}?t={}", EPISODE_URL, id, at.as_secs()),
+        None => format!("{}/{}", EPISODE_URL, id),
+    }
+}
+
+/// **Markdown** link to an episode, with its title as text.
+pub fn episode_link(episode: &Episode, at: Option<Duration>) -> String {
+    markdown::link(&episode_url(episode.id, at), &markdown::escape(&episode.title))
+}
+
+/// `mm:ss`, or `h:mm:ss` from one hour on.
+pub fn format_time(d: Duration) -> String {
+    let s = d.as_secs();
+    if s >= 3600 {
+        format!("{}:{:02}:{:02}", s / 3600, s / 60 % 60, s % 60)
+    } else {
+        format!("{:02}:{:02}", s / 60, s % 60)
+    }
+}
+
+pub fn format_range(t: &FromTo) -> String {
+    format!("{} - {}", format_time(t.from), format_time(t.to))
+}
+
+/// Parse `ss`, `mm:ss` or `h:mm:ss`.
+pub fn parse_time(s: &str) -> Option<Duration> {
+    let mut secs = 0u64;
+    let parts: Vec<&str> = s.split(':').collect();
+    if parts.len() > 3 {
+        return None;
+    }
+    for p in parts {
+        secs = secs.checked_mul(60)?.checked_add(p.parse::<u64>().ok()?)?;
+    }
+    Some(Duration::from_secs(secs))
+}
+
+/// Parse a `{time}-{time}` range, see `parse_time`.
+pub fn parse_range(s: &str) -> Option<FromTo> {
+    let (from, to) = s.split_once('-')?;
+    let r = FromTo { from: parse_time(from)?, to: parse_time(to)? };
+    (r.to > r.from).then_some(r)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn times() {
+        for (secs, time) in [
+            (0, "00:00"),
+            (59, "00:59"),
+            (59 * 60 + 59, "59:59"),
+            (3600, "1:00:00"),
+            (3600 + 61, "1:01:01"),
+            (10 * 3600 + 5, "10:00:05"),
+            (123 * 3600, "123:00:00"),
+        ] {
+            assert_eq!(format_time(Duration::from_secs(secs)), time);
+        }
+        // the fraction of second is dropped
+        assert_eq!(format_time(Duration::from_millis(59_999)), "00:59");
+    }
+
+    #[test]
+    fn urls() {
+        assert_eq!(episode_url(42, None), format!("{}/42", EPISODE_URL));
+        for (secs, t) in [(0, "0"), (59 * 60 + 59, "3599"), (3600, "3600"), (10 * 3600 + 1, "36001")] {
+            assert_eq!(episode_url(42, Some(Duration::from_millis(secs * 1000 + 999))), format!("{}/42?t={}", EPISODE_URL, t));
+        }
+    }
+
+    #[test]
+    fn ranges() {
+        let range = |from, to| FromTo { from: Duration::from_secs(from), to: Duration::from_secs(to) };
+        assert_eq!(format_range(&range(59, 3600)), "00:59 - 1:00:00");
+        assert_eq!(parse_range("1:00-1:02:03").map(|r| format_range(&r)).as_deref(), Some("01:00 - 1:02:03"));
+        assert_eq!(parse_time("1:00:00:00"), None);
+        assert!(parse_range("2:00-1:00").is_none());
+    }
+}
@@ -0,0 +1,236 @@
+//! Approximate matching of query terms against transcripts, for the words Whisper mishears:
+//! mostly English names spoken in Italian, e.g. "Undertale" transcribed as "under tail".
+//!
+//! Everything here works on transliterated text (see `NormalizedText`), which is plain ASCII.
+
+/// Words a run of transcript words may have beyond those of the query term, so that a term can
+/// match a word split in pieces.
+const MAX_EXTRA_WORDS: usize = 1;
+/// Runs with extra words can't start or end with a word shorter than this, or an article or
+/// conjunction next to the right word would be part of the match.
+const MIN_PIECE_LENGTH: usize = 3;
+
+/// A transcript word, as a byte range in the transliterated text and its lowercase letters.
+struct Word {
+    start: usize,
+    end: usize,
+    letters: String,
+}
+
+/// A query term: a word or a quoted phrase.
+struct Term {
+    letters: String,
+    key: String,
+    words: usize,
+    max_distance: usize,
+}
+
+impl Term {
+    fn new(term: &str) -> Option<Self> {
+        let letters = letters(term);
+        if letters.is_empty() {
+            return None;
+        }
+        Some(Self {
+            key: phonetic(&letters),
+            words: term.split_whitespace().count(),
+            max_distance: max_distance(letters.len()),
+            letters,
+        })
+    }
+
+    /// Distance between the term and `window`, if close enough to be a match. Sounding the same
+    /// counts as a match at the maximum distance.
+    fn distance(&self, window: &str) -> Option<usize> {
+        let diff = self.letters.len().abs_diff(window.len());
+        if diff <= self.max_distance {
+            let d = levenshtein(&self.letters, window);
+            if d <= self.max_distance {
+                return Some(d);
+            }
+        }
+        // short keys would make every other word sound the same
+        if self.key.len() >= 3 && diff <= self.letters.len() / 2 && phonetic(window) == self.key {
+            return Some(self.max_distance);
+        }
+        None
+    }
+}
+
+/// Edits tolerated for a term of `len` letters: short words must match exactly.
+pub fn max_distance(len: usize) -> usize {
+    match len {
+        0..=3 => 0,
+        4..=5 => 1,
+        6..=9 => 2,
+        _ => 3,
+    }
+}
+
+/// Lowercase letters and digits of `s`, without spaces or punctuation.
+fn letters(s: &str) -> String {
+    s.chars().filter(char::is_ascii_alphanumeric).map(|c| c.to_ascii_lowercase()).collect()
+}
+
+/// Rough pronunciation of `letters` as an Italian speaker would say it: letters with the same
+/// sound are merged, `h` is silent, double letters are single, every run of vowels is the same
+/// vowel and a final vowel is dropped, since it is either an Italian inflection or a silent
+/// English `e`.
+fn phonetic(letters: &str) -> String {
+    let mut s = letters.to_owned();
+    for (from, to) in [("sch", "sk"), ("ch", "k"), ("ck", "k"), ("gh", "g"), ("ph", "f"), ("th", "t"), ("sh", "s"), ("qu", "k"), ("gli", "li"), ("gn", "n")] {
+        s = s.replace(from, to);
+    }
+    let mut key = String::with_capacity(s.len());
+    for c in s.chars() {
+        let c = match c {
+            'c' | 'k' | 'q' => 'k',
+            'j' => 'g',
+            'z' | 'x' => 's',
+            'w' => 'v',
+            'a' | 'e' | 'i' | 'o' | 'u' | 'y' => 'a',
+            'h' => continue,
+            c => c,
+        };
+        if !key.ends_with(c) {
+            key.push(c);
+        }
+    }
+    if key.len() > 1 && key.ends_with('a') {
+        key.pop();
+    }
+    key
+}
+
+pub fn levenshtein(a: &str, b: &str) -> usize {
+    let b = b.as_bytes();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.bytes().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let cur = row[j + 1];
+            row[j + 1] = (prev + (ca != *cb) as usize).min(row[j] + 1).min(cur + 1);
+            prev = cur;
+        }
+    }
+    row[b.len()]
+}
+
+fn words(text: &str) -> Vec<Word> {
+    let mut words = vec![];
+    let mut start = None;
+    for (i, c) in text.char_indices().chain(std::iter::once((text.len(), ' '))) {
+        match (start, c.is_ascii_alphanumeric()) {
+            (None, true) => start = Some(i),
+            (Some(s), false) => {
+                words.push(Word { start: s, end: i, letters: letters(&text[s..i]) });
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    words
+}
+
+/// Byte ranges in `text` of the runs of words that look or sound like one of `terms`. Matches
+/// don't overlap, and each is the closest of the runs starting at the same word.
+pub fn find(text: &str, terms: &[String]) -> Vec<(usize, usize)> {
+    let terms = terms.iter().filter_map(|t| Term::new(t)).collect::<Vec<_>>();
+    let words = words(text);
+    let mut found = vec![];
+    let mut i = 0;
+    while i < words.len() {
+        let mut best: Option<(usize, usize)> = None;
+        for t in terms.iter() {
+            let mut window = String::new();
+            for (n, w) in words[i..].iter().take(t.words + MAX_EXTRA_WORDS).enumerate() {
+                window.push_str(&w.letters);
+                if window.len() > t.letters.len() * 2 {
+                    break;
+                }
+                if n + 1 > t.words && (words[i].letters.len() < MIN_PIECE_LENGTH || w.letters.len() < MIN_PIECE_LENGTH) {
+                    continue;
+                }
+                // prefer the closest match, then the one with fewer words
+                if let Some(d) = t.distance(&window) {
+                    if best.is_none_or(|(bd, _)| d < bd) {
+                        best = Some((d, n + 1));
+                    }
+                }
+            }
+        }
+        match best {
+            Some((_, n)) => {
+                found.push((words[i].start, words[i + n - 1].end));
+                i += n;
+            }
+            None => i += 1,
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn terms(t: &[&str]) -> Vec<String> {
+        t.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn levenshtein_distance() {
+        assert_eq!(levenshtein("kitten", "sitting"), 3);
+        assert_eq!(levenshtein("", "abc"), 3);
+        assert_eq!(levenshtein("abc", ""), 3);
+        assert_eq!(levenshtein("zelda", "zelda"), 0);
+        assert_eq!(levenshtein("undertale", "undertail"), 2);
+    }
+
+    #[test]
+    fn short_words_match_exactly() {
+        assert_eq!(max_distance(0), 0);
+        assert_eq!(max_distance(3), 0);
+        assert_eq!(max_distance(4), 1);
+        assert_eq!(max_distance(5), 1);
+        assert_eq!(max_distance(6), 2);
+        assert_eq!(max_distance(9), 2);
+        assert_eq!(max_distance(10), 3);
+    }
+
+    #[test]
+    fn phonetic_keys() {
+        assert_eq!(phonetic("undertale"), "andartal");
+        assert_eq!(phonetic("undertale"), phonetic("undertail"));
+        assert_eq!(phonetic("chrono"), phonetic("crono"));
+        assert_eq!(phonetic("photoshop"), phonetic("fotosop"));
+        assert_eq!(phonetic("jazz"), phonetic("gas"));
+        assert_ne!(phonetic("mario"), phonetic("metroid"));
+    }
+
+    #[test]
+    fn find_split_word() {
+        let text = "ho giocato a under tail ieri";
+        let found = find(text, &terms(&["Undertale"]));
+        assert_eq!(found, vec![(13, 23)]);
+        assert_eq!(&text[found[0].0..found[0].1], "under tail");
+    }
+
+    #[test]
+    fn find_skips_articles_and_short_words() {
+        let text = "la zelda e tre";
+        assert_eq!(find(text, &terms(&["Zelda"])), vec![(3, 8)]);
+        // three letters must match exactly, and their keys are too short to sound the same
+        assert!(find(text, &terms(&["the"])).is_empty());
+        assert!(find(text, &terms(&["", "!"])).is_empty());
+    }
+
+    #[test]
+    fn find_closest_and_non_overlapping() {
+        let text = "metroid metroyd metroid prime";
+        // on a tie the run with fewer words wins
+        assert_eq!(find(text, &terms(&["metroid", "metroid prime"])), vec![(0, 7), (8, 15), (16, 23)]);
+        assert_eq!(find(text, &terms(&["metroid prime"])), vec![(16, 29)]);
+    }
+}
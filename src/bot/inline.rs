@@ -0,0 +1,96 @@
+use std::{collections::HashMap, time::{Duration, Instant}};
+use lazy_static::lazy_static;
+#[allow(unused_imports)]
+use log::{debug, info, trace};
+use teloxide::{types::{InlineQueryResult, InlineQueryResultArticle, InputMessageContent, InputMessageContentText, ParseMode}, utils::markdown};
+use tokio::sync::Mutex;
+
+use crate::db::PPPDatabase;
+use super::{format::{episode_link, format_time}, query::Filters, search::SearchError, BotError};
+
+/// Telegram accepts at most 50 results per answer.
+const MAX_INLINE_RESULTS: usize = 50;
+/// Transcript results come with the whole transcript for their snippets: keep them few.
+const MAX_TRANSCRIPT_RESULTS: usize = 5;
+const CACHE_TTL: Duration = Duration::from_secs(600);
+
+lazy_static! {
+    static ref CACHE: Mutex<HashMap<String, (Instant, Vec<InlineQueryResult>)>> = Mutex::new(HashMap::new());
+}
+
+impl PPPDatabase {
+    /// Build the answer to an inline query: transcript hits first, best ranked first, each with a
+    /// hint and a link at the time of its first snippet, then episodes matching on title or description.
+    /// Answers are cached for `CACHE_TTL`.
+    pub async fn inline_results(&self, query: &str) -> Result<Vec<InlineQueryResult>, BotError> {
+        let key = query.trim().to_lowercase();
+        {
+            let mut cache = CACHE.lock().await;
+            cache.retain(|_, (t, _)| t.elapsed() < CACHE_TTL);
+            if let Some((_, r)) = cache.get(&key) {
+                debug!("inline cache hit for {:?}", key);
+                return Ok(r.clone());
+            }
+        }
+
+        let mut results = vec![];
+        let ranking = found(self.search_transcript_ranking(&key, &Filters::default()).await)?.unwrap_or_default();
+        let ranking = &ranking[..ranking.len().min(MAX_TRANSCRIPT_RESULTS)];
+        for r in self.search_transcript_results(&key, &Filters::default(), ranking).await? {
+            let first = r.snippets.first();
+            let at = first.map(|m| m.start.unwrap_or(m.time.from));
+            let text = match (first, at) {
+                (Some(m), Some(at)) => format!(
+                    "{} {}\n{}",
+                    episode_link(&r.episode, Some(at)),
+                    markdown::escape(&format!("({})", format_time(at))),
+                    markdown::blockquote(&markdown::escape(&format!("...{}...", m.hint))),
+                ),
+                _ => episode_link(&r.episode, None),
+            };
+            results.push(article(
+                format!("t{}", r.episode.id),
+                &r.episode.title,
+                first.map(|m| format!("{}: ...{}...", format_time(at.unwrap_or_default()), m.hint)).unwrap_or_default(),
+                text,
+            ));
+        }
+        for r in found(self.search_meta(key.clone(), &Filters::default()).await)?.unwrap_or_default() {
+            if results.len() >= MAX_INLINE_RESULTS {
+                break
+            }
+            // point to the chapter of the description that matched, when there is one
+            let (description, at) = match r.chapter {
+                Some(i) => (r.episode.chapters[i].title.clone(), r.episode.chapter_time(i).map(|t| t.from)),
+                None => (r.episode.description.chars().take(100).collect::<String>(), None),
+            };
+            results.push(article(format!("m{}", r.episode.id), &r.episode.title, description, episode_link(&r.episode, at)));
+        }
+        results.truncate(MAX_INLINE_RESULTS);
+
+        trace!("caching {} inline results for {:?}", results.len(), key);
+        CACHE.lock().await.insert(key, (Instant::now(), results.clone()));
+        Ok(results)
+    }
+}
+
+fn article(id: String, title: &str, description: String, text: String) -> InlineQueryResult {
+    let mut a = InlineQueryResultArticle::new(
+        id,
+        title,
+        InputMessageContent::Text(InputMessageContentText::new(text).parse_mode(ParseMode::MarkdownV2)),
+    );
+    if !description.is_empty() {
+        a = a.description(description);
+    }
+    InlineQueryResult::Article(a)
+}
+
+/// Turn `NoResults` into `None`, since an inline query with no hits is not an error.
+fn found<T>(r: Result<T, SearchError>) -> Result<Option<T>, BotError> {
+    match r {
+        Ok(r) => Ok(Some(r)),
+        Err(SearchError::NoResults) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
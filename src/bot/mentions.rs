@@ -0,0 +1,54 @@
+use std::time::Instant;
+use futures_util::TryStreamExt;
+#[allow(unused_imports)]
+use log::{debug, trace};
+use mongodb::bson::doc;
+use serde::Deserialize;
+
+use crate::{db::PPPDatabase, spreaker::Episode, transcript::Mention};
+use super::search::SearchError;
+
+/// Most episodes listed for a title.
+const MAX_MENTION_RESULTS: i64 = 100;
+
+/// An episode talking about a title, see `MentionDictionary`.
+#[derive(Deserialize, Debug)]
+pub struct MentionHit {
+    pub episode: Episode,
+    pub mention: Mention,
+}
+
+impl PPPDatabase {
+    /// Episodes mentioning a title containing `title`, the ones mentioning it the most first.
+    /// An episode mentioning more titles matching `title` is listed once for each.
+    pub async fn search_mentions(&self, title: &str) -> Result<Vec<MentionHit>, SearchError> {
+        self._ensure_status().await;
+        let _t = Instant::now();
+        let pattern = regex::escape(title.trim());
+        if pattern.is_empty() {
+            return Err(SearchError::NoResults);
+        }
+        let r = mongodb::bson::Regex { pattern, options: "i".to_string() };
+        let hits = self.db
+            .collection::<()>("mentions")
+            .aggregate(vec![
+                doc!{"$match": {"mentions.title": r.clone()}},
+                doc!{"$unwind": "$mentions"},
+                doc!{"$match": {"mentions.title": r}},
+                doc!{"$sort": {"mentions.count": -1}},
+                doc!{"$limit": MAX_MENTION_RESULTS},
+                doc!{"$lookup": {"from": "episodes", "localField": "episode_id", "foreignField": "id", "as": "episode"}},
+                doc!{"$unwind": "$episode"},
+                doc!{"$project": {"_id": 0, "episode": 1, "mention": "$mentions"}},
+            ])
+            .with_type::<MentionHit>()
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        trace!("timings: search_mentions: {:?}", _t.elapsed());
+        if hits.is_empty() {
+            return Err(SearchError::NoResults);
+        }
+        Ok(hits)
+    }
+}
@@ -1,7 +1,20 @@
 mod user;
 mod error;
 mod search;
+mod inline;
+mod fuzzy;
+mod semantic;
+mod query;
+mod episode;
+mod mentions;
 pub mod strings;
+pub mod format;
+pub mod clip;
+pub mod pages;
 
 pub use error::BotError;
 pub use user::BotUser;
+pub use semantic::SemanticHit;
+pub use mentions::MentionHit;
+pub use query::{Filters, Query, QueryError, Term};
+pub use search::{check_user_regex, first_mentions, text_query_pattern, EpisodeOffsetMatch, RankedResult, SearchError, SearchMode};
@@ -0,0 +1,347 @@
+use std::{collections::HashMap, sync::atomic::{AtomicU64, Ordering}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+use lazy_static::lazy_static;
+#[allow(unused_imports)]
+use log::{debug, trace};
+use teloxide::{types::{InlineKeyboardButton, InlineKeyboardMarkup}, utils::markdown};
+use tokio::sync::Mutex;
+
+use crate::transcript::ExportFormat;
+use super::{Filters, SearchMode};
+
+/// Telegram's limit on the length of a message.
+const MAX_PAGE_LENGTH: usize = 4096;
+const ENTRIES_PER_PAGE: usize = 10;
+/// Results of a ranking come with the whole transcript of their episode: keep their pages short.
+const RANKED_PER_PAGE: usize = 5;
+/// How long the buttons of a message keep working.
+pub const PAGES_TTL: Duration = Duration::from_secs(60 * 60 * 6);
+
+/// A result line, with optional buttons acting on it.
+pub struct Entry {
+    pub text: String,
+    pub buttons: Vec<InlineKeyboardButton>,
+}
+
+impl From<String> for Entry {
+    fn from(text: String) -> Self {
+        Self { text, buttons: vec![] }
+    }
+}
+
+#[derive(Clone)]
+pub struct Page {
+    pub text: String,
+    /// One row for each entry of the page that has buttons.
+    pub buttons: Vec<Vec<InlineKeyboardButton>>,
+}
+
+/// A search whose results can be refined from a button, e.g. by searching inside one of the
+/// episodes found.
+#[derive(Clone, Debug)]
+pub struct StoredQuery {
+    /// What to look for in single transcripts, see `PPPDatabase::search_transcript_one`.
+    pub pattern: String,
+    pub filters: Filters,
+    pub mode: SearchMode,
+}
+
+/// Episodes found by a search, best first, whose results are only built a page at a time.
+#[derive(Clone, Debug)]
+pub struct StoredRanking {
+    /// **Markdown** header of every page.
+    pub header: String,
+    /// What was searched, see `PPPDatabase::search_transcript_results`.
+    pub text: String,
+    pub filters: Filters,
+    /// Episode ids, with their score.
+    pub ranking: Vec<(u32, f64)>,
+    /// Key of the `StoredQuery` run by the buttons of the results.
+    pub query: u64,
+}
+
+/// Actions encoded in the callback data of the buttons we send.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Callback {
+    /// Show page `.1` of the results stored under `.0`.
+    Page(u64, usize),
+    /// Show page `.1` of the ranking stored under `.0`.
+    Ranking(u64, usize),
+    /// Run the query stored under `.0` inside episode `.1`.
+    SearchEpisode(u64, u32),
+    /// Show the details of an episode.
+    Details(u32),
+    /// Show the summary of an episode.
+    Summary(u32),
+    /// Send the transcript of an episode in a format.
+    Subtitles(u32, ExportFormat),
+    /// Cut the clip asked with the `/clip` arguments stored under `.0` out of episode `.1`.
+    Clip(u64, u32),
+}
+
+impl Callback {
+    pub fn parse(data: &str) -> Option<Self> {
+        let mut parts = data.split(':');
+        let c = match parts.next()? {
+            "p" => Self::Page(parts.next()?.parse().ok()?, parts.next()?.parse().ok()?),
+            "q" => Self::Ranking(parts.next()?.parse().ok()?, parts.next()?.parse().ok()?),
+            "s" => Self::SearchEpisode(parts.next()?.parse().ok()?, parts.next()?.parse().ok()?),
+            "d" => Self::Details(parts.next()?.parse().ok()?),
+            "r" => Self::Summary(parts.next()?.parse().ok()?),
+            "c" => Self::Clip(parts.next()?.parse().ok()?, parts.next()?.parse().ok()?),
+            "t" => Self::Subtitles(parts.next()?.parse().ok()?, parts.next()?.parse().ok()?),
+            _ => return None,
+        };
+        Some(c)
+    }
+
+    pub fn data(&self) -> String {
+        match self {
+            Self::Page(key, n) => format!("p:{}:{}", key, n),
+            Self::Ranking(key, n) => format!("q:{}:{}", key, n),
+            Self::SearchEpisode(key, id) => format!("s:{}:{}", key, id),
+            Self::Details(id) => format!("d:{}", id),
+            Self::Summary(id) => format!("r:{}", id),
+            Self::Subtitles(id, format) => format!("t:{}:{}", id, format),
+            Self::Clip(key, id) => format!("c:{}:{}", key, id),
+        }
+    }
+
+    pub fn button<T: Into<String>>(&self, text: T) -> InlineKeyboardButton {
+        InlineKeyboardButton::callback(text, self.data())
+    }
+}
+
+struct Stored<T> {
+    created: Instant,
+    value: T,
+}
+
+type Store<T> = Mutex<HashMap<u64, Stored<T>>>;
+
+lazy_static! {
+    static ref PAGES: Store<Vec<Page>> = Mutex::new(HashMap::new());
+    static ref QUERIES: Store<StoredQuery> = Mutex::new(HashMap::new());
+    static ref RANKINGS: Store<StoredRanking> = Mutex::new(HashMap::new());
+    static ref CLIPS: Store<Vec<String>> = Mutex::new(HashMap::new());
+    // seeded with the start time, so that buttons of messages sent before a restart don't point
+    // to someone else's results
+    static ref NEXT_KEY: AtomicU64 = AtomicU64::new(SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0) << 20);
+}
+
+async fn put<T>(store: &Store<T>, value: T) -> u64 {
+    let key = NEXT_KEY.fetch_add(1, Ordering::Relaxed);
+    let mut stored = store.lock().await;
+    stored.retain(|_, p| p.created.elapsed() < PAGES_TTL);
+    stored.insert(key, Stored { created: Instant::now(), value });
+    key
+}
+
+async fn with<T, R>(store: &Store<T>, key: u64, f: impl FnOnce(&T) -> Option<R>) -> Option<R> {
+    let stored = store.lock().await;
+    f(&stored.get(&key).filter(|p| p.created.elapsed() < PAGES_TTL)?.value)
+}
+
+/// Split **markdown** `entries` into pages of at most `ENTRIES_PER_PAGE` entries, each fitting
+/// in a single message, and each starting with `header`. Entries are separated by `separator`.
+pub fn paginate(header: &str, entries: Vec<Entry>, separator: &str) -> Vec<Page> {
+    let new_page = || Page { text: header.to_owned(), buttons: vec![] };
+    let mut pages = vec![];
+    let mut page = new_page();
+    let mut count = 0;
+    for e in entries {
+        if count == ENTRIES_PER_PAGE || (count > 0 && page.text.len() + separator.len() + e.text.len() > MAX_PAGE_LENGTH - 32) {
+            pages.push(page);
+            page = new_page();
+            count = 0;
+        }
+        if !page.text.is_empty() {
+            page.text.push_str(separator);
+        }
+        page.text.push_str(&e.text);
+        if !e.buttons.is_empty() {
+            page.buttons.push(e.buttons);
+        }
+        count += 1;
+    }
+    pages.push(page);
+    pages
+}
+
+/// Build a single page out of **markdown** `entries`, like those of `paginate`. Entries that
+/// would make it too long for a message keep only their first line, the last ones first.
+pub fn fit(header: &str, entries: Vec<Entry>, separator: &str) -> Page {
+    let mut texts = entries.iter().map(|e| e.text.as_str()).collect::<Vec<_>>();
+    let length = |texts: &[&str]| header.len() + texts.iter().map(|t| separator.len() + t.len()).sum::<usize>();
+    for i in (0..texts.len()).rev() {
+        if length(&texts) <= MAX_PAGE_LENGTH - 32 {
+            break;
+        }
+        texts[i] = texts[i].lines().next().unwrap_or_default();
+    }
+    let mut page = Page { text: header.to_owned(), buttons: vec![] };
+    for (text, e) in texts.into_iter().zip(&entries) {
+        if !page.text.is_empty() {
+            page.text.push_str(separator);
+        }
+        page.text.push_str(text);
+        if !e.buttons.is_empty() {
+            page.buttons.push(e.buttons.clone());
+        }
+    }
+    page
+}
+
+/// Keep `pages` around for the inline keyboard. Returns the key used in callback data.
+pub async fn store(pages: Vec<Page>) -> u64 {
+    trace!("storing {} pages", pages.len());
+    put(&PAGES, pages).await
+}
+
+/// Get page `n` of the results stored under `key`, along with the number of pages.
+pub async fn get(key: u64, n: usize) -> Option<(Page, usize)> {
+    with(&PAGES, key, |p| Some((p.get(n)?.clone(), p.len()))).await
+}
+
+pub async fn store_query(query: StoredQuery) -> u64 {
+    trace!("storing query {:?}", query);
+    put(&QUERIES, query).await
+}
+
+pub async fn get_query(key: u64) -> Option<StoredQuery> {
+    with(&QUERIES, key, |q| Some(q.clone())).await
+}
+
+/// Keep `ranking` around for the inline keyboard. Returns the key used in callback data.
+pub async fn store_ranking(ranking: StoredRanking) -> u64 {
+    trace!("storing a ranking of {} episodes", ranking.ranking.len());
+    put(&RANKINGS, ranking).await
+}
+
+/// Get page `n` of the ranking stored under `key`, holding only the episodes of that page, along
+/// with the number of pages.
+pub async fn get_ranking(key: u64, n: usize) -> Option<(StoredRanking, usize)> {
+    with(&RANKINGS, key, |r| {
+        let pages = r.ranking.len().div_ceil(RANKED_PER_PAGE);
+        let ranking = r.ranking.get(n * RANKED_PER_PAGE..)?.iter().take(RANKED_PER_PAGE).copied().collect::<Vec<_>>();
+        match ranking.is_empty() {
+            true => None,
+            false => Some((StoredRanking { header: r.header.clone(), text: r.text.clone(), filters: r.filters.clone(), ranking, query: r.query }, pages)),
+        }
+    }).await
+}
+
+/// Keep the arguments of a `/clip` after the episode, for the buttons picking the episode.
+pub async fn store_clip(args: Vec<String>) -> u64 {
+    trace!("storing clip arguments {:?}", args);
+    put(&CLIPS, args).await
+}
+
+pub async fn get_clip(key: u64) -> Option<Vec<String>> {
+    with(&CLIPS, key, |a| Some(a.clone())).await
+}
+
+/// Page `n` of `pages`, with the page number appended if there is more than one.
+pub fn render(page: &Page, n: usize, pages: usize) -> String {
+    if pages > 1 {
+        format!("{}\n\n{}", page.text, markdown::italic(&markdown::escape(&format!("Pagina {}/{}", n + 1, pages))))
+    } else {
+        page.text.clone()
+    }
+}
+
+/// The buttons of `page`, followed by those moving to the pages next to it, whose callbacks
+/// are built by `goto`, e.g. `|n| Callback::Page(key, n)`.
+pub fn keyboard(page: &Page, n: usize, pages: usize, goto: impl Fn(usize) -> Callback) -> InlineKeyboardMarkup {
+    let mut rows = page.buttons.clone();
+    let mut nav = vec![];
+    if n > 0 {
+        nav.push(goto(n - 1).button("« Precedente"));
+    }
+    if n + 1 < pages {
+        nav.push(goto(n + 1).button("Successiva »"));
+    }
+    if !nav.is_empty() {
+        rows.push(nav);
+    }
+    InlineKeyboardMarkup::new(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn callbacks_round_trip() {
+        for c in [
+            Callback::Page(u64::MAX, 0),
+            Callback::Ranking(1 << 40, 12),
+            Callback::SearchEpisode(7, 56245683),
+            Callback::Details(1),
+            Callback::Summary(u32::MAX),
+            Callback::Subtitles(3, ExportFormat::WebVtt),
+            Callback::Clip(9, 4),
+        ] {
+            let data = c.data();
+            // Telegram's limit on callback data
+            assert!(data.len() <= 64, "{}", data);
+            assert_eq!(Callback::parse(&data), Some(c));
+        }
+    }
+
+    #[test]
+    fn malformed_callbacks() {
+        for data in ["", "p", "p:1", "p:x:1", "p:1:-1", "d:", "d:99999999999", "t:1:doc", "x:1", "P:1:1", "page:1:1", "🍕"] {
+            assert_eq!(Callback::parse(data), None, "{}", data);
+        }
+    }
+
+    #[test]
+    fn paginate_boundaries() {
+        let entries = |n: usize| (0..n).map(|i| Entry::from(i.to_string())).collect::<Vec<_>>();
+        // no entries still make a page, with the header alone
+        let pages = paginate("h", vec![], "\n");
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages[0].text, "h");
+        let pages = paginate("h", entries(ENTRIES_PER_PAGE), "\n");
+        assert_eq!(pages.len(), 1);
+        let pages = paginate("h", entries(ENTRIES_PER_PAGE + 1), "\n");
+        assert_eq!(pages.len(), 2);
+        assert_eq!(pages[1].text, format!("h\n{}", ENTRIES_PER_PAGE));
+        // without a header, pages start with their first entry
+        let pages = paginate("", entries(2 * ENTRIES_PER_PAGE), ", ");
+        assert_eq!(pages.iter().map(|p| p.text.split(", ").count()).collect::<Vec<_>>(), vec![ENTRIES_PER_PAGE, ENTRIES_PER_PAGE]);
+        assert!(pages[1].text.starts_with(&ENTRIES_PER_PAGE.to_string()));
+    }
+
+    #[test]
+    fn paginate_splits_long_pages() {
+        let long = "x".repeat(MAX_PAGE_LENGTH / 3);
+        let entries = (0..4).map(|_| Entry { text: long.clone(), buttons: vec![Callback::Details(1).button("d")] }).collect();
+        let pages = paginate("", entries, "\n");
+        assert_eq!(pages.len(), 2);
+        assert!(pages.iter().all(|p| p.text.len() <= MAX_PAGE_LENGTH));
+        assert_eq!(pages.iter().map(|p| p.buttons.len()).collect::<Vec<_>>(), vec![2, 2]);
+    }
+
+    #[test]
+    fn page_numbers_and_navigation() {
+        let page = Page { text: "t".to_owned(), buttons: vec![] };
+        assert_eq!(render(&page, 0, 1), "t");
+        assert!(render(&page, 1, 3).ends_with("Pagina 2/3_"));
+        let nav = |n, pages| keyboard(&page, n, pages, |n| Callback::Page(5, n)).inline_keyboard.concat().len();
+        assert_eq!(nav(0, 1), 0);
+        assert_eq!(nav(0, 3), 1);
+        assert_eq!(nav(1, 3), 2);
+        assert_eq!(nav(2, 3), 1);
+    }
+
+    #[test]
+    fn fit_shortens_the_last_entries_first() {
+        let long = |c: &str| format!("{}\n{}", c, c.repeat(MAX_PAGE_LENGTH / 2));
+        let entries = vec![long("a"), long("b"), long("c")].into_iter().map(Entry::from).collect();
+        let page = fit("h", entries, "\n\n");
+        assert!(page.text.len() <= MAX_PAGE_LENGTH);
+        assert!(page.text.starts_with(&format!("h\n\n{}\n\n", long("a"))));
+        assert!(page.text.ends_with("\n\nb\n\nc"));
+    }
+}
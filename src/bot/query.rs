@@ -0,0 +1,321 @@
+use std::time::Duration;
+use chrono::{DateTime, NaiveDate, Utc};
+use lazy_static::lazy_static;
+use mongodb::bson::{doc, Document};
+use regex::Regex;
+
+use crate::spreaker::Episode;
+
+/// A search query as typed by the user: words, `"quoted phrases"` and `key:value` filters.
+///
+/// Filters are `after:`, `before:` (`2022`, `2022-01` or `2022-01-31`, the first day of the
+/// period counts), `title:`, `longer:`, `shorter:` (`90m`, `1h30m`, `45` minutes) and
+/// `speaker:`, `series:` (`pgdr`, `speciale`) and `guest:`. Values with spaces can be quoted,
+/// `title:"green oaks"`.
+#[derive(Debug, Default)]
+pub struct Query {
+    pub terms: Vec<Term>,
+    pub filters: Filters,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Term {
+    pub text: String,
+    pub quoted: bool,
+    pub negated: bool,
+}
+
+/// Conditions on the episodes a search may return.
+#[derive(Debug, Default, Clone)]
+pub struct Filters {
+    pub after: Option<DateTime<Utc>>,
+    pub before: Option<DateTime<Utc>>,
+    pub title: Option<String>,
+    pub longer: Option<Duration>,
+    pub shorter: Option<Duration>,
+    /// See `Episode.series`, case insensitive.
+    pub series: Option<String>,
+    /// Part of the name of one of `Episode.guests`.
+    pub guest: Option<String>,
+    /// Only applies to transcripts, see `PPPDatabase::search_transcript_ranking`.
+    pub speaker: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum QueryError {
+    UnclosedQuote,
+    EmptyFilter(String),
+    InvalidDate(String),
+    InvalidDuration(String),
+    /// Excluding words is only supported by searches in all the transcripts.
+    NegatedTerm(String),
+    /// The filter can't be applied to what the command searches.
+    UnsupportedFilter(String),
+}
+
+impl QueryError {
+    pub fn respond_client(&self) -> String {
+        match self {
+            QueryError::UnclosedQuote => "manca una virgoletta di chiusura".to_owned(),
+            QueryError::EmptyFilter(k) => format!("il filtro `{}:` non ha un valore", k),
+            QueryError::InvalidDate(d) => format!("data non valida: `{}`, usa ad esempio 2022, 2022-01 o 2022-01-31", d),
+            QueryError::InvalidDuration(d) => format!("durata non valida: `{}`, usa ad esempio 90m, 1h30m o 45", d),
+            QueryError::NegatedTerm(t) => format!("non si possono escludere parole (`-{}`) nella ricerca in un episodio", t),
+            QueryError::UnsupportedFilter(k) => format!("il filtro `{}:` vale solo per le trascrizioni, usa /sa o /sae", k),
+        }
+    }
+}
+
+impl Term {
+    /// The term as it would be typed, for the MongoDB text search syntax.
+    pub fn raw(&self) -> String {
+        format!(
+            "{}{}{}{}",
+            if self.negated { "-" } else { "" },
+            if self.quoted { "\"" } else { "" },
+            self.text,
+            if self.quoted { "\"" } else { "" },
+        )
+    }
+}
+
+impl Query {
+    pub fn parse(s: &str) -> Result<Self, QueryError> {
+        let mut q = Query::default();
+        for (key, term) in tokenize(s)? {
+            let value = term.text.clone();
+            match key.as_deref() {
+                None => {
+                    q.terms.push(term);
+                    continue;
+                }
+                Some(k) if value.is_empty() => return Err(QueryError::EmptyFilter(k.to_owned())),
+                Some("after") => q.filters.after = Some(parse_date(&value)?),
+                Some("before") => q.filters.before = Some(parse_date(&value)?),
+                Some("title") => q.filters.title = Some(value),
+                Some("longer") => q.filters.longer = Some(parse_duration(&value)?),
+                Some("shorter") => q.filters.shorter = Some(parse_duration(&value)?),
+                Some("speaker") => q.filters.speaker = Some(value),
+                Some("series") => q.filters.series = Some(value),
+                Some("guest") => q.filters.guest = Some(value),
+                Some(k) => unreachable!("unknown filter {}", k),
+            }
+        }
+        Ok(q)
+    }
+
+    /// Words and phrases in the MongoDB text search syntax, quotes and negations included.
+    pub fn text(&self) -> String {
+        self.terms.iter().map(Term::raw).collect::<Vec<_>>().join(" ")
+    }
+
+    /// Words and phrases without quotes, as they are to be found in the text.
+    pub fn plain(&self) -> String {
+        self.terms.iter().map(|t| t.text.as_str()).collect::<Vec<_>>().join(" ")
+    }
+}
+
+impl Filters {
+    pub fn is_empty(&self) -> bool {
+        self.after.is_none() && self.before.is_none() && self.title.is_none()
+            && self.longer.is_none() && self.shorter.is_none() && self.speaker.is_none()
+            && self.series.is_none() && self.guest.is_none()
+    }
+
+    /// MongoDB filter on an episode, whose fields are under `prefix`: `""` when querying the
+    /// episodes collection, `"episode."` for an episode joined to its transcript.
+    pub fn episode_filter(&self, prefix: &str) -> Document {
+        let mut d = doc!{};
+        let mut published = doc!{};
+        if let Some(after) = self.after {
+            published.insert("$gte", after.timestamp());
+        }
+        if let Some(before) = self.before {
+            published.insert("$lt", before.timestamp());
+        }
+        if !published.is_empty() {
+            d.insert(format!("{}published_at", prefix), published);
+        }
+        let mut duration = doc!{};
+        if let Some(longer) = self.longer {
+            duration.insert("$gte", longer.as_millis() as i64);
+        }
+        if let Some(shorter) = self.shorter {
+            duration.insert("$lte", shorter.as_millis() as i64);
+        }
+        if !duration.is_empty() {
+            d.insert(format!("{}duration", prefix), duration);
+        }
+        if let Some(title) = &self.title {
+            d.insert(format!("{}title", prefix), mongodb::bson::Regex { pattern: regex::escape(title), options: "i".to_string() });
+        }
+        if let Some(series) = &self.series {
+            d.insert(format!("{}series", prefix), mongodb::bson::Regex { pattern: format!("^{}$", regex::escape(series)), options: "i".to_string() });
+        }
+        // a regex on an array matches any of its elements
+        if let Some(guest) = &self.guest {
+            d.insert(format!("{}guests", prefix), mongodb::bson::Regex { pattern: regex::escape(guest), options: "i".to_string() });
+        }
+        d
+    }
+
+    /// Same as `episode_filter`, for an episode already at hand.
+    pub fn matches(&self, e: &Episode) -> bool {
+        let duration = Duration::from_millis(e.duration as u64);
+        self.after.is_none_or(|a| e.published_at >= a)
+            && self.before.is_none_or(|b| e.published_at < b)
+            && self.longer.is_none_or(|l| duration >= l)
+            && self.shorter.is_none_or(|s| duration <= s)
+            && self.title.as_ref().is_none_or(|t| e.title.to_lowercase().contains(&t.to_lowercase()))
+            && self.series.as_ref().is_none_or(|s| e.series.as_ref().is_some_and(|es| es.eq_ignore_ascii_case(s)))
+            && self.guest.as_ref().is_none_or(|g| e.guests.iter().any(|eg| eg.to_lowercase().contains(&g.to_lowercase())))
+    }
+}
+
+const FILTERS: [&str; 8] = ["after", "before", "title", "longer", "shorter", "speaker", "series", "guest"];
+
+/// Split `s` into terms, each with the filter it is the value of, if any. Only known filter
+/// names count as such, so that text like `ore 10:30` is left alone.
+fn tokenize(s: &str) -> Result<Vec<(Option<String>, Term)>, QueryError> {
+    let mut tokens = vec![];
+    let mut chars = s.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.peek().is_none() {
+            break;
+        }
+        let negated = chars.next_if_eq(&'-').is_some();
+        let mut word = String::new();
+        let mut key = None;
+        let mut quoted = false;
+        while let Some(c) = chars.next() {
+            match c {
+                '"' if word.is_empty() => {
+                    quoted = true;
+                    loop {
+                        match chars.next() {
+                            Some('"') => break,
+                            Some(c) => word.push(c),
+                            None => return Err(QueryError::UnclosedQuote),
+                        }
+                    }
+                    break;
+                }
+                ':' if key.is_none() && !negated && FILTERS.contains(&word.to_lowercase().as_str()) => {
+                    key = Some(word.to_lowercase());
+                    word.clear();
+                }
+                c if c.is_whitespace() => break,
+                c => word.push(c),
+            }
+        }
+        tokens.push((key, Term { text: word, quoted, negated }));
+    }
+    Ok(tokens)
+}
+
+fn parse_date(s: &str) -> Result<DateTime<Utc>, QueryError> {
+    let parts = s.split('-').map(|p| p.parse::<u32>().ok()).collect::<Option<Vec<_>>>();
+    let date = match parts.as_deref() {
+        Some([y]) => NaiveDate::from_ymd_opt(*y as i32, 1, 1),
+        Some([y, m]) => NaiveDate::from_ymd_opt(*y as i32, *m, 1),
+        Some([y, m, d]) => NaiveDate::from_ymd_opt(*y as i32, *m, *d),
+        _ => None,
+    };
+    date
+        .and_then(|d| d.and_hms_opt(0, 0, 0))
+        .map(|d| d.and_utc())
+        .ok_or_else(|| QueryError::InvalidDate(s.to_owned()))
+}
+
+fn parse_duration(s: &str) -> Result<Duration, QueryError> {
+    lazy_static! {
+        static ref DURATION: Regex = Regex::new(r"^(?:(\d+)h)?(?:(\d+)m)?(?:(\d+)s)?$").unwrap();
+    }
+    let invalid = || QueryError::InvalidDuration(s.to_owned());
+    // a bare number is in minutes
+    if let Ok(m) = s.parse::<u64>() {
+        return m.checked_mul(60).map(Duration::from_secs).ok_or_else(invalid);
+    }
+    let c = DURATION.captures(s).filter(|_| !s.is_empty()).ok_or_else(invalid)?;
+    let part = |i: usize| c.get(i).map(|m| m.as_str().parse::<u64>().ok()).unwrap_or(Some(0));
+    let secs = part(1).and_then(|h| h.checked_mul(3600))
+        .zip(part(2).and_then(|m| m.checked_mul(60)))
+        .zip(part(3))
+        .and_then(|((h, m), s)| h.checked_add(m)?.checked_add(s))
+        .ok_or_else(invalid)?;
+    Ok(Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn term(text: &str, quoted: bool, negated: bool) -> Term {
+        Term { text: text.to_owned(), quoted, negated }
+    }
+
+    #[test]
+    fn tokenize_words_quotes_and_filters() {
+        let tokens = tokenize(r#"  pokemon "green oaks" -zelda title:"a b" Speaker:sio ore 10:30 -after:2022"#).unwrap();
+        assert_eq!(tokens, vec![
+            (None, term("pokemon", false, false)),
+            (None, term("green oaks", true, false)),
+            (None, term("zelda", false, true)),
+            (Some("title".to_owned()), term("a b", true, false)),
+            (Some("speaker".to_owned()), term("sio", false, false)),
+            (None, term("ore", false, false)),
+            (None, term("10:30", false, false)),
+            // negated filters are plain words
+            (None, term("after:2022", false, true)),
+        ]);
+        assert!(tokenize("").unwrap().is_empty());
+        assert!(matches!(tokenize(r#"title:"green oaks"#), Err(QueryError::UnclosedQuote)));
+    }
+
+    #[test]
+    fn parse_filters() {
+        let q = Query::parse(r#"undertale after:2022-03 before:2023 longer:1h30m shorter:45 title:"green oaks" speaker:sio series:pgdr guest:mario"#).unwrap();
+        assert_eq!(q.text(), "undertale");
+        let f = q.filters;
+        assert_eq!(f.after, Some(NaiveDate::from_ymd_opt(2022, 3, 1).unwrap().and_hms_opt(0, 0, 0).unwrap().and_utc()));
+        assert_eq!(f.before, Some(NaiveDate::from_ymd_opt(2023, 1, 1).unwrap().and_hms_opt(0, 0, 0).unwrap().and_utc()));
+        assert_eq!(f.longer, Some(Duration::from_secs(90 * 60)));
+        assert_eq!(f.shorter, Some(Duration::from_secs(45 * 60)));
+        assert_eq!(f.title.as_deref(), Some("green oaks"));
+        assert_eq!(f.speaker.as_deref(), Some("sio"));
+        assert_eq!(f.series.as_deref(), Some("pgdr"));
+        assert_eq!(f.guest.as_deref(), Some("mario"));
+    }
+
+    #[test]
+    fn parse_dates() {
+        let day = |y, m, d| NaiveDate::from_ymd_opt(y, m, d).unwrap().and_hms_opt(0, 0, 0).unwrap().and_utc();
+        assert_eq!(parse_date("2022").unwrap(), day(2022, 1, 1));
+        assert_eq!(parse_date("2022-03").unwrap(), day(2022, 3, 1));
+        assert_eq!(parse_date("2022-03-15").unwrap(), day(2022, 3, 15));
+        for invalid in ["", "2022-13", "2022-02-30", "2022-03-15-1", "ieri", "2022/03"] {
+            assert!(matches!(parse_date(invalid), Err(QueryError::InvalidDate(d)) if d == invalid), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn parse_durations() {
+        assert_eq!(parse_duration("45").unwrap(), Duration::from_secs(45 * 60));
+        assert_eq!(parse_duration("90m").unwrap(), Duration::from_secs(90 * 60));
+        assert_eq!(parse_duration("1h30m").unwrap(), Duration::from_secs(90 * 60));
+        assert_eq!(parse_duration("2h").unwrap(), Duration::from_secs(2 * 3600));
+        assert_eq!(parse_duration("1h2m3s").unwrap(), Duration::from_secs(3723));
+        for invalid in ["", "h", "1.5h", "30m1h", "-5", "99999999999999999999m", &u64::MAX.to_string()] {
+            assert!(matches!(parse_duration(invalid), Err(QueryError::InvalidDuration(d)) if d == invalid), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn invalid_filter_values() {
+        assert!(matches!(Query::parse("title:"), Err(QueryError::EmptyFilter(k)) if k == "title"));
+        assert!(matches!(Query::parse(r#"guest:"""#), Err(QueryError::EmptyFilter(k)) if k == "guest"));
+        assert!(matches!(Query::parse("after:domani"), Err(QueryError::InvalidDate(_))));
+        assert!(matches!(Query::parse("longer:tanto"), Err(QueryError::InvalidDuration(_))));
+    }
+}
@@ -1,12 +1,15 @@
-use std::{cmp::{min,max}, collections::VecDeque, time::Instant};
+use std::{cmp::{min, Reverse}, collections::HashMap, time::{Duration, Instant}};
 use futures_util::{StreamExt, TryStreamExt};
+use lazy_static::lazy_static;
 use log::{debug, trace};
-use mongodb::bson::{doc, from_document};
-use regex::bytes::RegexBuilder;
+use mongodb::bson::doc;
+use regex::bytes::{Regex, RegexBuilder};
+use serde::Deserialize;
 use substring::Substring;
 use unidecode::unidecode;
 
-use crate::{db::PPPDatabase, spreaker::Episode, transcript::{EpisodeTranscript, FromTo, Timestamp}};
+use super::{fuzzy, query::Filters};
+use crate::{db::PPPDatabase, spreaker::Episode, transcript::{timestamp_at, EmbedError, EpisodeTranscript, FromTo, NormalizedText}};
 
 /// # Queries:
 /// Get audio timestamp from text offset
@@ -15,42 +18,140 @@ use crate::{db::PPPDatabase, spreaker::Episode, transcript::{EpisodeTranscript,
 /// Get episode id from search string
 /// db.transcripts.aggregate([{ $match: {$text: {$search: "undertale"} }}, {$project: {episode_id: 1, _id: 0}}, {$lookup: {from: "episodes", localField: "episode_id", foreignField: "id", as: "episodeDetails"}}, {$project: {name: "$episodeDetails.title", id: "$episode_id"}}])
 impl PPPDatabase {
-    /// Perform a full-text search across all transcripts in the database.
-    /// Returns a list of episodes in which the search string was found.
-    /// It does not return the actual matches nor the timestamps, take a look at `search_transcript_one` for that.
-    pub async fn search_transcript_all(&self, text: String) -> Result<Vec<SearchResult>, SearchError> {
+    /// Perform a full-text search across all transcripts in the database with the MongoDB text
+    /// index. Returns the ids of all the episodes in which the search string was found, best
+    /// first, with their text score: `search_transcript_results` builds the results of a page
+    /// of them, so transcripts are only fetched for the episodes shown.
+    ///
+    /// Only episodes passing `filters` are returned. With `filters.speaker` set, only episodes
+    /// where that speaker talks are.
+    pub async fn search_transcript_ranking(&self, text: &str, filters: &Filters) -> Result<Vec<(u32, f64)>, SearchError> {
         self._ensure_status().await;
         let _t = Instant::now();
-        let episodes = self.db
+        let mut filter = doc!{"$text": {"$search": text}};
+        if let Some(speaker) = &filters.speaker {
+            filter.insert("timestamps.speaker", speaker_regex(speaker));
+        }
+        // rank on the ids alone, whole transcripts would make the sort run out of memory
+        let ranking = self.db
             .collection::<EpisodeTranscript>("transcripts")
             .aggregate(vec![
-                doc!{"$match": {"$text": {"$search": text}}},
-                doc!{"$project": {"episode_id": 1, "_id": 0}},
-                doc!{"$sort": {"sort": {"$meta": "textScore"}}},
-                doc!{"$lookup": {"from": "episodes", "localField": "episode_id", "foreignField": "id", "as": "episodeDetails"}},
-                doc!{"$unwind": "$episodeDetails"},
-                doc!{"$replaceRoot": {"newRoot": "$episodeDetails"}},
+                doc!{"$match": filter},
+                doc!{"$project": {"_id": 0, "episode_id": 1, "score": {"$meta": "textScore"}}},
+                doc!{"$sort": {"score": -1}},
+                doc!{"$lookup": {"from": "episodes", "localField": "episode_id", "foreignField": "id", "as": "episode"}},
+                doc!{"$unwind": "$episode"},
+                doc!{"$match": filters.episode_filter("episode.")},
+                doc!{"$project": {"episode_id": 1, "score": 1}},
             ])
+            .with_type::<RankedId>()
             .await?
-            // unwrap safe: as long as the schema and query are correct, this should not fail after this point
-            .map(|d| d.map(|d| from_document::<Episode>(d.clone()).unwrap()))
-            .try_collect::<Vec<Episode>>()
+            .map_ok(|r| (r.episode_id, r.score))
+            .try_collect::<Vec<_>>()
             .await?;
-        if episodes.is_empty() {
-            Err(SearchError::NoResults)
-        } else {
-            let r = Ok(episodes.into_iter().map(|episode| SearchResult { episode }).collect());
-            trace!("timings: search_text: {:?}", _t.elapsed());
-            r
+        trace!("timings: search_text ranking: {:?}", _t.elapsed());
+        if ranking.is_empty() {
+            return Err(SearchError::NoResults);
+        }
+        Ok(ranking)
+    }
+
+    /// The results of the episodes in `ranking`, part of what `search_transcript_ranking` returned
+    /// for the same `text` and `filters`, in the same order, each with the number of matches and
+    /// its `MAX_SNIPPETS` best snippets. Matches are looked for with `text_query_pattern`, so they
+    /// can miss words that the text index finds through stemming.
+    /// With `filters.speaker` set, only the matches said by that speaker are counted.
+    pub async fn search_transcript_results(&self, text: &str, filters: &Filters, ranking: &[(u32, f64)]) -> Result<Vec<RankedResult>, SearchError> {
+        let _t = Instant::now();
+        // a query made only of negated terms has nothing to highlight
+        let matcher = match query_terms(text).is_empty() {
+            true => None,
+            false => Some(Matcher::Regex(build_regex(&text_query_pattern(text))?)),
+        };
+        let ids = ranking.iter().map(|(id, _)| *id as i64).collect::<Vec<_>>();
+        let mut documents = self.db
+            .collection::<EpisodeTranscript>("transcripts")
+            .aggregate(vec![
+                doc!{"$match": {"episode_id": {"$in": ids}}},
+                doc!{"$project": {"_id": 0, "episode_id": 1, "transcript": "$$ROOT"}},
+                doc!{"$lookup": {"from": "episodes", "localField": "episode_id", "foreignField": "id", "as": "episode"}},
+                doc!{"$unwind": "$episode"},
+            ])
+            .with_type::<RankedDocument>()
+            .await?;
+        let mut results = HashMap::new();
+        while let Some(d) = documents.try_next().await? {
+            let r = RankedResult::from(d, matcher.as_ref(), filters.speaker.as_deref());
+            results.insert(r.episode.id, r);
+        }
+        trace!("timings: search_text results: {:?}", _t.elapsed());
+        Ok(ranking.iter()
+            .filter_map(|(id, score)| results.remove(id).map(|r| RankedResult { score: *score, ..r }))
+            .collect())
+    }
+
+    /// Scan the transcripts of the `MAX_FUZZY_EPISODES` newest episodes passing `filters` for
+    /// words close to the terms of `text`, ignoring negated terms. Returns all the episodes in
+    /// which some were found, ranked by number of matches, each with its `MAX_SNIPPETS` best
+    /// snippets. With `filters.speaker` set, only episodes where that speaker talks are, and only
+    /// the matches said by them are counted.
+    pub async fn search_transcript_fuzzy(&self, text: &str, filters: &Filters) -> Result<Vec<RankedResult>, SearchError> {
+        self._ensure_status().await;
+        let _t = Instant::now();
+        let terms = query_terms(text);
+        if terms.is_empty() {
+            return Err(SearchError::NoResults);
         }
+        let matcher = Matcher::Fuzzy(terms);
+        let mut filter = doc!{};
+        if let Some(speaker) = &filters.speaker {
+            filter.insert("timestamps.speaker", speaker_regex(speaker));
+        }
+        // fuzzy results are ranked by their matches, found only once the transcript is here
+        let mut documents = self.db
+            .collection::<EpisodeTranscript>("transcripts")
+            .aggregate(vec![
+                doc!{"$match": filter},
+                doc!{"$project": {"_id": 0, "episode_id": 1}},
+                doc!{"$lookup": {"from": "episodes", "localField": "episode_id", "foreignField": "id", "as": "episode"}},
+                doc!{"$unwind": "$episode"},
+                doc!{"$match": filters.episode_filter("episode.")},
+                doc!{"$sort": {"episode.published_at": -1}},
+                doc!{"$limit": MAX_FUZZY_EPISODES as i64},
+                doc!{"$lookup": {"from": "transcripts", "localField": "episode_id", "foreignField": "episode_id", "as": "transcript"}},
+                doc!{"$unwind": "$transcript"},
+            ])
+            .with_type::<RankedDocument>()
+            .await?;
+        // transcripts are big, don't keep them around: a fuzzy search goes through all of them
+        let mut ranked = vec![];
+        while let Some(d) = documents.try_next().await? {
+            let r = RankedResult::from(d, Some(&matcher), filters.speaker.as_deref());
+            if r.count > 0 {
+                ranked.push(RankedResult { score: r.count as f64, ..r });
+            }
+        }
+        trace!("timings: search_text fuzzy: {:?}", _t.elapsed());
+        if ranked.is_empty() {
+            return Err(SearchError::NoResults);
+        }
+        ranked.sort_by(|a, b| b.score.total_cmp(&a.score));
+        Ok(ranked)
     }
     
-    /// Perform a full-text regex based search across a single transcript.
+    /// Perform a full-text search across a single transcript for `text`, interpreted according
+    /// to `mode`. Regex searches are limited in size and time, see `build_regex`; users' regexes
+    /// should also pass `check_user_regex`.
     /// Returns a list of matches with their timestamps and text in the neighborhood of the match for context.
-    pub async fn search_transcript_one(&self, id: u32, text: String) -> Result<OffsetSearchResult, SearchError> {
+    /// Nothing is found if the episode doesn't pass `filters`. With `filters.speaker` set, only
+    /// matches in segments attributed to that speaker are returned.
+    pub async fn search_transcript_one(&self, id: u32, text: String, filters: &Filters, mode: SearchMode) -> Result<OffsetSearchResult, SearchError> {
         self._ensure_status().await;
         let _t = Instant::now();
         let e = self.get::<Episode>(id).await?.ok_or(SearchError::EpisodeNotFound(id))?;
+        if !filters.matches(&e) {
+            return Err(SearchError::NoResults);
+        }
         let transcript = match self.db
             .collection::<EpisodeTranscript>("transcripts")
             .find_one(doc!{"episode_id": id})
@@ -59,32 +160,33 @@ impl PPPDatabase {
                 None => return Err(SearchError::EpisodeNotFound(id)),
             };
 
-
-        let r = RegexBuilder::new(&text)
-            .case_insensitive(true)
-            .build()
-            .map_err(SearchError::Regex)?;
+        let matcher = match mode {
+            SearchMode::Exact => Matcher::Regex(build_regex(&regex::escape(&unidecode(&text)))?),
+            SearchMode::Regex => Matcher::Regex(build_regex(&text)?),
+            SearchMode::Fuzzy => Matcher::Fuzzy(query_terms(&text)),
+        };
         
-        let data = unidecode(&transcript.data);
-        let mut matches = VecDeque::new();
-        for pos in r.find_iter(data.as_ref()) {
-            matches.push_back(pos.start());
-        }
+        let matches = matcher.find_within(&transcript, QUERY_TIME_BUDGET)?;
         if matches.is_empty() {
             return Err(SearchError::NoResults);
         }
-        let r = Ok(OffsetSearchResult::from(e, matches, transcript.timestamps, &transcript.data));
+        let r = OffsetSearchResult::from(e, matches, &transcript, filters.speaker.as_deref());
+        if r.matches.is_empty() {
+            return Err(SearchError::NoResults);
+        }
+        let r = Ok(r);
         trace!("timings: search_transcript_offset: {:?}", _t.elapsed());
         r
     }
 
-    pub async fn search_meta(&self, text: String) -> Result<Vec<SearchResult>, SearchError> {
+    /// Episodes passing `filters` whose title, description or guests contain `text`.
+    pub async fn search_meta(&self, text: String, filters: &Filters) -> Result<Vec<SearchResult>, SearchError> {
         let r = mongodb::bson::Regex { pattern: regex::escape(&text), options: "i".to_string() };
         let res = self.db
             .collection::<Episode>("episodes")
-            .find(doc!{"$or": [{"title": r.clone()}, {"description": r.clone()}]})
+            .find(doc!{"$and": [{"$or": [{"title": r.clone()}, {"description": r.clone()}, {"guests": r.clone()}]}, filters.episode_filter("")]})
             .await?
-            .map(|d| d.map(|d| SearchResult { episode: d }))
+            .map(|d| d.map(|d| SearchResult { chapter: matching_chapter(&d, &text), episode: d }))
             .try_collect::<Vec<SearchResult>>()
             .await?;
         if res.is_empty() {
@@ -93,44 +195,178 @@ impl PPPDatabase {
             Ok(res)
         }
     }
+}
 
-    /// Perform a search for a specific episode by its id/name/number or Magic Identifier™.
-    /// Returns an optional u32 representing the episode id.
-    pub async fn magic_episode_search(&self, query: String) -> Result<u32, SearchError> {
-        match query.parse::<u32>() {
-            Ok(num) => {
-                debug!("parsed number: {}", num);
-                if num > 10000 {
-                    debug!("assuming this is an episode id");
-                    // we assume that this is the episode id
-                    Ok(num)
-                } else {
-                    debug!("assuming this is an episode number, searching by title");
-                    // we assume that this is the episode number
-                    self.db
-                        .collection::<Episode>("episodes")
-                        .find_one(doc!{"title": mongodb::bson::Regex { pattern: num.to_string(), options: "i".to_string() }})
-                        .await?
-                        .map(|e| e.id)
-                        .ok_or(SearchError::NoResults)
+#[derive(Debug)]
+pub struct SearchResult {
+    pub episode: Episode,
+    /// Index in `episode.chapters` of the first chapter containing the query, if any.
+    pub chapter: Option<usize>,
+}
+
+fn matching_chapter(episode: &Episode, text: &str) -> Option<usize> {
+    let text = unidecode(text.trim()).to_lowercase();
+    if text.is_empty() {
+        return None;
+    }
+    episode.chapters.iter().position(|c| unidecode(&c.title).to_lowercase().contains(&text))
+}
+
+/// When each of `texts` is first said in `transcript`, looked for as in `SearchMode::Exact`.
+pub fn first_mentions(transcript: &EpisodeTranscript, texts: &[&str]) -> Vec<Option<Duration>> {
+    let normalized = transcript.normalized();
+    texts
+        .iter()
+        .map(|text| {
+            let matcher = Matcher::Regex(build_regex(&regex::escape(&unidecode(text))).ok()?);
+            let start = matcher.find_normalized(&normalized).into_iter().map(|(s, _)| s).min()?;
+            timestamp_at(&transcript.timestamps, start).map(|t| t.time.from)
+        })
+        .collect()
+}
+
+/// How query words are looked for in transcripts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SearchMode {
+    /// Case insensitive, on the transliterated text.
+    Exact,
+    /// Like `Exact`, but the query is a regex. Only single transcript searches support it.
+    Regex,
+    /// Also words that look or sound alike, or are split in pieces, see `fuzzy`.
+    Fuzzy,
+}
+
+enum Matcher {
+    Regex(Regex),
+    /// Query terms, see `query_terms`.
+    Fuzzy(Vec<String>),
+}
+
+impl Matcher {
+    /// Char ranges of the matches in the transcript. The search runs on the transliterated text,
+    /// match positions are brought back to char offsets in `data`, the same unit used by
+    /// `Timestamp.offsets`.
+    fn find(&self, transcript: &EpisodeTranscript) -> Vec<(usize, usize)> {
+        self.find_normalized(&transcript.normalized())
+    }
+
+    /// Same as `find`, on the already transliterated text of the transcript.
+    fn find_normalized(&self, normalized: &NormalizedText) -> Vec<(usize, usize)> {
+        // never fails without a deadline
+        self._find(normalized, None).unwrap_or_default()
+    }
+
+    /// Same as `find`, giving up with `SearchError::TooSlow` after `budget`.
+    fn find_within(&self, transcript: &EpisodeTranscript, budget: Duration) -> Result<Vec<(usize, usize)>, SearchError> {
+        self._find(&transcript.normalized(), Some(Instant::now() + budget))
+    }
+
+    fn _find(&self, normalized: &NormalizedText, deadline: Option<Instant>) -> Result<Vec<(usize, usize)>, SearchError> {
+        let found = match self {
+            Matcher::Regex(r) => {
+                let mut found = vec![];
+                // the regex runs in linear time, only the matches can make it slow
+                for m in r.find_iter(normalized.text.as_bytes()) {
+                    if deadline.is_some_and(|d| Instant::now() > d) {
+                        return Err(SearchError::TooSlow);
+                    }
+                    found.push((m.start(), m.end()));
                 }
+                found
             }
-            Err(_) => {
-                debug!("not a number, searching by title");
-                self.db
-                    .collection::<Episode>("episodes")
-                    .find_one(doc!{"title": mongodb::bson::Regex { pattern: query, options: "i".to_string() }})
-                    .await?
-                    .map(|e| e.id)
-                    .ok_or(SearchError::NoResults)
-            }
-        }
+            Matcher::Fuzzy(terms) => fuzzy::find(&normalized.text, terms),
+        };
+        Ok(found.into_iter().map(|(start, end)| normalized.char_range(start, end)).collect())
     }
 }
 
+/// Longest regex accepted from users, in bytes.
+const MAX_PATTERN_LENGTH: usize = 256;
+/// Limits on the memory taken by a compiled regex and by its lazy DFA.
+const REGEX_SIZE_LIMIT: usize = 1 << 20;
+const REGEX_DFA_SIZE_LIMIT: usize = 2 << 20;
+/// Time a single transcript search may take.
+const QUERY_TIME_BUDGET: Duration = Duration::from_secs(2);
+
+/// Refuse a regex written by a user when it is longer than `MAX_PATTERN_LENGTH`, the patterns
+/// built from a query are as long as the query needs.
+pub fn check_user_regex(pattern: &str) -> Result<(), SearchError> {
+    match pattern.len() > MAX_PATTERN_LENGTH {
+        true => Err(SearchError::PatternTooExpensive),
+        false => Ok(()),
+    }
+}
+
+/// Compile a case insensitive regex, refusing the ones compiling to something too big to be
+/// cheap to run and the ones matching the empty string, which would match everywhere.
+fn build_regex(pattern: &str) -> Result<Regex, SearchError> {
+    let r = RegexBuilder::new(pattern)
+        .case_insensitive(true)
+        .size_limit(REGEX_SIZE_LIMIT)
+        .dfa_size_limit(REGEX_DFA_SIZE_LIMIT)
+        .build()
+        .map_err(|e| match e {
+            regex::Error::CompiledTooBig(_) => SearchError::PatternTooExpensive,
+            e => SearchError::Regex(e),
+        })?;
+    if r.is_match(b"") {
+        return Err(SearchError::PatternMatchesEmpty);
+    }
+    Ok(r)
+}
+
+/// How many transcripts `search_transcript_fuzzy` scans, newest first: each is matched
+/// word by word, in the bot's process.
+const MAX_FUZZY_EPISODES: usize = 200;
+
+/// How many snippets transcript searches return for each episode.
+pub const MAX_SNIPPETS: usize = 3;
+
+#[derive(Deserialize)]
+struct RankedId {
+    episode_id: u32,
+    score: f64,
+}
+
+#[derive(Deserialize)]
+struct RankedDocument {
+    transcript: EpisodeTranscript,
+    episode: Episode,
+}
+
 #[derive(Debug)]
-pub struct SearchResult {
+pub struct RankedResult {
     pub episode: Episode,
+    /// Only meaningful to compare results of the same query: the MongoDB text score, or the
+    /// number of matches for fuzzy searches.
+    pub score: f64,
+    /// Number of matches in the transcript.
+    pub count: usize,
+    /// The snippets with the most matches close together, in order of time.
+    pub snippets: Vec<EpisodeOffsetMatch>,
+}
+
+impl RankedResult {
+    fn from(d: RankedDocument, matcher: Option<&Matcher>, speaker: Option<&str>) -> Self {
+        let found = matcher.map(|m| m.find(&d.transcript)).unwrap_or_default();
+        let starts = found.iter().map(|(s, _)| *s).collect::<Vec<_>>();
+        let mut snippets = OffsetSearchResult::from(d.episode, found, &d.transcript, speaker);
+        // every snippet stands for the matches merged into it, see `OffsetSearchResult::from`
+        let mut weighted = snippets.matches
+            .drain(..)
+            .map(|m| (starts.partition_point(|s| *s < m.offset + HINT_RADIUS) - starts.partition_point(|s| *s < m.offset), m))
+            .collect::<Vec<_>>();
+        let count = weighted.iter().map(|(n, _)| n).sum();
+        weighted.sort_by_key(|(n, m)| (Reverse(*n), m.offset));
+        weighted.truncate(MAX_SNIPPETS);
+        weighted.sort_by_key(|(_, m)| m.offset);
+        Self {
+            episode: snippets.episode,
+            score: 0.0,
+            count,
+            snippets: weighted.into_iter().map(|(_, m)| m).collect(),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -140,33 +376,46 @@ pub struct OffsetSearchResult {
 }
 
 impl OffsetSearchResult {
-    pub fn from(episode: Episode, mut input: VecDeque<usize>, timestamps: Vec<Timestamp>, data: &String) -> Self {
-        const HINT_RADIUS: usize = 50;
+    /// `input` are the `(start, end)` char offsets of the matches in the transcript data, in any
+    /// order. Matches starting within `HINT_RADIUS` chars of the previous one are merged into it,
+    /// since they would show the same hint. Matches outside every segment are dropped, as well as
+    /// the ones not said by `speaker`, when given.
+    pub fn from(episode: Episode, mut input: Vec<(usize, usize)>, transcript: &EpisodeTranscript, speaker: Option<&str>) -> Self {
+        let EpisodeTranscript { data, timestamps, words, .. } = transcript;
+        let data_len = data.chars().count();
 
-        let mut curr = match input.pop_front() {
-            Some(m) => m,
-            None => return Self { episode, matches: vec![] },
-        };
-        let mut matches = vec![];
-        // let mut prev_time = Duration::from_secs(0);
-        'a: for Timestamp { time, offsets } in timestamps {
-            debug!("checking timestamp: Timestamp {{ time: {:?}, offsets: {:?} }}", time, offsets);
-            loop {
-                if curr >= offsets.0 && curr < offsets.1 {
-                    let m = EpisodeOffsetMatch {
-                        time: time.clone(),
-                        hint: data.substring(max(0, curr as isize - HINT_RADIUS as isize) as usize, min(data.len(), curr + HINT_RADIUS)).to_string(),
-                    };
-                    debug!("found match: {:?}", m);
-                    matches.push(m);
-                    curr = match input.pop_front() {
-                        Some(m) => m,
-                        None => break 'a,
-                    };
-                } else if curr < offsets.0 && curr < offsets.1 {
-                    panic!("this should never happen... curr: {:?}, offsets: {:?}", curr, offsets);
-                } else { break }
+        input.sort_unstable();
+        let mut matches: Vec<EpisodeOffsetMatch> = vec![];
+        for (start, end) in input {
+            if matches.last().is_some_and(|m| start < m.offset + HINT_RADIUS) {
+                trace!("skipping match at {}: too close to the previous one", start);
+                continue;
+            }
+            let first = match timestamp_at(timestamps, start) {
+                Some(t) => t,
+                None => {
+                    debug!("match at {} is outside of every segment, skipping", start);
+                    continue;
+                }
+            };
+            if speaker.is_some_and(|s| !first.speaker.as_ref().is_some_and(|f| f.eq_ignore_ascii_case(s))) {
+                continue;
             }
+            // the last char of the match decides the segment it ends in
+            let last = timestamp_at(timestamps, end.saturating_sub(1).max(start)).unwrap_or(first);
+            // only trust the word if it lies in the segment, word offsets may be missing
+            let word = timestamp_at(words, start).filter(|w| w.offsets.0 < first.offsets.1);
+            let m = EpisodeOffsetMatch {
+                time: first.time.clone(),
+                end_time: if std::ptr::eq(first, last) { None } else { Some(last.time.clone()) },
+                start: word.map(|w| w.time.from),
+                speaker: first.speaker.clone(),
+                offset: start,
+                matched: data.substring(start, end).to_string(),
+                hint: data.substring(start.saturating_sub(HINT_RADIUS), min(data_len, start + HINT_RADIUS)).to_string(),
+            };
+            debug!("found match: {:?}", m);
+            matches.push(m);
         }
         Self {
             episode,
@@ -179,18 +428,81 @@ impl OffsetSearchResult {
     }
 }
 
+/// Chars of context shown on each side of a match.
+const HINT_RADIUS: usize = 50;
+
+/// Words and quoted phrases of a MongoDB text search query, without the negated ones.
+/// Terms are transliterated like the text they are matched against.
+pub fn query_terms(query: &str) -> Vec<String> {
+    lazy_static! {
+        static ref TERM: regex::Regex = regex::Regex::new(r#""([^"]+)"|(\S+)"#).unwrap();
+    }
+    TERM.captures_iter(query)
+        .filter_map(|c| c.get(1).or(c.get(2)))
+        .map(|t| t.as_str())
+        .filter(|t| !t.starts_with('-'))
+        .map(unidecode)
+        .collect()
+}
+
+/// Turn a MongoDB text search query into a regex for single transcripts: every term is an
+/// alternative, see `query_terms`.
+pub fn text_query_pattern(query: &str) -> String {
+    query_terms(query)
+        .iter()
+        .map(|t| regex::escape(t))
+        .collect::<Vec<_>>()
+        .join("|")
+}
+
+fn speaker_regex(speaker: &str) -> mongodb::bson::Regex {
+    mongodb::bson::Regex { pattern: format!("^{}$", regex::escape(speaker)), options: "i".to_string() }
+}
+
 #[derive(Debug)]
 pub struct EpisodeOffsetMatch {
+    /// Time range of the segment the match starts in.
     pub time: FromTo,
+    /// Time range of the segment the match ends in, if it spans more than one segment.
+    pub end_time: Option<FromTo>,
+    /// Exact time the match starts at, when the transcript has word timestamps.
+    pub start: Option<Duration>,
+    pub speaker: Option<String>,
+    /// Char offset of the match in the transcript.
+    pub offset: usize,
+    /// Text of the transcript that matched, which differs from the query in fuzzy searches.
+    pub matched: String,
     pub hint: String,
 }
 
+impl EpisodeOffsetMatch {
+    /// Time range covered by the match, from the start of its first segment to the end of its
+    /// last one.
+    pub fn span(&self) -> FromTo {
+        FromTo {
+            from: self.time.from,
+            to: self.end_time.as_ref().unwrap_or(&self.time).to,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum SearchError {
     EpisodeNotFound(u32),
     Mongo(mongodb::error::Error),
     Regex(regex::Error),
     NoResults,
+    Embedding(EmbedError),
+    /// No embedding backend is configured.
+    SemanticUnavailable,
+    /// The regex is too long or compiles to something too big.
+    PatternTooExpensive,
+    /// The regex matches the empty string.
+    PatternMatchesEmpty,
+    /// The search took longer than `QUERY_TIME_BUDGET`.
+    TooSlow,
+    /// More than one episode fits the query, with their ids and titles.
+    Ambiguous(Vec<(u32, String)>),
 }
 
 impl From<mongodb::error::Error> for SearchError {
@@ -205,13 +517,109 @@ impl From<regex::Error> for SearchError {
     }
 }
 
+impl From<EmbedError> for SearchError {
+    fn from(e: EmbedError) -> Self {
+        SearchError::Embedding(e)
+    }
+}
+
 impl SearchError {
     pub fn respond_client(&self) -> &str {
         match self {
             SearchError::EpisodeNotFound(_) => "l'episodio richiesto non esiste",
             SearchError::Mongo(_) => "errore del database",
-            SearchError::Regex(_) => "errore nella query",
+            SearchError::Regex(_) => "la regex non è valida",
             SearchError::NoResults => "nessun risultato trovato",
+            SearchError::Embedding(_) => "errore nel calcolo della ricerca per argomento",
+            SearchError::SemanticUnavailable => "la ricerca per argomento non è disponibile",
+            SearchError::PatternTooExpensive => "la regex è troppo lunga o complessa, prova a semplificarla",
+            SearchError::PatternMatchesEmpty => "la regex trova anche il testo vuoto, quindi ovunque: prova a renderla più specifica",
+            SearchError::TooSlow => "la ricerca ci sta mettendo troppo, prova una query più specifica",
+            SearchError::Ambiguous(_) => "più episodi corrispondono alla richiesta, ripeti il comando con l'id di quello che cerchi",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::transcript::Timestamp;
+
+    /// Words whose transliteration is longer or shorter than them: accents, ligatures, chars
+    /// outside the BMP.
+    const WORDS: &[&str] = &["perché", "città", "così", "più", "già", "caffè", "Pokémon", "naïve", "Æon", "straße", "ﬁne", "œuvre", "日本", "🎮", "undertale"];
+
+    /// A transcript with a segment per list of words, each word preceded by a space as whisper does.
+    fn transcript(segments: &[Vec<usize>]) -> EpisodeTranscript {
+        let mut data = String::new();
+        let mut timestamps = vec![];
+        for (i, words) in segments.iter().enumerate() {
+            let start = data.chars().count();
+            for w in words {
+                data.push(' ');
+                data.push_str(WORDS[*w]);
+            }
+            let time = FromTo { from: Duration::from_secs(i as u64 * 5), to: Duration::from_secs(i as u64 * 5 + 5) };
+            timestamps.push(Timestamp { time, offsets: (start, data.chars().count()), speaker: None });
+        }
+        EpisodeTranscript { episode_id: 1, data, timestamps, words: vec![] }
+    }
+
+    fn episode() -> Episode {
+        Episode {
+            id: 1,
+            title: "PPP 1".to_owned(),
+            duration: 0,
+            show_id: 0,
+            author_id: 0,
+            published_at: Utc::now(),
+            download_url: String::new(),
+            description: String::new(),
+            description_html: String::new(),
+            number: Some(1),
+            series: None,
+            guests: vec![],
+            links: vec![],
+            chapters: vec![],
+            enriched: 0,
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn matches_map_to_their_segment_and_hint(
+            segments in prop::collection::vec(prop::collection::vec(0..WORDS.len(), 1..8), 1..20),
+            query in 0..WORDS.len(),
+        ) {
+            let needle = unidecode(WORDS[query]).to_lowercase();
+            prop_assume!(!needle.trim().is_empty());
+            let t = transcript(&segments);
+            let chars = t.data.chars().collect::<Vec<_>>();
+            let matcher = Matcher::Regex(build_regex(&regex::escape(&unidecode(WORDS[query]))).unwrap());
+            let found = matcher.find(&t);
+
+            let said = segments.iter().flatten().filter(|w| **w == query).count();
+            prop_assert_eq!(found.len(), said);
+            for (start, end) in found.iter().copied() {
+                let matched = chars[start..end].iter().collect::<String>();
+                prop_assert!(unidecode(&matched).to_lowercase().contains(&needle), "{:?} is not {:?}", matched, needle);
+                let segment = t.timestamps.iter().find(|s| s.offsets.0 <= start && start < s.offsets.1).unwrap();
+                prop_assert!(std::ptr::eq(timestamp_at(&t.timestamps, start).unwrap(), segment));
+                prop_assert!(end <= segment.offsets.1);
+            }
+
+            let r = OffsetSearchResult::from(episode(), found, &t, None);
+            for m in r.matches.iter() {
+                let segment = t.timestamps.iter().find(|s| s.offsets.0 <= m.offset && m.offset < s.offsets.1).unwrap();
+                prop_assert_eq!(m.time.from, segment.time.from);
+                prop_assert!(unidecode(&m.matched).to_lowercase().contains(&needle));
+                let hint = chars[m.offset.saturating_sub(HINT_RADIUS)..min(chars.len(), m.offset + HINT_RADIUS)].iter().collect::<String>();
+                prop_assert_eq!(&m.hint, &hint);
+                prop_assert!(m.hint.contains(&m.matched));
+            }
         }
     }
 }
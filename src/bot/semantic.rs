@@ -0,0 +1,160 @@
+use std::{collections::{hash_map::Entry, HashMap}, time::Instant};
+use futures_util::TryStreamExt;
+use lazy_static::lazy_static;
+#[allow(unused_imports)]
+use log::{debug, info, trace, warn};
+use mongodb::bson::doc;
+use substring::Substring;
+
+use crate::{config::CONFIG, db::PPPDatabase, spreaker::Episode, transcript::{similarity, Embedder, EpisodeEmbeddings, EpisodeTranscript, FromTo, WINDOW_SEGMENTS, WINDOW_STRIDE}};
+use super::search::SearchError;
+
+/// Chars of transcript shown for each hit.
+const MAX_HIT_TEXT: usize = 300;
+/// Windows of the same episode a window can overlap, see `Window::split`: the best `limit` hits
+/// not overlapping each other are among the best `limit * (1 + MAX_OVERLAPPING)` windows.
+const MAX_OVERLAPPING: usize = 2 * (WINDOW_SEGMENTS.div_ceil(WINDOW_STRIDE) - 1);
+
+/// A window of an episode scored against the query, without its vector.
+struct ScoredWindow {
+    similarity: f32,
+    episode_id: u32,
+    time: FromTo,
+    offsets: (usize, usize),
+}
+
+lazy_static! {
+    static ref EMBEDDER: Option<Embedder> = CONFIG.import.embedder
+        .as_ref()
+        .map(|c| Embedder::from_config(c, reqwest::Client::new()));
+}
+
+/// A part of an episode talking about what was searched.
+#[derive(Debug)]
+pub struct SemanticHit {
+    pub episode: Episode,
+    pub time: FromTo,
+    /// Cosine similarity with the query, at most 1.
+    pub similarity: f32,
+    /// Beginning of the text of the window.
+    pub text: String,
+}
+
+impl PPPDatabase {
+    /// Find the `limit` transcript windows closest in meaning to `query`, best first. Windows
+    /// overlapping a better hit of the same episode are skipped. Vectors are read from the
+    /// database at every query, only the best candidates are kept in memory.
+    pub async fn search_semantic(&self, query: &str, limit: usize) -> Result<Vec<SemanticHit>, SearchError> {
+        self._ensure_status().await;
+        let _t = Instant::now();
+        let embedder = EMBEDDER.as_ref().ok_or(SearchError::SemanticUnavailable)?;
+        let query = embedder
+            .embed(&[query.to_owned()])
+            .await?
+            .pop()
+            .ok_or(SearchError::NoResults)?;
+        // vectors are streamed an episode at a time, only the best windows are kept
+        let keep = limit * (1 + MAX_OVERLAPPING);
+        let mut candidates = vec![];
+        // windows embedded by another model can't be compared to the query, they need embedding again
+        let mut mismatched = 0;
+        let mut embeddings = self.db
+            .collection::<EpisodeEmbeddings>("embeddings")
+            .find(doc!{})
+            .await?;
+        while let Some(e) = embeddings.try_next().await? {
+            for w in e.windows {
+                match similarity(&query, &w.vector) {
+                    Ok(similarity) => candidates.push(ScoredWindow { similarity, episode_id: e.episode_id, time: w.time, offsets: w.offsets }),
+                    Err(_) => mismatched += 1,
+                }
+            }
+            if candidates.len() >= 2 * keep {
+                keep_best(&mut candidates, keep);
+            }
+        }
+        trace!("timings: search_semantic scoring: {:?}", _t.elapsed());
+        if mismatched > 0 {
+            warn!("{} embedded windows don't have the {} dimensions of the query, skipping them", mismatched, query.len());
+        }
+        let best = select(candidates, limit);
+        if best.is_empty() {
+            return Err(SearchError::NoResults);
+        }
+
+        let mut transcripts: HashMap<u32, Option<(Episode, EpisodeTranscript)>> = HashMap::new();
+        let mut hits = vec![];
+        for w in best {
+            if let Entry::Vacant(v) = transcripts.entry(w.episode_id) {
+                let e = self.get::<Episode>(w.episode_id).await?;
+                let t = self.get::<EpisodeTranscript>(w.episode_id).await?;
+                v.insert(e.zip(t));
+            }
+            // the transcript may have been deleted since the episode was embedded
+            if let Some((e, t)) = &transcripts[&w.episode_id] {
+                let mut text = t.data.substring(w.offsets.0, w.offsets.1.min(w.offsets.0 + MAX_HIT_TEXT)).trim().to_owned();
+                if w.offsets.1 > w.offsets.0 + MAX_HIT_TEXT {
+                    text.push('…');
+                }
+                hits.push(SemanticHit { episode: e.clone(), time: w.time, similarity: w.similarity, text });
+            }
+        }
+        trace!("timings: search_semantic: {:?}", _t.elapsed());
+        Ok(hits)
+    }
+}
+
+/// Keep only the `n` most similar of `windows`, most similar first.
+fn keep_best(windows: &mut Vec<ScoredWindow>, n: usize) {
+    windows.sort_unstable_by(|a, b| b.similarity.total_cmp(&a.similarity));
+    windows.truncate(n);
+}
+
+/// The `limit` most similar of `windows`, skipping those overlapping a better one of the same
+/// episode.
+fn select(mut windows: Vec<ScoredWindow>, limit: usize) -> Vec<ScoredWindow> {
+    windows.sort_unstable_by(|a, b| b.similarity.total_cmp(&a.similarity));
+    let mut best: Vec<ScoredWindow> = vec![];
+    for w in windows {
+        if best.len() == limit {
+            break;
+        }
+        if !best.iter().any(|b| b.episode_id == w.episode_id && b.time.from < w.time.to && w.time.from < b.time.to) {
+            best.push(w);
+        }
+    }
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::*;
+
+    fn window(similarity: f32, episode_id: u32, from: u64, to: u64) -> ScoredWindow {
+        let time = FromTo { from: Duration::from_secs(from), to: Duration::from_secs(to) };
+        ScoredWindow { similarity, episode_id, time, offsets: (0, 0) }
+    }
+
+    #[test]
+    fn select_skips_overlapping_windows() {
+        let windows = vec![
+            window(0.5, 1, 20, 60),
+            window(0.9, 1, 0, 40),
+            window(0.8, 2, 0, 40),
+            // touching the best one isn't overlapping it
+            window(0.4, 1, 40, 80),
+            window(0.3, 2, 60, 100),
+        ];
+        let best = select(windows, 3);
+        let picked = best.iter().map(|w| (w.episode_id, w.time.from.as_secs())).collect::<Vec<_>>();
+        assert_eq!(picked, vec![(1, 0), (2, 0), (1, 40)]);
+    }
+
+    #[test]
+    fn keep_best_keeps_the_most_similar() {
+        let mut windows = (0..10).map(|i| window(i as f32 / 10.0, i, 0, 1)).collect::<Vec<_>>();
+        keep_best(&mut windows, 3);
+        assert_eq!(windows.iter().map(|w| w.episode_id).collect::<Vec<_>>(), vec![9, 8, 7]);
+    }
+}
@@ -6,45 +6,114 @@ use teloxide::utils::markdown;
 pub static DESC_COMMAND_SEARCH: &str = concat!(
     "Ricerca semplice: cerca all'interno di titoli e scontrini (descrizioni) degli episodi.\n",
     "Sintassi `/search {query}`.\n",
-    "La query è case-insensitive.\n",
+    "La query è case-insensitive. Se la query è uno degli argomenti elencati nello scontrino viene mostrato anche quello, ",
+    "con il link al momento in cui se ne parla quando lo scontrino lo indica.\n",
     "Es. \n",
-    "- `/s pokemon` trova tutte le puntate con \"pokemon\" nel titolo o nella descrizione.\n",
+    "- `/s pokemon` trova tutte le puntate con \"pokemon\" nel titolo, nella descrizione o tra gli ospiti.\n",
     "- `/s green oaks` trova la puntata \"PPP Speciale: PGdR™ - Green Oaks\".",
 );
 
 pub static DESC_COMMAND_SEARCH_ADVANCED: &str = concat!(
     "Ricerca transcript: cerca all'interno della *trascrizione* della puntata, ovvero quello che viene pronunciato ",
     " dagli host in puntata. La ricerca viene effettuata su tutte le puntate. Una volta trovata la puntata utilizza ",
-    "il comando /sae per cercare all'interno di una singola puntata.\n",
+    "il comando /sae per cercare all'interno di una singola puntata, oppure il pulsante \"🔍 Cerca in\" sotto ai risultati.\n",
     "Sintassi `/sa {query}`.\n",
     "La query è case-insensitive. E supporta alcune keywords come google, ecco alcuni esempi: \n",
     "- `lorro -sio`: cerca tutte le puntate in cui viene detto \"lorro\" ed esclude quelle in cui viene detto \"sio\".\n",
     "- `nick sio`: cerca tutte le puntate in cui viene detto \"nick\" e quelle in cui viene detto \"sio\".\n",
     "- `\"nick lorro\"`: cerca tutte le puntate in cui viene detto \"nick\" e subito dopo \"lorro\".\n",
     "Es. se voglio cercare \"pokemon rosso\", devo scrivere `/sa \"pokemon rosso\"`, se scrivo `/sa pokemon rosso` la ",
-    "ricerca sarà su tutte le puntate in cui viene detto \"pokemon\", ma anche **tutte** le puntate in cui viene detto \"rosso\"!.",
+    "ricerca sarà su tutte le puntate in cui viene detto \"pokemon\", ma anche **tutte** le puntate in cui viene detto \"rosso\"!.\n",
+    "Inizia la query con `~` per una ricerca approssimata, che trova anche le parole trascritte male: ",
+    "`/sa ~undertale` trova anche \"under tail\". È più lenta, cerca solo nelle 200 puntate più recenti e ignora le parole escluse con `-`.",
 );
 
 pub static DESC_COMMAND_SEARCH_ADVANCED_EPISODE: &str = concat!(
     "Ricerca testo del transcript di una puntata, fornisci il numero della puntata e il testo.\n",
     "Sintassi `/sae {episodio} {query}`.\n",
-    "La query è case-insensitive. `{episodio}` può essere il numero dell'episodio, il titolo o il codice identificativo spreaker ",
-    "(avanzato).\n",
-    "La query non supporta le keywords di /sa. Ma supporta ricerca tramite regex (avanzato).\n",
-    "Gli argomenti possono essere racchiusi tra virgolette `\"` per cercare frasi intere.\n",
+    "La query è case-insensitive. `{episodio}` può essere il numero dell'episodio (`12` o `#12`), il titolo, anche approssimato, ",
+    "o il codice identificativo spreaker (avanzato). Per le puntate speciali usa il titolo, es. `\"pgdr green oaks\"`. ",
+    "Se più episodi corrispondono potrai scegliere quello giusto.\n",
+    "La query non supporta le keywords di /sa e viene cercata così com'è. Per cercare tramite regex (avanzato) ",
+    "racchiudila tra `/`, es. `/sae 1 /poke?mon/`; nelle regex le lettere accentate vanno scritte senza accento.\n",
+    "Tutto quello che segue l'episodio è la query; l'episodio può essere racchiuso tra virgolette `\"` se contiene spazi.\n",
     "Es.\n",
-    "- `/sae 1 \"pokemon rosso\"`: cerca la frase \"pokemon rosso\" all'interno della puntata",
+    "- `/sae 1 pokemon rosso`: cerca la frase \"pokemon rosso\" all'interno della puntata\n",
+    "- `/sae 1 pokemon speaker:sio`: cerca \"pokemon\" solo quando a parlare è Sio\n",
+    "- `/sae 1 ~undertale`: ricerca approssimata, come per /sa, mostra anche le parole trovate",
 );
 
-pub static WELCOME_STRING: &str = concat!(
-    "Ciao! Sono il bot di PPP, posso aiutarti a trovare le puntate in cui si parla di un argomento specifico.",
+pub static DESC_QUERY_FILTERS: &str = concat!(
+    "Filtri: /s, /sa e /sae accettano dei filtri nella forma `{filtro}:{valore}`, in qualsiasi punto della query.\n",
+    "- `after:{data}` e `before:{data}`: puntate uscite da quella data in poi o prima di quella data; la data può essere ",
+    "`2022`, `2022-03` o `2022-03-15`\n",
+    "- `longer:{durata}` e `shorter:{durata}`: puntate più lunghe o più corte di così, es. `90m`, `1h30m` o `45` (minuti)\n",
+    "- `title:{testo}`: puntate con quel testo nel titolo\n",
+    "- `speaker:{nome}`: solo quello che dice un certo host, solo con /sa e /sae\n",
+    "- `series:{serie}`: puntate di una serie speciale, `pgdr` o `speciale`\n",
+    "- `guest:{nome}`: puntate con un certo ospite\n",
+    "I valori con spazi vanno racchiusi tra virgolette, es. `title:\"green oaks\"`.\n",
+    "Es.\n",
+    "- `/sa undertale after:2022 speaker:sio`: puntate dal 2022 in cui Sio parla di Undertale\n",
+    "- `/s after:2023-01 before:2023-02`: puntate uscite a gennaio 2023",
 );
 
-/// Note: the footer string must be **markdown** formatted!
-pub static FOOTER_STRING: &str = concat!(
-    "Questo bot è sviluppato da @topongo ed è open\\-source\\! [topongo/ppp\\-bot](https://github.com/topongo/ppp\\-bot)",
+pub static DESC_COMMAND_SEMANTIC: &str = concat!(
+    "Ricerca per argomento: trova i momenti delle puntate in cui si parla di qualcosa, anche se non vengono usate ",
+    "proprio le parole della query.\n",
+    "Sintassi `/sem {descrizione}`.\n",
+    "Es.\n",
+    "- `/sem giochi horror ambientati nello spazio`",
+);
+
+pub static DESC_COMMAND_MENTIONS: &str = concat!(
+    "Menzioni: elenca le puntate in cui si parla di un gioco o di un titolo, a partire da quelle in cui se ne parla di più, ",
+    "con i link ai momenti in cui viene nominato. I titoli riconosciuti sono quelli di un dizionario e degli scontrini.\n",
+    "Sintassi `/mentions {titolo}`.\n",
+    "Es.\n",
+    "- `/mentions zelda`: tutte le puntate in cui si parla di un gioco di Zelda",
 );
 
+pub static DESC_COMMAND_SUMMARY: &str = concat!(
+    "Riassunto: mostra il riassunto di una puntata e i suoi capitoli, ognuno con il link al suo inizio. ",
+    "Sono scritti automaticamente dalla trascrizione, quindi possono contenere errori.\n",
+    "Sintassi `/summary {episodio}`.\n",
+    "`{episodio}` funziona come per /sae.\n",
+    "Es.\n",
+    "- `/summary 100`: riassunto della puntata 100",
+);
+
+pub static DESC_COMMAND_EPISODE: &str = concat!(
+    "Episodio: mostra i dettagli di una puntata, con numero, serie, ospiti, link citati nella descrizione e capitoli dello ",
+    "scontrino, ognuno con il link al momento in cui se ne parla.\n",
+    "Sintassi `/episode {episodio}`.\n",
+    "`{episodio}` funziona come per /sae.\n",
+    "Es.\n",
+    "- `/episode 100`: mostra la puntata 100",
+);
+
+pub static DESC_COMMAND_SUBTITLES: &str = concat!(
+    "Sottotitoli: invia la trascrizione di una puntata come file.\n",
+    "Sintassi `/sub {episodio} {formato}`.\n",
+    "`{episodio}` funziona come per /sae, `{formato}` può essere `srt` (predefinito), `vtt`, `md`, `txt` o `json`.\n",
+    "Es.\n",
+    "- `/sub 1 vtt`: invia i sottotitoli WebVTT della puntata 1",
+);
+
+pub static DESC_COMMAND_CLIP: &str = concat!(
+    "Clip audio: invia il pezzo di puntata in cui viene detta una frase, come messaggio vocale.\n",
+    "Sintassi `/clip {episodio} {query} {n}` oppure `/clip {episodio} {inizio}-{fine}`.\n",
+    "`{n}` è il numero del risultato di /sae da ascoltare (predefinito il primo), i tempi sono nel formato `mm:ss`.\n",
+    "Es.\n",
+    "- `/clip 1 undertale 2`: invia il secondo punto della puntata 1 in cui si dice \"undertale\"\n",
+    "- `/clip 1 12:30-13:00`: invia la puntata 1 dal minuto 12:30 al minuto 13:00",
+);
+
+pub static WELCOME_STRING: &str = "Ciao! Sono il bot di PPP, posso aiutarti a trovare le puntate in cui si parla di un argomento specifico.";
+
+/// Note: the footer string must be **markdown** formatted!
+pub static FOOTER_STRING: &str = "Questo bot è sviluppato da @topongo ed è open\\-source\\! [topongo/ppp\\-bot](https://github.com/topongo/ppp\\-bot)";
+
 
 lazy_static!{
         // (':', '.', '(', ')', '-', '!'].iter().cloned().collect();
@@ -59,7 +128,7 @@ lazy_static!{
     pub static ref HELP_MESSAGE: String = format!(
         "{}\n\n{}\n\n{}",
         markdown::escape(WELCOME_STRING),
-        [DESC_COMMAND_SEARCH, DESC_COMMAND_SEARCH_ADVANCED, DESC_COMMAND_SEARCH_ADVANCED_EPISODE]
+        [DESC_COMMAND_SEARCH, DESC_COMMAND_SEARCH_ADVANCED, DESC_COMMAND_SEARCH_ADVANCED_EPISODE, DESC_QUERY_FILTERS, DESC_COMMAND_SEMANTIC, DESC_COMMAND_MENTIONS, DESC_COMMAND_EPISODE, DESC_COMMAND_SUMMARY, DESC_COMMAND_SUBTITLES, DESC_COMMAND_CLIP]
             .iter()
             .map(|s| s
                 .chars()
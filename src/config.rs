@@ -1,4 +1,4 @@
-use std::{io::{Read, Write}, path::Path, process::exit};
+use std::{collections::HashMap, io::{Read, Write}, path::Path, process::exit};
 
 use lazy_static::lazy_static;
 use log::debug;
@@ -10,6 +10,8 @@ pub struct Config {
     pub db: DbConfig,
     pub tg: TgConfig,
     pub import: ImportConfig,
+    #[serde(default)]
+    pub download: DownloadConfig,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -44,10 +46,37 @@ impl Default for DbConfig {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct TgConfig {
     pub token: String,
     pub admin: String,
+    /// Cache directory for the audio clips sent by the bot.
+    #[serde(default = "TgConfig::default_clip_dir")]
+    pub clip_dir: String,
+    /// Size in bytes above which the oldest clips are removed from `clip_dir`.
+    #[serde(default = "TgConfig::default_clip_cache_size")]
+    pub clip_cache_size: u64,
+}
+
+impl TgConfig {
+    fn default_clip_dir() -> String {
+        "clips".to_owned()
+    }
+
+    fn default_clip_cache_size() -> u64 {
+        256 * 1024 * 1024
+    }
+}
+
+impl Default for TgConfig {
+    fn default() -> Self {
+        Self {
+            token: String::new(),
+            admin: String::new(),
+            clip_dir: Self::default_clip_dir(),
+            clip_cache_size: Self::default_clip_cache_size(),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -57,6 +86,57 @@ pub struct ImportConfig {
     pub wav_dir: String,
     pub transcript_dir: String,
     pub transcriber_url: String,
+    /// Diarization backend, the stage is skipped when unset.
+    #[serde(default)]
+    pub diarizer: Option<DiarizerConfig>,
+    /// Map from the diarization backend labels to host names.
+    #[serde(default)]
+    pub speakers: HashMap<String, String>,
+    /// Directory of the embedded transcript index, used only when built with the `index` feature.
+    /// The index is neither updated nor searched when unset.
+    #[serde(default)]
+    pub index_dir: Option<String>,
+    /// Embedding backend for semantic search, the stage is skipped when unset.
+    #[serde(default)]
+    pub embedder: Option<EmbedderConfig>,
+    /// Dictionary of game and media titles to look for in transcripts, see `MentionDictionary`.
+    /// The stage is skipped when unset.
+    #[serde(default)]
+    pub mentions_file: Option<String>,
+    /// Language model writing episode summaries and chapters, the stage is skipped when unset.
+    #[serde(default)]
+    pub summarizer: Option<SummarizerConfig>,
+    /// Transcripts scoring less than this are transcribed again, see `TranscriptQuality`. Scores
+    /// are stored anyway, nothing is transcribed again when unset.
+    #[serde(default)]
+    pub min_transcript_quality: Option<f32>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum DiarizerConfig {
+    /// See `Diarizer` for the protocol.
+    Http { url: String },
+    /// Made up turns alternating between two speakers, no backend needed.
+    Stub,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum EmbedderConfig {
+    /// See `Embedder` for the protocol.
+    Http { url: String },
+    /// Local word hashing, no backend needed.
+    Stub { dimensions: usize },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum SummarizerConfig {
+    /// See `Summarizer` for the protocol.
+    Http { url: String, model: Option<String> },
+    /// Titles and summary made of the most frequent words, no backend needed.
+    Stub,
 }
 
 impl Default for ImportConfig {
@@ -67,6 +147,33 @@ impl Default for ImportConfig {
             wav_dir: "audio/wav".to_owned(),
             transcript_dir: "transcripts".to_owned(),
             transcriber_url: "http://localhost:8080/inference".to_owned(),
+            diarizer: None,
+            speakers: HashMap::new(),
+            index_dir: None,
+            embedder: None,
+            mentions_file: None,
+            summarizer: None,
+            min_transcript_quality: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DownloadConfig {
+    /// Maximum bandwidth used by all downloads together, in bytes per second.
+    pub bandwidth_limit: Option<u64>,
+    /// Maximum number of requests per second sent to Spreaker.
+    pub requests_per_second: Option<f64>,
+    /// Seconds between two progress log lines.
+    pub progress_interval: u64,
+}
+
+impl Default for DownloadConfig {
+    fn default() -> Self {
+        Self {
+            bandwidth_limit: None,
+            requests_per_second: None,
+            progress_interval: 10,
         }
     }
 }
@@ -38,6 +38,10 @@ impl PPPDatabase {
             .options(IndexOptions::builder().default_language("italian".to_owned()).build())
             .build()
         ).await?;
+        self.db
+            .collection::<()>("transcripts")
+            .create_index(IndexModel::builder().keys(doc!{"episode_id": 1}).build())
+            .await?;
         self.db
             .collection::<()>("episodes")
             .create_index(IndexModel::builder()
@@ -45,6 +49,20 @@ impl PPPDatabase {
                 .options(IndexOptions::builder().unique(true).build())
                 .build()
         ).await?;
+        for key in ["number", "series", "guests"] {
+            self.db
+                .collection::<()>("episodes")
+                .create_index(IndexModel::builder().keys(doc!{key: 1}).build())
+                .await?;
+        }
+        self.db
+            .collection::<()>("mentions")
+            .create_index(IndexModel::builder().keys(doc!{"mentions.title": 1}).build())
+            .await?;
+        self.db
+            .collection::<()>("quality")
+            .create_index(IndexModel::builder().keys(doc!{"queued": 1}).build())
+            .await?;
         self.db
             .collection::<()>("users")
             .create_index(IndexModel::builder()
@@ -1,10 +1,13 @@
 use std::{fs::create_dir_all, sync::Arc};
 use std::path::PathBuf;
+use std::time::Duration;
 
-use power_pizza_bot::spreaker::{SimpleEpisode, SpreakerDownloader, SpreakerListResponse, SpreakerResponse};
+use power_pizza_bot::{config::CONFIG, throttle::Throttle};
+use power_pizza_bot::spreaker::{SimpleEpisode, SpreakerData, SpreakerDownloader};
 use reqwest::Client;
 use tokio_stream::StreamExt;
 use lazy_static::lazy_static;
+use log::{error, info, warn};
 
 lazy_static! {
     static ref OUTPUT_DIR: PathBuf = PathBuf::from("output");
@@ -12,11 +15,11 @@ lazy_static! {
 
 
 #[tokio::main]
-async fn main() -> Result<(), Box<(dyn std::error::Error + 'static)>> {
+async fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
     pretty_env_logger::init();
     let cli = Arc::new(Client::new());
 
-    let mut it = SpreakerResponse::<SimpleEpisode>::request(
+    let mut it = SpreakerData::<SimpleEpisode>::request(
         "https://api.spreaker.com/v2/shows/3039391/episodes".to_owned(),
         cli.clone(),
     );
@@ -25,11 +28,33 @@ async fn main() -> Result<(), Box<(dyn std::error::Error + 'static)>> {
         create_dir_all(OUTPUT_DIR.clone()).unwrap()
     }
 
-    let downloader = SpreakerDownloader::new(cli, 4, OUTPUT_DIR.clone());
-    while let Some(e) = it.next().await {
-        downloader.download(e);
+    let mut downloader = SpreakerDownloader::new(cli, 4, OUTPUT_DIR.clone(), true, Arc::new(Throttle::from_config(&CONFIG.download))).await?;
+    downloader.reporter().log_every(Duration::from_secs(CONFIG.download.progress_interval));
+    let cancel = downloader.cancel_handle();
+    let cancelled = cancel.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            warn!("interrupted, stopping downloads");
+            cancel.cancel();
+        }
+    });
+    // stop paging through the episodes too, or every one left would be queued just to be cancelled
+    loop {
+        tokio::select! {
+            e = it.next() => match e {
+                Some(e) => downloader.download(e),
+                None => break,
+            },
+            _ = cancelled.cancelled() => break,
+        }
+    }
+    let results = downloader.join().await;
+    let failed: Vec<_> = results.iter().filter(|(_, r)| r.is_err()).collect();
+    info!("{} episodes downloaded, {} failed", results.len() - failed.len(), failed.len());
+    for (id, e) in failed {
+        // unwrap safe: filtered above
+        error!("episode {}: {}", id, e.as_ref().unwrap_err());
     }
-    downloader.join().await.unwrap();
 
     Ok(())
 }
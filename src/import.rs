@@ -2,8 +2,10 @@ use std::{collections::HashSet, sync::Arc, time::Duration};
 use tokio::sync::Mutex;
 #[allow(unused_imports)]
 use log::{info,debug,warn,error};
+use futures_util::TryStreamExt;
+use mongodb::bson::doc;
 use crate::db::DB;
-use crate::spreaker::{Episode, SimpleEpisode, SpreakerData};
+use crate::spreaker::{Episode, SimpleEpisode, SpreakerData, ENRICH_VERSION};
 use tokio_stream::StreamExt;
 
 pub async fn import_database(show: String) -> Result<(), Box<dyn std::error::Error>> {
@@ -83,6 +85,27 @@ pub async fn import_database(show: String) -> Result<(), Box<dyn std::error::Err
             }
         }
     }
+    enrich_episodes().await?;
 
     Ok(())
 }
+
+/// Parse the structured fields of the episodes imported before they existed, or parsed by an
+/// older version of the parsers, see `Episode::enrich` and `ENRICH_VERSION`.
+async fn enrich_episodes() -> Result<(), mongodb::error::Error> {
+    let missing = DB.db
+        .collection::<Episode>("episodes")
+        // episodes without a version were imported before it existed
+        .find(doc!{"enriched": {"$not": {"$gte": ENRICH_VERSION}}})
+        .await?
+        .try_collect::<Vec<_>>()
+        .await?;
+    if !missing.is_empty() {
+        info!("enriching {} episodes", missing.len());
+    }
+    for mut e in missing {
+        e.enrich();
+        DB.update_one_stateless(e.id, &e).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,282 @@
+//! Embedded full-text index of transcript segments, an alternative to the MongoDB `$text` index
+//! offering phrase queries, per-segment hits and highlights. Kept on local disk in
+//! `CONFIG.import.index_dir`: the import writes it, the bot only reads it.
+use std::{fmt::Display, ops::Range, path::Path, time::Duration};
+use lazy_static::lazy_static;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace};
+use tantivy::{
+    collector::TopDocs,
+    directory::MmapDirectory,
+    doc,
+    query::{BooleanQuery, Occur, Query, QueryParser, TermQuery},
+    schema::{Field, IndexRecordOption, Schema, TextFieldIndexing, TextOptions, Value, FAST, INDEXED, STORED},
+    snippet::SnippetGenerator,
+    tokenizer::{AsciiFoldingFilter, Language, LowerCaser, RawTokenizer, RemoveLongFilter, SimpleTokenizer, Stemmer, TextAnalyzer},
+    Index, IndexReader, IndexWriter, ReloadPolicy, TantivyDocument, Term,
+};
+
+use crate::{config::CONFIG, transcript::{EpisodeTranscript, FromTo}};
+
+/// Heap shared by the indexing threads.
+const WRITER_HEAP: usize = 64 * 1024 * 1024;
+/// Length of the fragment of text around a hit.
+const SNIPPET_LENGTH: usize = 150;
+
+lazy_static! {
+    /// The index in `CONFIG.import.index_dir`, if set and readable.
+    pub static ref INDEX: Option<TranscriptIndex> = CONFIG.import.index_dir
+        .as_ref()
+        .and_then(|dir| TranscriptIndex::open(dir)
+            .map_err(|e| error!("failed to open transcript index in {}: {}", dir, e))
+            .ok());
+}
+
+pub struct TranscriptIndex {
+    index: Index,
+    reader: IndexReader,
+    fields: Fields,
+}
+
+#[derive(Clone, Copy)]
+struct Fields {
+    episode_id: Field,
+    from: Field,
+    to: Field,
+    speaker: Field,
+    text: Field,
+}
+
+impl Fields {
+    fn schema() -> Schema {
+        let mut b = Schema::builder();
+        b.add_u64_field("episode_id", INDEXED | STORED | FAST);
+        b.add_u64_field("from", STORED);
+        b.add_u64_field("to", STORED);
+        b.add_text_field("speaker", Self::options("speaker"));
+        b.add_text_field("text", Self::options("italian"));
+        b.build()
+    }
+
+    fn options(tokenizer: &str) -> TextOptions {
+        TextOptions::default()
+            .set_indexing_options(TextFieldIndexing::default()
+                .set_tokenizer(tokenizer)
+                .set_index_option(IndexRecordOption::WithFreqsAndPositions))
+            .set_stored()
+    }
+
+    fn from(schema: &Schema) -> Result<Self, IndexError> {
+        Ok(Self {
+            episode_id: schema.get_field("episode_id")?,
+            from: schema.get_field("from")?,
+            to: schema.get_field("to")?,
+            speaker: schema.get_field("speaker")?,
+            text: schema.get_field("text")?,
+        })
+    }
+}
+
+/// A transcript segment matching a query.
+#[derive(Debug)]
+pub struct SegmentHit {
+    pub episode_id: u32,
+    pub time: FromTo,
+    pub speaker: Option<String>,
+    pub score: f32,
+    /// Text around the matched words, at most `SNIPPET_LENGTH` bytes.
+    pub fragment: String,
+    /// Byte ranges of the matched words in `fragment`.
+    pub highlighted: Vec<Range<usize>>,
+}
+
+impl SegmentHit {
+    /// Split `fragment` into pieces, telling which ones were matched.
+    pub fn pieces(&self) -> Vec<(&str, bool)> {
+        let mut pieces = vec![];
+        let mut last = 0;
+        for r in self.highlighted.iter() {
+            if r.start > last {
+                pieces.push((&self.fragment[last..r.start], false));
+            }
+            pieces.push((&self.fragment[r.clone()], true));
+            last = r.end;
+        }
+        if last < self.fragment.len() {
+            pieces.push((&self.fragment[last..], false));
+        }
+        pieces
+    }
+}
+
+impl TranscriptIndex {
+    /// Open the index in `dir`, failing if there is none: readers have nothing to search in a
+    /// new index, it's up to the import to build it.
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self, IndexError> {
+        Self::with_index(Index::open(MmapDirectory::open(&dir)?)?)
+    }
+
+    /// Open the index in `dir`, creating an empty one if missing, for the import.
+    pub fn open_or_create<P: AsRef<Path>>(dir: P) -> Result<Self, IndexError> {
+        std::fs::create_dir_all(&dir)?;
+        Self::with_index(Index::open_or_create(MmapDirectory::open(&dir)?, Fields::schema())?)
+    }
+
+    fn with_index(index: Index) -> Result<Self, IndexError> {
+        index.tokenizers().register("italian", TextAnalyzer::builder(SimpleTokenizer::default())
+            .filter(RemoveLongFilter::limit(40))
+            .filter(LowerCaser)
+            .filter(AsciiFoldingFilter)
+            .filter(Stemmer::new(Language::Italian))
+            .build());
+        index.tokenizers().register("speaker", TextAnalyzer::builder(RawTokenizer::default())
+            .filter(LowerCaser)
+            .build());
+        let fields = Fields::from(&index.schema())?;
+        let reader = index.reader_builder().reload_policy(ReloadPolicy::OnCommitWithDelay).try_into()?;
+        Ok(Self { index, reader, fields })
+    }
+
+    /// Take the write lock on the index, only one writer can exist at a time across processes.
+    pub fn writer(&self) -> Result<TranscriptIndexWriter, IndexError> {
+        Ok(TranscriptIndexWriter {
+            writer: self.index.writer(WRITER_HEAP)?,
+            fields: self.fields,
+        })
+    }
+
+    /// Search the segments of every transcript, best hits first. `query` supports the usual
+    /// syntax: `"quoted phrases"`, `"phrases with slop"~2`, `+required` and `-excluded` words.
+    /// Syntax errors are not fatal, the parts of the query that make sense are used.
+    pub fn search(&self, query: &str, speaker: Option<&str>, limit: usize) -> Result<Vec<SegmentHit>, IndexError> {
+        let searcher = self.reader.searcher();
+        let parser = QueryParser::for_index(&self.index, vec![self.fields.text]);
+        let (text_query, errors) = parser.parse_query_lenient(query);
+        if !errors.is_empty() {
+            debug!("ignoring errors in query {:?}: {:?}", query, errors);
+        }
+        let query: Box<dyn Query> = match speaker {
+            Some(s) => Box::new(BooleanQuery::new(vec![
+                (Occur::Must, text_query.box_clone()),
+                (Occur::Must, Box::new(TermQuery::new(Term::from_field_text(self.fields.speaker, &s.to_lowercase()), IndexRecordOption::Basic))),
+            ])),
+            None => text_query.box_clone(),
+        };
+        let top = searcher.search(&query, &TopDocs::with_limit(limit))?;
+        // highlight the words of the text query only, the speaker isn't part of the text
+        let mut snippets = SnippetGenerator::create(&searcher, &*text_query, self.fields.text)?;
+        snippets.set_max_num_chars(SNIPPET_LENGTH);
+
+        let mut hits = vec![];
+        for (score, address) in top {
+            let d: TantivyDocument = searcher.doc(address)?;
+            let u64_of = |f: Field| d.get_first(f).and_then(|v| v.as_u64()).unwrap_or_default();
+            let snippet = snippets.snippet_from_doc(&d);
+            hits.push(SegmentHit {
+                episode_id: u64_of(self.fields.episode_id) as u32,
+                time: FromTo {
+                    from: Duration::from_millis(u64_of(self.fields.from)),
+                    to: Duration::from_millis(u64_of(self.fields.to)),
+                },
+                speaker: d.get_first(self.fields.speaker).and_then(|v| v.as_str()).map(str::to_owned),
+                score,
+                fragment: snippet.fragment().to_owned(),
+                highlighted: snippet.highlighted().to_vec(),
+            });
+        }
+        trace!("index search for {:?}: {} hits", query, hits.len());
+        Ok(hits)
+    }
+}
+
+pub struct TranscriptIndexWriter {
+    writer: IndexWriter,
+    fields: Fields,
+}
+
+impl TranscriptIndexWriter {
+    /// Replace the segments of `transcript`'s episode. Changes are visible after `commit`.
+    pub fn update(&mut self, transcript: &EpisodeTranscript) -> Result<(), IndexError> {
+        let f = self.fields;
+        self.writer.delete_term(Term::from_field_u64(f.episode_id, transcript.episode_id as u64));
+        for (t, (time, text)) in transcript.timestamps.iter().zip(transcript.segments()) {
+            let mut d = doc!(
+                f.episode_id => transcript.episode_id as u64,
+                f.from => time.from.as_millis() as u64,
+                f.to => time.to.as_millis() as u64,
+                f.text => text.trim(),
+            );
+            if let Some(s) = &t.speaker {
+                d.add_text(f.speaker, s);
+            }
+            self.writer.add_document(d)?;
+        }
+        debug!("indexed {} segments of episode {}", transcript.timestamps.len(), transcript.episode_id);
+        Ok(())
+    }
+
+    /// Remove every segment, to rebuild the index from scratch.
+    pub fn clear(&mut self) -> Result<(), IndexError> {
+        self.writer.delete_all_documents()?;
+        Ok(())
+    }
+
+    pub fn commit(&mut self) -> Result<(), IndexError> {
+        self.writer.commit()?;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum IndexError {
+    Tantivy(tantivy::TantivyError),
+    Directory(tantivy::directory::error::OpenDirectoryError),
+    Io(std::io::Error),
+}
+
+impl Display for IndexError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tantivy(e) => write!(f, "Tantivy error: {}", e),
+            Self::Directory(e) => write!(f, "Index directory error: {}", e),
+            Self::Io(e) => write!(f, "IO error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for IndexError {}
+
+impl From<tantivy::TantivyError> for IndexError {
+    fn from(e: tantivy::TantivyError) -> Self {
+        Self::Tantivy(e)
+    }
+}
+
+impl From<tantivy::directory::error::OpenDirectoryError> for IndexError {
+    fn from(e: tantivy::directory::error::OpenDirectoryError) -> Self {
+        Self::Directory(e)
+    }
+}
+
+impl From<std::io::Error> for IndexError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn open_needs_an_existing_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index");
+        assert!(TranscriptIndex::open(&path).is_err());
+        // an empty directory has no index either
+        std::fs::create_dir(&path).unwrap();
+        assert!(TranscriptIndex::open(&path).is_err());
+        TranscriptIndex::open_or_create(&path).unwrap();
+        assert!(TranscriptIndex::open(&path).unwrap().search("pizza", None, 10).unwrap().is_empty());
+    }
+}
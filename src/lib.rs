@@ -6,3 +6,7 @@ pub mod import;
 pub mod transcript;
 pub mod bot;
 pub mod config;
+pub mod progress;
+pub mod throttle;
+#[cfg(feature = "index")]
+pub mod index;
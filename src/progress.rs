@@ -0,0 +1,209 @@
+use std::{collections::BTreeMap, time::{Duration, Instant}};
+use log::info;
+use serde::Serialize;
+use tokio::{sync::watch, task::JoinHandle};
+
+/// Log target used for progress lines, filter on it with `RUST_LOG=ppp_progress=info`.
+pub const PROGRESS_TARGET: &str = "ppp_progress";
+
+/// Snapshot of a batch of downloads.
+#[derive(Debug, Clone)]
+pub struct DownloadProgress {
+    pub started: Instant,
+    /// Bytes received during this run, across all episodes.
+    pub bytes: u64,
+    pub episodes_done: usize,
+    /// Episodes that had nothing to transfer, e.g. already downloaded. They don't count toward
+    /// the size of the completed ones.
+    pub episodes_skipped: usize,
+    pub episodes_failed: usize,
+    pub episodes_total: usize,
+    /// Episodes currently being downloaded.
+    pub active: BTreeMap<u32, EpisodeProgress>,
+    /// Size of the episodes completed so far, used to estimate the ones not started yet.
+    finished_bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EpisodeProgress {
+    pub bytes: u64,
+    pub expected: Option<u64>,
+}
+
+impl Default for DownloadProgress {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            bytes: 0,
+            episodes_done: 0,
+            episodes_skipped: 0,
+            episodes_failed: 0,
+            episodes_total: 0,
+            active: BTreeMap::new(),
+            finished_bytes: 0,
+        }
+    }
+}
+
+impl DownloadProgress {
+    /// Average download rate since the start of the run, in bytes per second.
+    pub fn rate(&self) -> f64 {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        if elapsed > 0.0 {
+            self.bytes as f64 / elapsed
+        } else {
+            0.0
+        }
+    }
+
+    pub fn pending(&self) -> usize {
+        self.episodes_total.saturating_sub(self.episodes_done + self.episodes_skipped + self.episodes_failed + self.active.len())
+    }
+
+    /// Estimated time left for the whole batch. Episodes not started yet are assumed to be as big
+    /// as the average completed one, active episodes without a known size are ignored.
+    pub fn eta(&self) -> Option<Duration> {
+        let rate = self.rate();
+        if rate <= 0.0 {
+            return None;
+        }
+        let active = self.active
+            .values()
+            .filter_map(|e| e.expected.map(|x| x.saturating_sub(e.bytes)))
+            .sum::<u64>();
+        let pending = match self.episodes_done {
+            0 => 0,
+            n => self.finished_bytes / n as u64 * self.pending() as u64,
+        };
+        Some(Duration::from_secs_f64((active + pending) as f64 / rate))
+    }
+
+    /// Render this snapshot as a single JSON line.
+    pub fn log_line(&self, kind: &str) -> String {
+        #[derive(Serialize)]
+        struct Line<'a> {
+            kind: &'a str,
+            elapsed_secs: u64,
+            bytes: u64,
+            rate: u64,
+            eta_secs: Option<u64>,
+            episodes_done: usize,
+            episodes_skipped: usize,
+            episodes_failed: usize,
+            episodes_total: usize,
+            active: &'a BTreeMap<u32, EpisodeProgress>,
+        }
+        // unwrap safe: the line only contains plain values and string keys
+        serde_json::to_string(&Line {
+            kind,
+            elapsed_secs: self.started.elapsed().as_secs(),
+            bytes: self.bytes,
+            rate: self.rate() as u64,
+            eta_secs: self.eta().map(|d| d.as_secs()),
+            episodes_done: self.episodes_done,
+            episodes_skipped: self.episodes_skipped,
+            episodes_failed: self.episodes_failed,
+            episodes_total: self.episodes_total,
+            active: &self.active,
+        }).unwrap()
+    }
+}
+
+/// Collects progress from concurrent download tasks. Cloning the reporter shares the same state.
+#[derive(Clone)]
+pub struct ProgressReporter {
+    kind: &'static str,
+    tx: watch::Sender<DownloadProgress>,
+}
+
+impl ProgressReporter {
+    pub fn new(kind: &'static str) -> Self {
+        Self {
+            kind,
+            tx: watch::Sender::new(DownloadProgress::default()),
+        }
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<DownloadProgress> {
+        self.tx.subscribe()
+    }
+
+    pub fn enqueue(&self) {
+        self.tx.send_modify(|p| p.episodes_total += 1);
+    }
+
+    /// Mark episode `id` as started. `resumed_from` is the number of bytes already on disk from a
+    /// previous run, which count toward the episode but not toward this run's rate.
+    pub fn start(&self, id: u32, resumed_from: u64, expected: Option<u64>) {
+        self.tx.send_modify(|p| {
+            p.active.insert(id, EpisodeProgress { bytes: resumed_from, expected });
+        });
+    }
+
+    pub fn advance(&self, id: u32, bytes: u64) {
+        self.tx.send_modify(|p| {
+            p.bytes += bytes;
+            if let Some(e) = p.active.get_mut(&id) {
+                e.bytes += bytes;
+            }
+        });
+    }
+
+    /// Mark episode `id` as finished. Successful episodes never started had nothing to transfer
+    /// and are counted as skipped.
+    pub fn finish(&self, id: u32, ok: bool) {
+        self.tx.send_modify(|p| {
+            match (ok, p.active.remove(&id)) {
+                (true, Some(e)) => {
+                    p.episodes_done += 1;
+                    p.finished_bytes += e.expected.unwrap_or(e.bytes);
+                }
+                (true, None) => p.episodes_skipped += 1,
+                (false, _) => p.episodes_failed += 1,
+            }
+        });
+    }
+
+    /// Spawn a task logging a progress line every `interval` while something is in progress.
+    /// The task stops once every clone of this reporter has been dropped.
+    pub fn log_every(&self, interval: Duration) -> JoinHandle<()> {
+        let kind = self.kind;
+        let mut rx = self.subscribe();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                if rx.has_changed().is_err() {
+                    info!(target: PROGRESS_TARGET, "{}", rx.borrow().log_line(kind));
+                    break
+                }
+                let p = rx.borrow_and_update();
+                if !p.active.is_empty() {
+                    info!(target: PROGRESS_TARGET, "{}", p.log_line(kind));
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skipped_episodes_are_not_done() {
+        let r = ProgressReporter::new("test");
+        for _ in 0..4 {
+            r.enqueue();
+        }
+        r.finish(1, true);
+        r.start(2, 0, Some(1000));
+        r.advance(2, 1000);
+        r.finish(2, true);
+        r.start(3, 0, None);
+        r.finish(3, false);
+        let p = r.subscribe().borrow().clone();
+        assert_eq!((p.episodes_done, p.episodes_skipped, p.episodes_failed, p.pending()), (1, 1, 1, 1));
+        // the skipped episode doesn't halve the size expected of the pending one
+        assert_eq!(p.finished_bytes / p.episodes_done as u64, 1000);
+    }
+}
@@ -1,148 +1,368 @@
-use reqwest::Client;
-use std::{collections::VecDeque, fs::File, io::Write, path::PathBuf, sync::{Arc, Mutex}};
-use tokio::{sync::Notify, task::JoinHandle};
+use reqwest::{header::{CONTENT_RANGE, RANGE}, Client, StatusCode};
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, path::{Path, PathBuf}, sync::Arc};
+use tokio::{fs::{self, File, OpenOptions}, io::{AsyncReadExt, AsyncWriteExt}, sync::{watch, Mutex, Semaphore}, task::{Id, JoinSet}};
 use tokio_stream::StreamExt;
 #[allow(unused_imports)]
 use log::{info,warn,debug,error,trace};
 
-use super::{error::SpreakerError, simple_episode::SimpleEpisode};
+use crate::{progress::{DownloadProgress, ProgressReporter}, throttle::Throttle};
+use super::{error::SpreakerError, manifest::{Manifest, ManifestEntry}, simple_episode::SimpleEpisode};
 
+/// Downloads episodes concurrently, at most `jobs` at a time.
+///
+/// Every call to `download` spawns a task that waits for a free slot; `join` waits for all of
+/// them and returns the outcome of each episode. Progress can be followed through `progress`.
 pub struct SpreakerDownloader {
-    queue: Arc<Mutex<VecDeque<SimpleEpisode>>>,
-    manager: JoinHandle<Result<(), SpreakerError>>,
-    waiting: Arc<Mutex<bool>>,
-    wake: Arc<Notify>,
+    tasks: JoinSet<(u32, Result<(), SpreakerError>)>,
+    ids: HashMap<Id, u32>,
+    shared: Arc<Shared>,
 }
 
-type Worker = JoinHandle<Result<(), SpreakerError>>;
+/// State shared between the downloader and its tasks.
+struct Shared {
+    cli: Arc<Client>,
+    output: PathBuf,
+    checksum: bool,
+    manifest: Mutex<Manifest>,
+    slots: Semaphore,
+    throttle: Arc<Throttle>,
+    progress: ProgressReporter,
+    cancel: watch::Sender<bool>,
+}
+
+/// Handle used to stop a running `SpreakerDownloader` from elsewhere.
+///
+/// Cancelled downloads leave their partial file in place, so they are resumed on the next run.
+#[derive(Clone)]
+pub struct CancelHandle(watch::Sender<bool>);
+
+impl CancelHandle {
+    pub fn cancel(&self) {
+        self.0.send_replace(true);
+    }
+
+    /// Wait until the downloader is cancelled, returns at once if it already was.
+    pub async fn cancelled(&self) {
+        // the sender is in `self`, the channel can't close
+        let _ = self.0.subscribe().wait_for(|c| *c).await;
+    }
+}
 
 impl SpreakerDownloader {
-    pub fn new(cli: Arc<Client>, jobs: usize, output: PathBuf) -> Self {
-        let queue = Arc::new(Mutex::new(VecDeque::new()));
-        let workers = Arc::new(Mutex::new(vec![]));
-        let wake = Arc::new(Notify::new());
-        let waiting = Arc::new(Mutex::new(false));
-        let manager = tokio::spawn(Self::_manager(
-            cli.clone(),
-            workers.clone(),
-            queue.clone(),
-            jobs,
-            wake.clone(),
-            waiting.clone(),
-            output.clone(),
-        ));
-        Self {
-            waiting,
-            queue,
-            manager,
-            wake,
-        }
+    /// Create a new downloader writing into `output`. Finished downloads are recorded in the
+    /// output directory manifest; when `checksum` is set their sha256 is recorded as well.
+    /// Requests and received bytes go through `throttle`, which may be shared with other downloaders.
+    pub async fn new(cli: Arc<Client>, jobs: usize, output: PathBuf, checksum: bool, throttle: Arc<Throttle>) -> Result<Self, SpreakerError> {
+        let manifest = Manifest::load(&output).await?;
+        Ok(Self {
+            tasks: JoinSet::new(),
+            ids: HashMap::new(),
+            shared: Arc::new(Shared {
+                cli,
+                output,
+                checksum,
+                manifest: Mutex::new(manifest),
+                slots: Semaphore::new(jobs),
+                throttle,
+                progress: ProgressReporter::new("download"),
+                cancel: watch::Sender::new(false),
+            }),
+        })
     }
 
-    pub fn download(&self, ep: SimpleEpisode) {
-        let mut q = self.queue.lock().unwrap();
-        q.push_back(ep);
-        debug!("pushed episode to queue");
-        self.wake.notify_one();
-    }
-
-    async fn _manager(
-        cli: Arc<Client>,
-        workers: Arc<Mutex<Vec<Worker>>>,
-        queue: Arc<Mutex<VecDeque<SimpleEpisode>>>,
-        jobs: usize,
-        wake: Arc<Notify>,
-        waiting: Arc<Mutex<bool>>,
-        output: PathBuf,
-    ) -> Result<(), SpreakerError> {
-        let notify = Arc::new(Notify::new());
-        let output = Arc::new(output);
-        loop {
-            loop {
-                let nw = workers.lock().unwrap().len();
-                debug!("wokers now: {}", nw);
-                if nw >= jobs {
-                    break
-                }
+    pub fn download(&mut self, ep: SimpleEpisode) {
+        let id = ep.id;
+        debug!("enqueuing download for episode {}", id);
+        self.shared.progress.enqueue();
+        let handle = self.tasks.spawn(Self::_download(self.shared.clone(), ep));
+        self.ids.insert(handle.id(), id);
+    }
 
-                let ep = queue.lock().unwrap().pop_front();
-                match ep {
-                    Some(ep) => {
-                        info!("spawning download worker for episode {}", ep.id);
-                        let cli = cli.clone();
-                        let notify = notify.clone();
-                        workers
-                            .lock()
-                            .unwrap()
-                            .push(tokio::spawn(Self::_download(cli, ep, notify, output.clone())));
-                    }
-                    None => {
-                        wake.notified().await;
-                        if *waiting.lock().unwrap() {
-                            return Ok(())
-                        }
-                    }
-                }
-            }
-            debug!("waiting for any worker to finish");
-            notify.notified().await;
-            debug!("removing finished workers");
-            let mut lc = 0;
-            loop {
-                let wn = workers.lock().unwrap().len();
-                for j in lc..wn {
-                    let mut w = workers.lock().unwrap();
-                    if w[j].is_finished() {
-                        w.remove(j);
-                        lc = j;
-                        break
-                    }
-                }
-                if lc == wn - 1 {
-                    break
-                }
-            }
-        }
-    } 
+    pub fn progress(&self) -> watch::Receiver<DownloadProgress> {
+        self.shared.progress.subscribe()
+    }
+
+    pub fn reporter(&self) -> &ProgressReporter {
+        &self.shared.progress
+    }
+
+    pub fn cancel_handle(&self) -> CancelHandle {
+        CancelHandle(self.shared.cancel.clone())
+    }
 
-    async fn _download(cli: Arc<Client>, ep: SimpleEpisode, notify: Arc<Notify>, output: Arc<PathBuf>) -> Result<(), SpreakerError> {
+    pub fn cancel(&self) {
+        self.cancel_handle().cancel()
+    }
+
+    async fn _download(shared: Arc<Shared>, ep: SimpleEpisode) -> (u32, Result<(), SpreakerError>) {
         let ep_id = ep.id;
-        match Self::_download_inner(cli, ep, output).await {
+        let mut cancelled = shared.cancel.subscribe();
+        let r = tokio::select! {
+            r = async {
+                // unwrap safe: the semaphore is never closed
+                let _permit = shared.slots.acquire().await.unwrap();
+                Self::_download_inner(&shared, ep).await
+            } => r,
+            _ = cancelled.wait_for(|c| *c) => Err(SpreakerError::Cancelled),
+        };
+        match &r {
             Ok(_) => {
                 info!("episode {} downloaded", ep_id);
-                notify.notify_one();
-                Ok(())
+                shared.progress.finish(ep_id, true);
             }
             Err(e) => {
-                error!("error downloading episode {}: {:?}", ep_id, e);
-                notify.notify_one();
-                Err(e)
+                error!("error downloading episode {}: {}", ep_id, e);
+                shared.progress.finish(ep_id, false);
             }
         }
+        (ep_id, r)
     }
 
-    async fn _download_inner(cli: Arc<Client>, ep: SimpleEpisode, output: Arc<PathBuf>) -> Result<(), SpreakerError> {
+    async fn _download_inner(shared: &Shared, ep: SimpleEpisode) -> Result<(), SpreakerError> {
+        let Shared { cli, output, checksum, manifest, .. } = shared;
+        let checksum = *checksum;
         info!("starting downlod for episode {}", ep.id);
-        let req = cli.get(&ep.download_url).send().await.map_err(SpreakerError::RequestError)?;
-        debug!("generated request for episode {}", ep.id);
-        let output = output.join(format!("{} - {}.mp3", ep.id, ep.title));
-        if output.exists() && output.is_file() && output.metadata().unwrap().len() == req.content_length().unwrap() {
-            info!("episode {} already downloaded", ep.id);
-            return Ok(())
+        let name = format!("{} - {}.mp3", ep.id, ep.title);
+        let target = output.join(&name);
+        let part = output.join(format!("{}.part", name));
+
+        let existing = match fs::metadata(&target).await {
+            Ok(m) if m.is_file() => Some(m.len()),
+            _ => None,
+        };
+        if let Some(len) = existing {
+            if manifest.lock().await.get(ep.id).is_some_and(|m| m.size == len) {
+                info!("episode {} already downloaded", ep.id);
+                return Ok(())
+            }
         }
-        let mut file = File::create(output).map_err(SpreakerError::IOError)?;
-        let mut res = req.bytes_stream();
+
+        let mut offset = match fs::metadata(&part).await {
+            Ok(m) if m.is_file() && existing.is_none() => m.len(),
+            _ => 0,
+        };
+        let res = loop {
+            let mut req = cli.get(&ep.download_url);
+            if offset > 0 {
+                debug!("resuming episode {} from byte {}", ep.id, offset);
+                req = req.header(RANGE, format!("bytes={}-", offset));
+            }
+            shared.throttle.request().await;
+            let res = req.send().await.map_err(SpreakerError::RequestError)?;
+            debug!("generated request for episode {}", ep.id);
+            match res.status() {
+                _ if offset == 0 => break res,
+                StatusCode::PARTIAL_CONTENT => match content_range_start(&res) {
+                    Some(start) if start == offset => break res,
+                    // appending another range would leave a hole or a repeated piece in the file
+                    start => {
+                        warn!("server sent episode {} from byte {:?} instead of {}, restarting download", ep.id, start, offset);
+                        offset = 0;
+                        continue;
+                    }
+                },
+                StatusCode::RANGE_NOT_SATISFIABLE => {}
+                _ => break res,
+            }
+            match content_range_total(&res) {
+                // the server has nothing past what we already have: the partial file is complete
+                Some(total) if total == offset => {
+                    debug!("partial file for episode {} already complete", ep.id);
+                    return Self::_finalize(shared, ep.id, &name, &part, &target, offset).await;
+                }
+                // the file changed on the server, or the partial one is broken
+                total => {
+                    warn!("partial file for episode {} has {} bytes, the server has {:?}, restarting download", ep.id, offset, total);
+                    offset = 0;
+                }
+            }
+        };
+
+        let (mut file, mut hasher, mut written, expected) = match res.status() {
+            // the range was checked above, a 206 to a request without one is unexpected
+            StatusCode::PARTIAL_CONTENT if offset > 0 => {
+                let hasher = if checksum { Some(hash_file(&part).await?) } else { None };
+                let file = OpenOptions::new().append(true).open(&part).await.map_err(SpreakerError::IOError)?;
+                (file, hasher, offset, res.content_length().map(|l| l + offset))
+            }
+            StatusCode::OK => {
+                if let Some(len) = existing {
+                    // no manifest entry, trust the file if its size matches what the server reports
+                    if res.content_length().is_none_or(|l| l == len) {
+                        info!("episode {} already downloaded, recording it in the manifest", ep.id);
+                        let sha256 = if checksum { Some(format!("{:x}", hash_file(&target).await?.finalize())) } else { None };
+                        return manifest.lock().await.record(ep.id, ManifestEntry { file: name, size: len, sha256 }).await;
+                    }
+                }
+                if offset > 0 {
+                    warn!("server ignored range request for episode {}, restarting download", ep.id);
+                }
+                let file = File::create(&part).await.map_err(SpreakerError::IOError)?;
+                (file, checksum.then(Sha256::new), 0, res.content_length())
+            }
+            s => return Err(SpreakerError::Status(s)),
+        };
+
+        shared.progress.start(ep.id, written, expected);
+        let mut res = res.bytes_stream();
         while let Some(v) = res.next().await {
             let v = v.map_err(SpreakerError::RequestError)?;
             trace!("writing chunk {}", v.len());
-            file.write(&v).map_err(SpreakerError::IOError)?;
+            file.write_all(&v).await.map_err(SpreakerError::IOError)?;
+            if let Some(h) = hasher.as_mut() {
+                h.update(&v);
+            }
+            written += v.len() as u64;
+            shared.progress.advance(ep.id, v.len() as u64);
+            shared.throttle.consume(v.len()).await;
         }
+        file.flush().await.map_err(SpreakerError::IOError)?;
+        drop(file);
+
+        if let Some(expected) = expected {
+            if expected != written {
+                return Err(SpreakerError::Incomplete { expected, got: written });
+            }
+        }
+        let sha256 = hasher.map(|h| format!("{:x}", h.finalize()));
+        fs::rename(&part, &target).await.map_err(SpreakerError::IOError)?;
+        manifest.lock().await.record(ep.id, ManifestEntry { file: name, size: written, sha256 }).await?;
         debug!("worker for episode {} finished", ep.id);
         Ok(())
     }
-    
-    pub async fn join(self) -> Result<(), SpreakerError> {
-        *self.waiting.lock().unwrap() = true;
-        self.manager.await.map_err(SpreakerError::Runtime)?
+
+    async fn _finalize(shared: &Shared, id: u32, name: &str, part: &Path, target: &Path, size: u64) -> Result<(), SpreakerError> {
+        let sha256 = if shared.checksum { Some(format!("{:x}", hash_file(part).await?.finalize())) } else { None };
+        fs::rename(part, target).await.map_err(SpreakerError::IOError)?;
+        shared.manifest.lock().await.record(id, ManifestEntry { file: name.to_owned(), size, sha256 }).await
+    }
+
+    /// Wait for every enqueued download to finish and return the outcome of each one, in
+    /// completion order.
+    pub async fn join(mut self) -> Vec<(u32, Result<(), SpreakerError>)> {
+        let mut results = Vec::with_capacity(self.tasks.len());
+        while let Some(r) = self.tasks.join_next_with_id().await {
+            match r {
+                Ok((_, r)) => results.push(r),
+                Err(e) => {
+                    // unwrap safe: every spawned task has its id recorded in `download`
+                    let id = *self.ids.get(&e.id()).unwrap();
+                    results.push((id, Err(SpreakerError::Runtime(e))));
+                }
+            }
+        }
+        results
+    }
+}
+
+/// Parse the first byte position out of a `Content-Range: bytes start-end/total` header.
+fn content_range_start(res: &reqwest::Response) -> Option<u64> {
+    res.headers()
+        .get(CONTENT_RANGE)?
+        .to_str()
+        .ok()?
+        .strip_prefix("bytes ")?
+        .split('-')
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Parse the total size out of a `Content-Range: bytes */total` header, sent with a 416.
+fn content_range_total(res: &reqwest::Response) -> Option<u64> {
+    res.headers()
+        .get(CONTENT_RANGE)?
+        .to_str()
+        .ok()?
+        .strip_prefix("bytes */")?
+        .trim()
+        .parse()
+        .ok()
+}
+
+async fn hash_file(path: &Path) -> Result<Sha256, SpreakerError> {
+    let mut f = File::open(path).await.map_err(SpreakerError::IOError)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = f.read(&mut buf).await.map_err(SpreakerError::IOError)?;
+        if n == 0 {
+            break Ok(hasher)
+        }
+        hasher.update(&buf[..n]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{io::AsyncBufReadExt, net::TcpListener};
+
+    use super::*;
+
+    /// Serve `responses` in order, one per connection, returning the address and the `Range`
+    /// header of each request.
+    async fn serve(responses: Vec<(&'static str, &'static str, &'static str)>) -> (String, tokio::task::JoinHandle<Vec<Option<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/episode.mp3", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let mut ranges = vec![];
+            for (status, headers, body) in responses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let (read, mut write) = socket.split();
+                let mut lines = tokio::io::BufReader::new(read).lines();
+                let mut range = None;
+                while let Some(l) = lines.next_line().await.unwrap() {
+                    if l.is_empty() {
+                        break;
+                    }
+                    if let Some(r) = l.to_lowercase().strip_prefix("range: ") {
+                        range = Some(r.to_owned());
+                    }
+                }
+                ranges.push(range);
+                let response = format!("HTTP/1.1 {}\r\ncontent-length: {}\r\nconnection: close\r\n{}\r\n{}", status, body.len(), headers, body);
+                write.write_all(response.as_bytes()).await.unwrap();
+            }
+            ranges
+        });
+        (url, handle)
+    }
+
+    async fn download(dir: &Path, url: String) -> Result<(), SpreakerError> {
+        let mut d = SpreakerDownloader::new(Arc::new(Client::new()), 1, dir.to_owned(), true, Arc::new(Throttle::unlimited())).await?;
+        d.download(SimpleEpisode { id: 1, download_url: url, title: "Test".to_owned(), remaining: serde_json::Value::Null });
+        d.join().await.pop().unwrap().1
+    }
+
+    #[tokio::test]
+    async fn wrong_range_restarts_download() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("1 - Test.mp3.part"), "hello").unwrap();
+        let (url, server) = serve(vec![
+            ("206 Partial Content", "content-range: bytes 0-9/10\r\n", "helloworld"),
+            ("200 OK", "", "HELLOWORLD"),
+        ]).await;
+        download(dir.path(), url).await.unwrap();
+        assert_eq!(server.await.unwrap(), vec![Some("bytes=5-".to_owned()), None]);
+        assert_eq!(std::fs::read_to_string(dir.path().join("1 - Test.mp3")).unwrap(), "HELLOWORLD");
+        let manifest = Manifest::load(dir.path()).await.unwrap();
+        assert_eq!(manifest.get(1).unwrap().size, 10);
+    }
+
+    #[tokio::test]
+    async fn partial_content_needs_a_range() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("1 - Test.mp3.part"), "hello").unwrap();
+        let (url, server) = serve(vec![
+            ("206 Partial Content", "", "world"),
+            ("206 Partial Content", "content-range: bytes 5-9/10\r\n", "world"),
+        ]).await;
+        assert!(matches!(download(dir.path(), url).await, Err(SpreakerError::Status(StatusCode::PARTIAL_CONTENT))));
+        server.await.unwrap();
+        // nothing is finished, and the partial file is only replaced by a full download
+        assert!(!dir.path().join("1 - Test.mp3").exists());
+        assert_eq!(std::fs::read_to_string(dir.path().join("1 - Test.mp3.part")).unwrap(), "hello");
+        assert!(Manifest::load(dir.path()).await.unwrap().get(1).is_none());
     }
 }
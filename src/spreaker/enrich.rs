@@ -0,0 +1,316 @@
+//! Structured fields of an episode parsed from its title and description, see `Episode::enrich`.
+use std::time::Duration;
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::transcript::FromTo;
+use super::{Chapter, Episode};
+
+lazy_static! {
+    /// The number after the show name, after a `#`, or at the start of the title followed by a dash.
+    static ref TITLE_NUMBER: Regex = Regex::new(r"(?i)^\s*(?:ppp|power\s*pizza|puntata|episodio|ep\.?)\s*#?\s*(\d{1,4})\b|#\s*(\d{1,4})\b|^\s*(\d{1,4})\s*[-–]\s").unwrap();
+    /// Special series with their canonical names, the first matching the title wins: PGdR™
+    /// episodes are also titled as specials.
+    static ref SERIES: Vec<(Regex, &'static str)> = vec![
+        (Regex::new(r"(?i)\bpgdr").unwrap(), "PGdR"),
+        (Regex::new(r"(?i)\bspecial[ei]\b").unwrap(), "Speciale"),
+    ];
+    /// `Ospite: Mario Rossi`, `con ospiti Mario e Luigi`, `con la partecipazione di Mario`...
+    static ref GUESTS: Regex = Regex::new(r"(?i)(?:\bospit[ei](?:\s+(?:speciali?|d'onore|della puntata|di oggi))?|\bpartecipazione di|\bfeat\.?)\s*:?\s+(?:(?:è|sono)\s+)?(?:(?:il|la|lo|i|gli|le)\s+)?([^.;!?\n(]+)").unwrap();
+    static ref GUEST_SEPARATOR: Regex = Regex::new(r"\s*(?:,|\s+e\s+|&)\s*").unwrap();
+    static ref HREF: Regex = Regex::new(r#"href\s*=\s*"([^"]+)""#).unwrap();
+    /// Tags ending a line of the HTML description.
+    static ref LINE_BREAK: Regex = Regex::new(r"(?i)<br\s*/?>|</(?:p|li|div|h\d)>").unwrap();
+    static ref TAG: Regex = Regex::new(r"<[^>]*>").unwrap();
+    /// `12:34 Topic`, `[1:02:03] - Topic`...
+    static ref CHAPTER_TIME_FIRST: Regex = Regex::new(r"^[\[(]?(\d{1,2}(?::\d{2}){1,2})[\])]?\s*[-–—:.)]?\s+(.+)$").unwrap();
+    /// `Topic 12:34`, `Topic - (12:34)`...
+    static ref CHAPTER_TIME_LAST: Regex = Regex::new(r"^(.+?)\s*[-–—:]?\s*[\[(]?(\d{1,2}(?::\d{2}){1,2})[\])]?$").unwrap();
+    /// Words before the time of day in prose, `ci vediamo alle 21:30`, `in diretta dalle 18:00`...
+    static ref CLOCK_TIME: Regex = Regex::new(r"(?i)\b(?:alle|all'|dalle|dall'|ore|le|verso|entro|fino a)$").unwrap();
+    /// `- Topic`, `• Topic`, `1. Topic`...
+    static ref CHAPTER_ITEM: Regex = Regex::new(r"^(?:[-–—•*·▪►]|\d{1,2}[.)])\s*(.+)$").unwrap();
+}
+
+/// Version of the parsers below: bump it when they change, so that the episodes enriched by an
+/// older one are parsed again at the next import.
+pub const ENRICH_VERSION: u32 = 2;
+
+/// Longest text taken as a guest name, longer ones are more likely a sentence.
+const MAX_GUEST_LENGTH: usize = 40;
+
+impl Episode {
+    /// Fill the fields parsed from title and description.
+    pub fn enrich(&mut self) {
+        self.number = Self::parse_number(&self.title);
+        self.series = Self::parse_series(&self.title);
+        self.guests = Self::parse_guests(&self.description);
+        self.links = Self::parse_links(&self.description_html);
+        self.chapters = Self::parse_chapters(&self.description, &self.description_html);
+        self.enriched = ENRICH_VERSION;
+    }
+
+    /// Number of the episode titled `title`. Specials have none: numbers in their titles are
+    /// numbers of their own series, e.g. the PGdR™ sessions.
+    pub fn parse_number(title: &str) -> Option<u32> {
+        if Self::is_special_title(title) {
+            return None;
+        }
+        TITLE_NUMBER
+            .captures(title)
+            .and_then(|c| c.get(1).or(c.get(2)).or(c.get(3)))
+            .and_then(|m| m.as_str().parse().ok())
+    }
+
+    /// Canonical name of the special series in `title`, if any.
+    pub fn parse_series(title: &str) -> Option<String> {
+        SERIES.iter().find(|(r, _)| r.is_match(title)).map(|(_, s)| s.to_string())
+    }
+
+    /// Whether `title` is of a special episode, outside the numbered series.
+    pub fn is_special_title(title: &str) -> bool {
+        Self::parse_series(title).is_some()
+    }
+
+    pub fn is_special(&self) -> bool {
+        self.series.is_some()
+    }
+
+    /// Names following `ospite` and the like in `description`. Only capitalized names or
+    /// `@handles` are kept, to skip sentences like "ospite a sorpresa".
+    pub fn parse_guests(description: &str) -> Vec<String> {
+        let mut guests: Vec<String> = vec![];
+        for c in GUESTS.captures_iter(description) {
+            for name in GUEST_SEPARATOR.split(c[1].trim()) {
+                let handle = name.trim().strip_prefix('@');
+                let name = handle.unwrap_or(name.trim());
+                let capitalized = name.chars().next().is_some_and(|c| c.is_uppercase() || c.is_ascii_digit());
+                if (capitalized || handle.is_some_and(|h| !h.is_empty())) && name.chars().count() <= MAX_GUEST_LENGTH && !guests.iter().any(|g| g.eq_ignore_ascii_case(name)) {
+                    guests.push(name.to_owned());
+                }
+            }
+        }
+        guests
+    }
+
+    /// Targets of the links in `html`, without duplicates.
+    pub fn parse_links(html: &str) -> Vec<String> {
+        let mut links: Vec<String> = vec![];
+        for c in HREF.captures_iter(html) {
+            let link = c[1].replace("&amp;", "&");
+            if !links.contains(&link) {
+                links.push(link);
+            }
+        }
+        links
+    }
+
+    /// Topics listed in the description, one per line, either with a `h:mm:ss` or `mm:ss` time
+    /// before or after them or as items of a bulleted or numbered list. A time after words like
+    /// `alle` is a time of day, the line is prose. Lines are taken from the
+    /// HTML description when there is one, the plain one often loses line breaks. A single item
+    /// is more likely a line that happens to start with a dash than a list, so it isn't one.
+    pub fn parse_chapters(description: &str, html: &str) -> Vec<Chapter> {
+        let text = match html.trim().is_empty() {
+            true => description.to_owned(),
+            false => html_to_text(html),
+        };
+        let chapters = text
+            .lines()
+            .map(str::trim)
+            .filter_map(|l| {
+                if let Some(c) = CHAPTER_TIME_FIRST.captures(l) {
+                    parse_time(&c[1]).map(|t| Chapter { title: c[2].trim().to_owned(), start: Some(t) })
+                } else if let Some(c) = CHAPTER_TIME_LAST.captures(l).filter(|c| !CLOCK_TIME.is_match(c[1].trim())) {
+                    parse_time(&c[2]).map(|t| Chapter { title: c[1].trim().to_owned(), start: Some(t) })
+                } else {
+                    CHAPTER_ITEM.captures(l).map(|c| Chapter { title: c[1].trim().to_owned(), start: None })
+                }
+            })
+            .filter(|c| !c.title.is_empty())
+            .collect::<Vec<_>>();
+        if chapters.len() < 2 {
+            return vec![];
+        }
+        chapters
+    }
+
+    /// Time range of chapter `i`: from its start to the start of the next timed chapter, or to
+    /// the end of the episode. `None` if the chapter has no start.
+    pub fn chapter_time(&self, i: usize) -> Option<FromTo> {
+        let from = self.chapters.get(i)?.start?;
+        let to = self.chapters[i + 1..]
+            .iter()
+            .find_map(|c| c.start)
+            .unwrap_or(Duration::from_millis(self.duration as u64));
+        Some(FromTo { from, to: to.max(from) })
+    }
+}
+
+/// Text of `html`, with a line per paragraph, list item or line break.
+fn html_to_text(html: &str) -> String {
+    let text = LINE_BREAK.replace_all(html, "\n");
+    let text = TAG.replace_all(&text, "");
+    text.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Parse `mm:ss` or `h:mm:ss`.
+fn parse_time(s: &str) -> Option<Duration> {
+    s.split(':')
+        .try_fold(0u64, |secs, p| secs.checked_mul(60)?.checked_add(p.parse().ok()?))
+        .map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn title_numbers() {
+        for (title, number) in [
+            ("PPP 123 - Perché Hollow Knight è così difficile?", Some(123)),
+            ("Power Pizza #45: il ritorno", Some(45)),
+            ("Puntata 7 - Buone feste", Some(7)),
+            ("Ep. 300 – Trecento", Some(300)),
+            ("Il meglio del 2023 #250", Some(250)),
+            ("321 - Metroid Dread", Some(321)),
+            ("PPP1000 - Mille", Some(1000)),
+            ("I 10 giochi dell'anno", None),
+            ("Speciale: PGdR™ - La Campagna, Sessione 3", None),
+            ("PGdR #12 - Il drago", None),
+            ("Speciale 100 puntate", None),
+        ] {
+            assert_eq!(Episode::parse_number(title), number, "{}", title);
+        }
+    }
+
+    #[test]
+    fn title_series() {
+        assert_eq!(Episode::parse_series("Speciale: PGdR™ - La Campagna, Sessione 3").as_deref(), Some("PGdR"));
+        assert_eq!(Episode::parse_series("Speciale E3 2021").as_deref(), Some("Speciale"));
+        assert_eq!(Episode::parse_series("Gli speciali di Natale").as_deref(), Some("Speciale"));
+        assert_eq!(Episode::parse_series("PPP 123 - Specialità della casa"), None);
+        assert!(!Episode::is_special_title("PPP 123 - Perché Hollow Knight è così difficile?"));
+    }
+
+    #[test]
+    fn guests() {
+        let long = format!("Ospite: {}", "A".repeat(MAX_GUEST_LENGTH + 1));
+        for (description, guests) in [
+            ("Ospite: Mario Rossi. Buon ascolto!", vec!["Mario Rossi"]),
+            ("Oggi con ospiti Mario e Luigi", vec!["Mario", "Luigi"]),
+            ("Ospiti speciali: Anna, Bea & Carla", vec!["Anna", "Bea", "Carla"]),
+            ("Ospite della puntata è il Dottor Male", vec!["Dottor Male"]),
+            ("con la partecipazione di @dario_moccia", vec!["dario_moccia"]),
+            ("feat. Sio e @", vec!["Sio"]),
+            ("Ospite: Mario\nOspite: MARIO", vec!["Mario"]),
+            ("un ospite a sorpresa", vec![]),
+            ("Ospiti: mario e luigi", vec![]),
+            ("Nessun ospite.", vec![]),
+            (long.as_str(), vec![]),
+        ] {
+            assert_eq!(Episode::parse_guests(description), guests, "{}", description);
+        }
+    }
+
+    #[test]
+    fn links() {
+        for (html, links) in [
+            (r#"<a href="https://example.com/?a=1&amp;b=2">x</a>"#, vec!["https://example.com/?a=1&b=2"]),
+            (r#"<a href = "https://a.it">a</a> <a href="https://b.it">b</a> <a href="https://a.it">a</a>"#, vec!["https://a.it", "https://b.it"]),
+            ("https://not.a.link", vec![]),
+            ("", vec![]),
+        ] {
+            assert_eq!(Episode::parse_links(html), links, "{}", html);
+        }
+    }
+
+    fn chapter(title: &str, start: Option<u64>) -> Chapter {
+        Chapter { title: title.to_owned(), start: start.map(Duration::from_secs) }
+    }
+
+    #[test]
+    fn chapters_from_html() {
+        let html = "<p>Gli argomenti:</p><ul><li>00:00 Intro</li><li>[05:30] - Hollow Knight</li><li>1:02:03 Saluti &amp; baci</li></ul>\
+            <p>Ospite: Mario<br>ci vediamo alle 21:30<br/>Seguiteci!</p>";
+        assert_eq!(Episode::parse_chapters("plain text loses lines", html), vec![
+            chapter("Intro", Some(0)),
+            chapter("Hollow Knight", Some(330)),
+            chapter("Saluti & baci", Some(3723)),
+        ]);
+    }
+
+    #[test]
+    fn chapters_with_the_time_last() {
+        let description = "Intro 0:00\nMetroid Dread - (12:34)\nGiochi dell'anno: [1:00:00]\nIn diretta dalle 18:00";
+        assert_eq!(Episode::parse_chapters(description, ""), vec![
+            chapter("Intro", Some(0)),
+            chapter("Metroid Dread", Some(754)),
+            chapter("Giochi dell'anno", Some(3600)),
+        ]);
+    }
+
+    #[test]
+    fn chapters_as_list_items() {
+        let description = "Di cosa parliamo:\n- Zelda\n• Mario\n1. Sonic\n2) Pac-Man";
+        assert_eq!(Episode::parse_chapters(description, ""), vec![
+            chapter("Zelda", None),
+            chapter("Mario", None),
+            chapter("Sonic", None),
+            chapter("Pac-Man", None),
+        ]);
+    }
+
+    #[test]
+    fn a_single_item_is_no_list() {
+        assert!(Episode::parse_chapters("Una puntata speciale\n- a cura di Sio", "").is_empty());
+        assert!(Episode::parse_chapters("05:00 Unico argomento", "").is_empty());
+        assert!(Episode::parse_chapters("ci vediamo alle 21:30\nin diretta dalle 18:00", "").is_empty());
+        assert!(Episode::parse_chapters("", "").is_empty());
+    }
+
+    #[test]
+    fn times() {
+        assert_eq!(parse_time("0:00"), Some(Duration::ZERO));
+        assert_eq!(parse_time("12:34"), Some(Duration::from_secs(754)));
+        assert_eq!(parse_time("1:02:03"), Some(Duration::from_secs(3723)));
+        assert_eq!(parse_time("1:xx"), None);
+        assert_eq!(parse_time(""), None);
+    }
+
+    #[test]
+    fn chapter_times() {
+        let mut e = Episode::from(crate::spreaker::ProtoEpisode {
+            episode_id: 1,
+            title: "PPP 1".to_owned(),
+            duration: 3_600_000,
+            show_id: 0,
+            author_id: 0,
+            published_at: "2022-01-01 00:00:00".to_owned(),
+            download_url: String::new(),
+            description: String::new(),
+            description_html: String::new(),
+        });
+        e.chapters = vec![
+            chapter("Intro", Some(0)),
+            chapter("Senza tempo", None),
+            chapter("Zelda", Some(600)),
+            // past the end of the episode
+            chapter("Saluti", Some(4000)),
+        ];
+        let time = |e: &Episode, i| e.chapter_time(i).map(|t| (t.from.as_secs(), t.to.as_secs()));
+        // untimed chapters are skipped to find the end
+        assert_eq!(time(&e, 0), Some((0, 600)));
+        assert_eq!(time(&e, 1), None);
+        assert_eq!(time(&e, 2), Some((600, 4000)));
+        assert_eq!(time(&e, 3), Some((4000, 4000)));
+        assert_eq!(time(&e, 4), None);
+        e.chapters.pop();
+        assert_eq!(time(&e, 2), Some((600, 3600)));
+    }
+}
@@ -1,11 +1,13 @@
+use std::time::Duration;
 use chrono::{DateTime, NaiveDateTime, Utc};
 use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DurationMilliSeconds};
 
 use crate::db::PPPData;
 
 use super::SimpleEpisode;
 
-#[derive(Deserialize, Debug, Serialize)]
+#[derive(Deserialize, Debug, Serialize, Clone)]
 pub struct Episode {
     #[serde(alias = "episode_id")]
     pub id: u32,
@@ -18,6 +20,35 @@ pub struct Episode {
     pub download_url: String,
     pub description: String,
     pub description_html: String,
+    /// Number of the episode, parsed from the title at import, see `Episode::parse_number`.
+    #[serde(default)]
+    pub number: Option<u32>,
+    /// Special series the episode is part of, see `Episode::parse_series`.
+    #[serde(default)]
+    pub series: Option<String>,
+    /// Guests named in the description, see `Episode::parse_guests`.
+    #[serde(default)]
+    pub guests: Vec<String>,
+    /// Links in the HTML description.
+    #[serde(default)]
+    pub links: Vec<String>,
+    /// Topics listed in the description, see `Episode::parse_chapters`.
+    #[serde(default)]
+    pub chapters: Vec<Chapter>,
+    /// Version of the parsers that filled the fields above, see `ENRICH_VERSION`.
+    #[serde(default)]
+    pub enriched: u32,
+}
+
+/// A topic of the "scontrino", the list of what is talked about in the description.
+#[serde_as]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Chapter {
+    pub title: String,
+    /// When the topic starts, if the description tells.
+    #[serde_as(as = "Option<DurationMilliSeconds<u64>>")]
+    #[serde(default)]
+    pub start: Option<Duration>,
 }
 
 #[derive(Deserialize)]
@@ -36,8 +67,14 @@ pub struct ProtoEpisode {
 impl From<ProtoEpisode> for Episode {
     fn from(p: ProtoEpisode) -> Self {
         let date = NaiveDateTime::parse_from_str(p.published_at.as_str(), "%Y-%m-%d %H:%M:%S").unwrap();
-        Self {
+        let mut e = Self {
             id: p.episode_id,
+            number: None,
+            series: None,
+            guests: vec![],
+            links: vec![],
+            chapters: vec![],
+            enriched: 0,
             title: p.title,
             duration: p.duration,
             show_id: p.show_id,
@@ -46,7 +83,9 @@ impl From<ProtoEpisode> for Episode {
             download_url: p.download_url,
             description: p.description,
             description_html: p.description_html,
-        }
+        };
+        e.enrich();
+        e
     }
 }
 
@@ -6,6 +6,10 @@ pub enum SpreakerError {
     JsonError(reqwest::Error),
     Runtime(tokio::task::JoinError),
     IOError(std::io::Error),
+    Manifest(serde_json::Error),
+    Status(reqwest::StatusCode),
+    Incomplete { expected: u64, got: u64 },
+    Cancelled,
 }
 
 impl From<reqwest::Error> for SpreakerError {
@@ -21,6 +25,10 @@ impl Display for SpreakerError {
             SpreakerError::JsonError(e) => write!(f, "Json error: {}", e),
             SpreakerError::Runtime(e) => write!(f, "Runtime error: {}", e),
             SpreakerError::IOError(e) => write!(f, "IO error: {}", e),
+            SpreakerError::Manifest(e) => write!(f, "Manifest error: {}", e),
+            SpreakerError::Status(s) => write!(f, "Unexpected status: {}", s),
+            SpreakerError::Incomplete { expected, got } => write!(f, "Incomplete download: expected {} bytes, got {}", expected, got),
+            SpreakerError::Cancelled => write!(f, "Download cancelled"),
         }
     }
 }
@@ -0,0 +1,57 @@
+use std::{collections::HashMap, path::{Path, PathBuf}};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+#[allow(unused_imports)]
+use log::{info,warn,debug,error,trace};
+
+use super::error::SpreakerError;
+
+/// Record of the episodes already downloaded in an output directory, with their size and
+/// (optionally) their sha256 checksum.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Manifest {
+    #[serde(skip)]
+    path: PathBuf,
+    pub episodes: HashMap<u32, ManifestEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ManifestEntry {
+    pub file: String,
+    pub size: u64,
+    pub sha256: Option<String>,
+}
+
+impl Manifest {
+    pub const FILE_NAME: &'static str = "manifest.json";
+
+    /// Load the manifest stored in `dir`, or start an empty one if there is none.
+    pub async fn load(dir: &Path) -> Result<Self, SpreakerError> {
+        let path = dir.join(Self::FILE_NAME);
+        let mut manifest = match fs::read_to_string(&path).await {
+            Ok(s) => serde_json::from_str::<Manifest>(&s).map_err(SpreakerError::Manifest)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                debug!("no manifest found in {:?}, starting a new one", dir);
+                Manifest::default()
+            }
+            Err(e) => return Err(SpreakerError::IOError(e)),
+        };
+        manifest.path = path;
+        Ok(manifest)
+    }
+
+    pub fn get(&self, id: u32) -> Option<&ManifestEntry> {
+        self.episodes.get(&id)
+    }
+
+    /// Record an entry and persist the manifest. The file is written to a temporary path first
+    /// and then renamed, so an interrupted write never leaves a truncated manifest behind.
+    pub async fn record(&mut self, id: u32, entry: ManifestEntry) -> Result<(), SpreakerError> {
+        self.episodes.insert(id, entry);
+        let tmp = self.path.with_extension("json.tmp");
+        let content = serde_json::to_vec_pretty(self).map_err(SpreakerError::Manifest)?;
+        fs::write(&tmp, content).await.map_err(SpreakerError::IOError)?;
+        fs::rename(&tmp, &self.path).await.map_err(SpreakerError::IOError)?;
+        Ok(())
+    }
+}
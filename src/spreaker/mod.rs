@@ -1,13 +1,17 @@
 mod error;
 mod downloader;
 mod episode;
+mod enrich;
 mod simple_episode;
 mod paginator;
+mod manifest;
 
 pub use error::SpreakerError;
-pub use downloader::SpreakerDownloader;
-pub use episode::{ProtoEpisode, Episode};
+pub use downloader::{CancelHandle, SpreakerDownloader};
+pub use episode::{Chapter, ProtoEpisode, Episode};
+pub use enrich::ENRICH_VERSION;
 pub use simple_episode::SimpleEpisode;
+pub use manifest::{Manifest, ManifestEntry};
 
 use std::sync::Arc;
 use paginator::SpreakerDataIter;
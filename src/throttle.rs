@@ -0,0 +1,62 @@
+use std::time::Duration;
+use tokio::{sync::Mutex, time::{sleep_until, Instant}};
+
+use crate::config::DownloadConfig;
+
+/// Global limits on how hard we hit Spreaker's CDN, shared by every download task.
+///
+/// Both limits work on a virtual clock: each request (or each received chunk) books the next
+/// free slot and waits until it comes.
+#[derive(Debug)]
+pub struct Throttle {
+    bandwidth: Option<u64>,
+    request_interval: Option<Duration>,
+    next_request: Mutex<Instant>,
+    next_bytes: Mutex<Instant>,
+}
+
+impl Throttle {
+    pub fn new(bandwidth: Option<u64>, requests_per_second: Option<f64>) -> Self {
+        Self {
+            bandwidth: bandwidth.filter(|b| *b > 0),
+            request_interval: requests_per_second
+                .filter(|r| *r > 0.0)
+                .map(|r| Duration::from_secs_f64(1.0 / r)),
+            next_request: Mutex::new(Instant::now()),
+            next_bytes: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Self::new(None, None)
+    }
+
+    pub fn from_config(config: &DownloadConfig) -> Self {
+        Self::new(config.bandwidth_limit, config.requests_per_second)
+    }
+
+    /// Wait until a new request may be sent.
+    pub async fn request(&self) {
+        if let Some(interval) = self.request_interval {
+            let at = Self::book(&self.next_request, interval).await;
+            sleep_until(at).await;
+        }
+    }
+
+    /// Account for `bytes` just received, waiting as long as needed to stay under the bandwidth
+    /// limit.
+    pub async fn consume(&self, bytes: usize) {
+        if let Some(bw) = self.bandwidth {
+            let cost = Duration::from_secs_f64(bytes as f64 / bw as f64);
+            let at = Self::book(&self.next_bytes, cost).await;
+            sleep_until(at + cost).await;
+        }
+    }
+
+    async fn book(next: &Mutex<Instant>, cost: Duration) -> Instant {
+        let mut next = next.lock().await;
+        let at = (*next).max(Instant::now());
+        *next = at + cost;
+        at
+    }
+}
@@ -1,11 +1,27 @@
-use std::{collections::HashSet, fs::{read_dir, read_to_string}, sync::Arc};
+use std::{collections::HashSet, fs::{create_dir_all, read_dir, read_to_string}, path::PathBuf, sync::Arc};
 use log::{debug, error, info, warn};
-use power_pizza_bot::{config::CONFIG, db::DB, import::import_database, spreaker::Episode, transcript::{EpisodeTranscript, JobManager, Transcript}};
+use power_pizza_bot::{config::CONFIG, db::DB, import::import_database, spreaker::Episode, transcript::{missing_mentions, missing_quality, missing_summaries, retranscription_queue, update_mentions, update_quality, update_summaries, EpisodeEmbeddings, EpisodeTranscript, ExportFormat, JobManager, MentionDictionary, Summarizer, Transcript}};
+
+static USAGE: &str = "usage: ppp_import [export <episode id|all> [srt|vtt|md|txt|json|all] [output dir] | reindex | mentions | summarize [episode id|all] | quality [episode id]]";
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     pretty_env_logger::init();
 
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        None => {}
+        Some("export") => return export(&args[1..]).await,
+        Some("reindex") => return reindex().await,
+        Some("mentions") => return mentions().await,
+        Some("summarize") => return summarize(&args[1..]).await,
+        Some("quality") => return quality(&args[1..]).await,
+        Some(_) => {
+            eprintln!("{}", USAGE);
+            return Ok(());
+        }
+    }
+
     info!("check for missing directories");
     if !CONFIG.import.check_dirs() {
         error!("missing directories");
@@ -73,6 +89,25 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     }
 
+    // poor transcripts are made again, the old ones stay unless a new one scores better
+    update_quality(&missing_quality().await?).await?;
+    let to_retranscribe = match CONFIG.import.min_transcript_quality {
+        Some(_) => retranscription_queue().await?,
+        None => vec![],
+    };
+    for e in to_retranscribe.iter() {
+        match audio_files.contains(e) {
+            true => {
+                info!("poor transcript for {}: add to transcript list", e);
+                to_transcribe.push(*e);
+            }
+            false => {
+                info!("poor transcript and audio file missing for {}: add to download list", e);
+                to_download.push(*e);
+            }
+        }
+    }
+
     for e in to_download {
         converter.run_download(e);
     }
@@ -82,8 +117,156 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     for e in to_transcribe {
         converter.run_transcribe(e);
     }
+    if CONFIG.import.embedder.is_some() {
+        let embedded: HashSet<u32> = DB.get_ids::<EpisodeEmbeddings>().await?.into_iter().collect();
+        for e in transcripts.difference(&embedded) {
+            info!("embeddings missing for {}: add to embed list", e);
+            converter.run_embed(*e);
+        }
+    }
 
     converter.wait().await?;
 
+    if let Some(dictionary) = MentionDictionary::load().await? {
+        let mut ids = missing_mentions().await?;
+        ids.extend(to_retranscribe.iter().filter(|e| !ids.contains(e)).collect::<Vec<_>>());
+        update_mentions(&dictionary, &ids).await?;
+    }
+    if let Some(config) = &CONFIG.import.summarizer {
+        let summarizer = Summarizer::from_config(config, reqwest::Client::clone(&cli));
+        let mut ids = missing_summaries().await?;
+        ids.extend(to_retranscribe.iter().filter(|e| !ids.contains(e)).collect::<Vec<_>>());
+        update_summaries(&summarizer, &ids).await?;
+    }
+
+    Ok(())
+}
+
+/// Write stored transcripts to disk as `{output dir}/{episode id}.{extension}`.
+async fn export(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let ids = match args.first().map(String::as_str) {
+        Some("all") => DB.get_ids::<EpisodeTranscript>().await?,
+        Some(id) => match id.parse::<u32>() {
+            Ok(id) => vec![id],
+            Err(_) => {
+                eprintln!("{}", USAGE);
+                return Ok(());
+            }
+        },
+        None => {
+            eprintln!("{}", USAGE);
+            return Ok(());
+        }
+    };
+    let formats = match args.get(1).map(String::as_str) {
+        None => vec![ExportFormat::Srt],
+        Some("all") => ExportFormat::ALL.to_vec(),
+        Some(f) => match f.parse::<ExportFormat>() {
+            Ok(f) => vec![f],
+            Err(_) => {
+                eprintln!("{}", USAGE);
+                return Ok(());
+            }
+        },
+    };
+    let output = PathBuf::from(args.get(2).map(String::as_str).unwrap_or("export"));
+    create_dir_all(&output)?;
+
+    for id in ids {
+        let transcript = match DB.get::<EpisodeTranscript>(id).await? {
+            Some(t) => t,
+            None => {
+                warn!("no transcript for episode {}, skipping", id);
+                continue;
+            }
+        };
+        let episode = DB.get::<Episode>(id).await?;
+        for f in formats.iter() {
+            let path = output.join(format!("{}.{}", id, f.extension()));
+            info!("exporting episode {} to {:?}", id, path);
+            std::fs::write(path, f.render(&transcript, episode.as_ref()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Look for mentions in every transcript again, after the dictionary changed.
+async fn mentions() -> Result<(), Box<dyn std::error::Error>> {
+    match MentionDictionary::load().await? {
+        Some(dictionary) => update_mentions(&dictionary, &DB.get_ids::<EpisodeTranscript>().await?).await?,
+        None => error!("mentions_file is not set in the config"),
+    }
+    Ok(())
+}
+
+/// Summarize the transcripts never summarized, or again the given one or all of them, after the
+/// summarizer changed.
+async fn summarize(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let config = match &CONFIG.import.summarizer {
+        Some(c) => c,
+        None => {
+            error!("summarizer is not set in the config");
+            return Ok(());
+        }
+    };
+    let ids = match args.first().map(String::as_str) {
+        None => missing_summaries().await?,
+        Some("all") => DB.get_ids::<EpisodeTranscript>().await?,
+        Some(id) => match id.parse::<u32>() {
+            Ok(id) => vec![id],
+            Err(_) => {
+                eprintln!("{}", USAGE);
+                return Ok(());
+            }
+        },
+    };
+    update_summaries(&Summarizer::from_config(config, reqwest::Client::new()), &ids).await?;
+    Ok(())
+}
+
+/// Score the given transcript or all of them again, after the analysis changed.
+async fn quality(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let ids = match args.first().map(String::as_str) {
+        None => DB.get_ids::<EpisodeTranscript>().await?,
+        Some(id) => match id.parse::<u32>() {
+            Ok(id) => vec![id],
+            Err(_) => {
+                eprintln!("{}", USAGE);
+                return Ok(());
+            }
+        },
+    };
+    update_quality(&ids).await?;
+    Ok(())
+}
+
+/// Rebuild the embedded transcript index from the transcripts in the database.
+#[cfg(feature = "index")]
+async fn reindex() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = match &CONFIG.import.index_dir {
+        Some(dir) => dir,
+        None => {
+            error!("index_dir is not set in the config");
+            return Ok(());
+        }
+    };
+    let mut index = power_pizza_bot::index::TranscriptIndex::open_or_create(dir)?.writer()?;
+    index.clear()?;
+    let ids = DB.get_ids::<EpisodeTranscript>().await?;
+    info!("indexing {} transcripts", ids.len());
+    for id in ids {
+        match DB.get::<EpisodeTranscript>(id).await? {
+            Some(t) => index.update(&t)?,
+            None => warn!("transcript {} disappeared while indexing", id),
+        }
+    }
+    index.commit()?;
+    info!("index rebuilt in {}", dir);
+    Ok(())
+}
+
+#[cfg(not(feature = "index"))]
+async fn reindex() -> Result<(), Box<dyn std::error::Error>> {
+    error!("ppp_import was built without the index feature");
     Ok(())
 }
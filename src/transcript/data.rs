@@ -3,6 +3,8 @@ use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
 use serde_with::{DurationMilliSeconds, DurationSeconds};
 
+use unidecode::unidecode_char;
+
 use crate::db::PPPData;
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -20,6 +22,16 @@ pub struct Segment {
     #[serde(rename = "offsets")]
     pub timestamps: FromTo,
     pub text: String,
+    /// Word-level timestamps, missing from transcripts made before they were requested.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub words: Vec<Word>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Word {
+    #[serde(rename = "offsets")]
+    pub timestamps: FromTo,
+    pub text: String,
 }
 
 #[serde_as]
@@ -30,6 +42,18 @@ pub struct SegmentAlt {
     #[serde_as(as = "DurationSeconds<f64>")]
     pub end: Duration,
     pub text: String,
+    #[serde(default)]
+    pub words: Vec<WordAlt>,
+}
+
+#[serde_as]
+#[derive(Deserialize, Serialize, Debug)]
+pub struct WordAlt {
+    #[serde_as(as = "DurationSeconds<f64>")]
+    pub start: Duration,
+    #[serde_as(as = "DurationSeconds<f64>")]
+    pub end: Duration,
+    pub word: String,
 }
 
 #[serde_as]
@@ -41,10 +65,21 @@ pub struct FromTo {
     pub to: Duration,
 }
 
+/// A segment of an `EpisodeTranscript`. `offsets` are **char** (not byte) positions in `data`,
+/// start inclusive and end exclusive.
 #[derive(Deserialize, Serialize, Debug)]
 pub struct Timestamp {
     pub time: FromTo,
     pub offsets: (usize, usize),
+    /// Who is speaking, only set for segments of diarized transcripts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub speaker: Option<String>,
+}
+
+/// Find the segment (or word) containing char offset `pos`. Offsets falling in a gap between two
+/// of them are attributed to the following one.
+pub fn timestamp_at(timestamps: &[Timestamp], pos: usize) -> Option<&Timestamp> {
+    timestamps.get(timestamps.partition_point(|t| t.offsets.1 <= pos))
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -52,6 +87,9 @@ pub struct EpisodeTranscript {
     pub episode_id: u32,
     pub data: String,
     pub timestamps: Vec<Timestamp>,
+    /// Same as `timestamps`, one per word. Empty for transcripts without word timestamps.
+    #[serde(default)]
+    pub words: Vec<Timestamp>,
 }
 
 impl PPPData for EpisodeTranscript {
@@ -60,6 +98,69 @@ impl PPPData for EpisodeTranscript {
     type IdType = u32;
 }
 
+/// The ascii transliteration of a transcript used for searching, with the position in the
+/// original text of every byte.
+pub struct NormalizedText {
+    pub text: String,
+    /// `origin[b]` is the char offset in the original text of the char that produced byte `b`
+    origin: Vec<usize>,
+    len: usize,
+}
+
+impl NormalizedText {
+    pub fn new(data: &str) -> Self {
+        let mut text = String::with_capacity(data.len());
+        let mut origin = Vec::with_capacity(data.len());
+        let mut len = 0;
+        for (i, c) in data.chars().enumerate() {
+            let t = unidecode_char(c);
+            text.push_str(t);
+            origin.extend(std::iter::repeat_n(i, t.len()));
+            len = i + 1;
+        }
+        Self { text, origin, len }
+    }
+
+    /// Map a byte position in `text` to a char offset in the original text. Positions past the
+    /// end map to the length of the original text.
+    pub fn char_offset(&self, byte: usize) -> usize {
+        self.origin.get(byte).copied().unwrap_or(self.len)
+    }
+
+    /// Map a byte range in `text` to a char range in the original text.
+    pub fn char_range(&self, start: usize, end: usize) -> (usize, usize) {
+        let s = self.char_offset(start);
+        if end > start {
+            (s, self.char_offset(end - 1) + 1)
+        } else {
+            (s, s)
+        }
+    }
+}
+
+impl EpisodeTranscript {
+    pub fn normalized(&self) -> NormalizedText {
+        NormalizedText::new(&self.data)
+    }
+
+    /// Split `data` back into the text of each segment, along with its time range. There is one
+    /// item per timestamp, segments with an end before their start have no text.
+    pub fn segments(&self) -> Vec<(&FromTo, &str)> {
+        // offsets are in chars, map them to byte positions in `data`
+        let bytes: Vec<usize> = self.data.char_indices().map(|(i, _)| i).chain(std::iter::once(self.data.len())).collect();
+        let byte_at = |c: usize| bytes.get(c).copied().unwrap_or(self.data.len());
+        self.timestamps
+            .iter()
+            .map(|t| {
+                let start = byte_at(t.offsets.0);
+                // offsets come from the database, a broken segment must not take the others down
+                let end = byte_at(t.offsets.1.max(t.offsets.0));
+                (&t.time, &self.data[start..end])
+            })
+            .collect()
+    }
+}
+
 impl From<(u32, Transcript)> for EpisodeTranscript {
     fn from(transcript: (u32, Transcript)) -> Self {
         let (episode_id, transcript) = transcript;
@@ -67,15 +168,36 @@ impl From<(u32, Transcript)> for EpisodeTranscript {
 
         let size = transcription.iter().map(|t| t.text.len() + 1).sum::<usize>();
         let mut timestamps: Vec<Timestamp> = Vec::with_capacity(transcription.len());
+        let mut words: Vec<Timestamp> = vec![];
         let mut data = String::with_capacity(size);
         let mut len = 0;
         for segment in transcription {
-            let Segment { timestamps: ts, text: t } = segment;
+            let Segment { timestamps: ts, text: t, words: ws } = segment;
             let sl = t.chars().count();
+            // words are located inside the segment text in order, the ones that can't be found
+            // (whisper sometimes tokenizes differently) are dropped
+            let mut cursor = 0;
+            for w in ws {
+                let wt = w.text.trim();
+                if wt.is_empty() {
+                    continue;
+                }
+                if let Some(b) = t[cursor..].find(wt) {
+                    let start = len + t[..cursor + b].chars().count();
+                    let wl = wt.chars().count();
+                    words.push(Timestamp {
+                        time: w.timestamps,
+                        offsets: (start, start + wl),
+                        speaker: None,
+                    });
+                    cursor += b + wt.len();
+                }
+            }
             data.push_str(&t);
             timestamps.push(Timestamp {
                 time: ts,
                 offsets: (len, len + sl),
+                speaker: None,
             });
             len += sl;
         }
@@ -83,6 +205,7 @@ impl From<(u32, Transcript)> for EpisodeTranscript {
             episode_id,
             data,
             timestamps,
+            words,
         }
     }
 }
@@ -92,12 +215,16 @@ impl From<TranscriptAlt> for Transcript {
         let TranscriptAlt { segments } = transcript;
         let mut transcription = Vec::with_capacity(segments.len());
         for segment in segments {
-            let SegmentAlt { start, end, text } = segment;
+            let SegmentAlt { start, end, text, words } = segment;
             let timestamps = FromTo {
                 from: start,
                 to: end,
             };
-            transcription.push(Segment { timestamps, text });
+            let words = words
+                .into_iter()
+                .map(|w| Word { timestamps: FromTo { from: w.start, to: w.end }, text: w.word })
+                .collect();
+            transcription.push(Segment { timestamps, text, words });
         }
         Self { transcription }
     }
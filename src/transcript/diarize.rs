@@ -0,0 +1,153 @@
+use std::{collections::HashMap, time::Duration};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DurationSeconds};
+#[allow(unused_imports)]
+use log::{debug, info, warn};
+
+use crate::config::DiarizerConfig;
+use super::data::EpisodeTranscript;
+use super::jobs::JobManagerError;
+
+/// Length of the turns made up by `Diarizer::Stub`.
+const STUB_TURN: Duration = Duration::from_secs(30);
+
+/// Output of the diarization backend: who is speaking when.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Diarization {
+    pub turns: Vec<SpeakerTurn>,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SpeakerTurn {
+    #[serde_as(as = "DurationSeconds<f64>")]
+    pub start: Duration,
+    #[serde_as(as = "DurationSeconds<f64>")]
+    pub end: Duration,
+    pub speaker: String,
+}
+
+/// Tells who is speaking when.
+///
+/// The HTTP backend receives the episode wav as the multipart field `file` and must answer with
+/// `{"turns": [{"start": 0.0, "end": 4.2, "speaker": "SPEAKER_00"}, ...]}`, times in seconds. The
+/// stub needs no backend: it makes up turns of `STUB_TURN` alternating between `SPEAKER_00` and
+/// `SPEAKER_01` over the transcript, enough to try the pipeline out.
+pub enum Diarizer {
+    Http { cli: reqwest::Client, url: String },
+    Stub,
+}
+
+impl Diarizer {
+    pub fn from_config(config: &DiarizerConfig, cli: reqwest::Client) -> Self {
+        match config {
+            DiarizerConfig::Http { url } => Self::Http { cli, url: url.clone() },
+            DiarizerConfig::Stub => Self::Stub,
+        }
+    }
+
+    /// Speaker turns of the episode in `wav`, whose transcript is `transcript`.
+    pub async fn diarize(&self, wav: &str, transcript: &EpisodeTranscript) -> Result<Diarization, JobManagerError> {
+        match self {
+            Self::Http { cli, url } => Ok(cli
+                .post(url)
+                .multipart(reqwest::multipart::Form::new().file("file", wav).await?)
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<Diarization>()
+                .await?),
+            Self::Stub => {
+                let end = transcript.timestamps.iter().map(|t| t.time.to).max().unwrap_or_default();
+                let turns = (0..)
+                    .map(|i| STUB_TURN * i)
+                    .take_while(|start| *start < end)
+                    .enumerate()
+                    .map(|(i, start)| SpeakerTurn { start, end: (start + STUB_TURN).min(end), speaker: format!("SPEAKER_{:02}", i % 2) })
+                    .collect();
+                Ok(Diarization { turns })
+            }
+        }
+    }
+
+    /// Whether turns are worth caching, made up ones aren't.
+    pub fn cached(&self) -> bool {
+        matches!(self, Self::Http { .. })
+    }
+}
+
+impl Diarization {
+    /// Label every segment of `transcript` with the speaker talking the most during it. Backend
+    /// labels are translated through `names`, labels without a mapping are kept as they are.
+    pub fn assign(&self, transcript: &mut EpisodeTranscript, names: &HashMap<String, String>) {
+        for t in transcript.timestamps.iter_mut() {
+            let mut talk: HashMap<&str, Duration> = HashMap::new();
+            for turn in self.turns.iter() {
+                let from = turn.start.max(t.time.from);
+                let to = turn.end.min(t.time.to);
+                if to > from {
+                    *talk.entry(&turn.speaker).or_default() += to - from;
+                }
+            }
+            t.speaker = talk
+                .into_iter()
+                .max_by_key(|(_, d)| *d)
+                .map(|(s, _)| names.get(s).cloned().unwrap_or_else(|| s.to_owned()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transcript::{FromTo, Timestamp};
+
+    fn secs(s: u64) -> Duration {
+        Duration::from_secs(s)
+    }
+
+    fn turn(start: u64, end: u64, speaker: &str) -> SpeakerTurn {
+        SpeakerTurn { start: secs(start), end: secs(end), speaker: speaker.to_owned() }
+    }
+
+    fn transcript(segments: &[(u64, u64)]) -> EpisodeTranscript {
+        let timestamps = segments
+            .iter()
+            .map(|(from, to)| Timestamp { time: FromTo { from: secs(*from), to: secs(*to) }, offsets: (0, 0), speaker: None })
+            .collect();
+        EpisodeTranscript { episode_id: 1, data: String::new(), timestamps, words: vec![] }
+    }
+
+    #[test]
+    fn assign_picks_the_longest_talker_and_maps_names() {
+        let d = Diarization { turns: vec![
+            turn(0, 12, "SPEAKER_00"),
+            // overlaps the end of the first turn
+            turn(8, 20, "SPEAKER_01"),
+            // a gap between 20 and 30
+            turn(30, 40, "SPEAKER_02"),
+        ] };
+        let mut t = transcript(&[(0, 10), (10, 20), (5, 14), (20, 30), (28, 40)]);
+        let names = HashMap::from([("SPEAKER_00".to_owned(), "Sio".to_owned()), ("SPEAKER_01".to_owned(), "Lorro".to_owned())]);
+        d.assign(&mut t, &names);
+        let speakers = t.timestamps.iter().map(|t| t.speaker.as_deref()).collect::<Vec<_>>();
+        assert_eq!(speakers, vec![
+            Some("Sio"),
+            Some("Lorro"),
+            // 7s of the first turn against 6s of the second
+            Some("Sio"),
+            // nobody talks in the gap
+            None,
+            // labels without a name are kept
+            Some("SPEAKER_02"),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn stub_alternates_turns_over_the_transcript() {
+        let t = transcript(&[(0, 40), (40, 65)]);
+        let d = Diarizer::Stub.diarize("missing.wav", &t).await.unwrap();
+        let turns = d.turns.iter().map(|t| (t.start.as_secs(), t.end.as_secs(), t.speaker.as_str())).collect::<Vec<_>>();
+        assert_eq!(turns, vec![(0, 30, "SPEAKER_00"), (30, 60, "SPEAKER_01"), (60, 65, "SPEAKER_00")]);
+    }
+}
@@ -0,0 +1,252 @@
+use std::fmt::Display;
+use serde::{Deserialize, Serialize};
+use unidecode::unidecode;
+#[allow(unused_imports)]
+use log::{debug, info, warn};
+
+use crate::{config::EmbedderConfig, db::PPPData};
+use super::data::{EpisodeTranscript, FromTo};
+
+/// Segments embedded together: a single segment is often a few words, too little to tell what is
+/// being talked about.
+pub const WINDOW_SEGMENTS: usize = 8;
+/// Segments between the starts of two windows, windows overlap so that a topic isn't cut in half.
+pub const WINDOW_STRIDE: usize = 4;
+/// Texts sent to the backend in a single request.
+const BATCH_SIZE: usize = 32;
+
+/// Vectors of the segment windows of an episode, stored in their own collection: they are big,
+/// and only semantic search needs them.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EpisodeEmbeddings {
+    pub episode_id: u32,
+    pub windows: Vec<EmbeddedWindow>,
+}
+
+impl PPPData for EpisodeEmbeddings {
+    const ID_KEY: &'static str = "episode_id";
+    const COLLECTION: &'static str = "embeddings";
+    type IdType = u32;
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EmbeddedWindow {
+    pub time: FromTo,
+    /// Char offsets of the window in the transcript data, see `Timestamp.offsets`.
+    pub offsets: (usize, usize),
+    /// Unit length, so that the dot product of two vectors is their cosine similarity.
+    pub vector: Vec<f32>,
+}
+
+/// Text of a window, before it goes to the backend.
+pub struct Window {
+    pub time: FromTo,
+    pub offsets: (usize, usize),
+    pub text: String,
+}
+
+impl Window {
+    /// Windows of `WINDOW_SEGMENTS` segments every `WINDOW_STRIDE` segments, the last one may be
+    /// shorter.
+    pub fn split(transcript: &EpisodeTranscript) -> Vec<Self> {
+        let segments = transcript.segments();
+        let mut windows = vec![];
+        let mut start = 0;
+        while start < segments.len() {
+            let end = (start + WINDOW_SEGMENTS).min(segments.len());
+            windows.push(Window {
+                time: FromTo { from: segments[start].0.from, to: segments[end - 1].0.to },
+                offsets: (transcript.timestamps[start].offsets.0, transcript.timestamps[end - 1].offsets.1),
+                text: segments[start..end].iter().map(|(_, s)| s.trim()).collect::<Vec<_>>().join(" "),
+            });
+            if end == segments.len() {
+                break;
+            }
+            start += WINDOW_STRIDE;
+        }
+        windows
+    }
+}
+
+/// Turns text into vectors.
+///
+/// The HTTP backend receives `{"input": ["text", ...]}` and must answer with
+/// `{"embeddings": [[0.1, ...], ...]}`, one vector per input, in the same order. The stub needs no
+/// backend: it hashes words into buckets, so it only finds windows sharing words with the query,
+/// but it is enough to try the pipeline out.
+pub enum Embedder {
+    Http { cli: reqwest::Client, url: String },
+    Stub { dimensions: usize },
+}
+
+#[derive(Serialize)]
+struct EmbedRequest<'a> {
+    input: &'a [String],
+}
+
+#[derive(Deserialize)]
+struct EmbedResponse {
+    embeddings: Vec<Vec<f32>>,
+}
+
+impl Embedder {
+    pub fn from_config(config: &EmbedderConfig, cli: reqwest::Client) -> Self {
+        match config {
+            EmbedderConfig::Http { url } => Self::Http { cli, url: url.clone() },
+            EmbedderConfig::Stub { dimensions } => Self::Stub { dimensions: *dimensions },
+        }
+    }
+
+    /// Unit length vectors of `texts`.
+    pub async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, EmbedError> {
+        let mut vectors = Vec::with_capacity(texts.len());
+        for batch in texts.chunks(BATCH_SIZE) {
+            let embeddings = match self {
+                Self::Http { cli, url } => cli
+                    .post(url)
+                    .json(&EmbedRequest { input: batch })
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json::<EmbedResponse>()
+                    .await?
+                    .embeddings,
+                Self::Stub { dimensions } => batch.iter().map(|t| stub_vector(t, *dimensions)).collect(),
+            };
+            if embeddings.len() != batch.len() {
+                return Err(EmbedError::Count { expected: batch.len(), got: embeddings.len() });
+            }
+            vectors.extend(embeddings.into_iter().map(normalize));
+        }
+        Ok(vectors)
+    }
+
+    pub async fn embed_transcript(&self, transcript: &EpisodeTranscript) -> Result<EpisodeEmbeddings, EmbedError> {
+        let windows = Window::split(transcript);
+        let texts = windows.iter().map(|w| w.text.clone()).collect::<Vec<_>>();
+        let vectors = self.embed(&texts).await?;
+        debug!("embedded {} windows of episode {}", windows.len(), transcript.episode_id);
+        Ok(EpisodeEmbeddings {
+            episode_id: transcript.episode_id,
+            windows: windows
+                .into_iter()
+                .zip(vectors)
+                .map(|(w, vector)| EmbeddedWindow { time: w.time, offsets: w.offsets, vector })
+                .collect(),
+        })
+    }
+}
+
+fn normalize(mut v: Vec<f32>) -> Vec<f32> {
+    let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        v.iter_mut().for_each(|x| *x /= norm);
+    }
+    v
+}
+
+/// Feature hashing of the words of `text`, cut to their first letters to merge inflections.
+/// The hash must not change between builds, or stored vectors won't match new queries.
+fn stub_vector(text: &str, dimensions: usize) -> Vec<f32> {
+    let mut v = vec![0.0; dimensions.max(1)];
+    for word in unidecode(text).to_lowercase().split(|c: char| !c.is_ascii_alphanumeric()).filter(|w| w.len() > 3) {
+        // FNV-1a
+        let h = word.bytes().take(6).fold(0xcbf29ce484222325u64, |h, b| (h ^ b as u64).wrapping_mul(0x100000001b3));
+        let i = (h % v.len() as u64) as usize;
+        v[i] += if h & (1 << 63) == 0 { 1.0 } else { -1.0 };
+    }
+    v
+}
+
+/// Cosine similarity of two unit length vectors, which must come from the same model.
+pub fn similarity(a: &[f32], b: &[f32]) -> Result<f32, EmbedError> {
+    if a.len() != b.len() {
+        return Err(EmbedError::Dimensions { expected: a.len(), got: b.len() });
+    }
+    Ok(a.iter().zip(b).map(|(x, y)| x * y).sum())
+}
+
+#[derive(Debug)]
+pub enum EmbedError {
+    Reqwest(reqwest::Error),
+    /// The backend answered with a different number of vectors than the texts sent.
+    Count { expected: usize, got: usize },
+    /// Two vectors of different sizes were compared, they come from different models.
+    Dimensions { expected: usize, got: usize },
+}
+
+impl Display for EmbedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Reqwest(e) => write!(f, "Reqwest error: {}", e),
+            Self::Count { expected, got } => write!(f, "expected {} embeddings, got {}", expected, got),
+            Self::Dimensions { expected, got } => write!(f, "expected vectors of {} dimensions, got {}", expected, got),
+        }
+    }
+}
+
+impl std::error::Error for EmbedError {}
+
+impl From<reqwest::Error> for EmbedError {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Reqwest(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::*;
+    use crate::transcript::Timestamp;
+
+    /// A transcript with a segment `sN` for each of `n` seconds.
+    fn transcript(n: usize) -> EpisodeTranscript {
+        let mut data = String::new();
+        let mut timestamps = vec![];
+        for i in 0..n {
+            let start = data.chars().count();
+            data.push_str(&format!("s{} ", i));
+            let time = FromTo { from: Duration::from_secs(i as u64), to: Duration::from_secs(i as u64 + 1) };
+            timestamps.push(Timestamp { time, offsets: (start, data.chars().count()), speaker: None });
+        }
+        EpisodeTranscript { episode_id: 1, data, timestamps, words: vec![] }
+    }
+
+    #[test]
+    fn stub_vectors_are_deterministic() {
+        let v = stub_vector("Parliamo di Undertale e di Deltarune", 64);
+        assert_eq!(v.len(), 64);
+        assert_eq!(v, stub_vector("parliamo di undertale, e di deltarune!", 64));
+        // short words are left out, long ones only count for their first 6 letters
+        assert_eq!(v, stub_vector("parliamone di undertale deltarunes", 64));
+        assert_ne!(v, stub_vector("parliamo di zelda", 64));
+        assert_eq!(stub_vector("", 0), vec![0.0]);
+    }
+
+    #[test]
+    fn windows_overlap_by_the_stride() {
+        let t = transcript(2 * WINDOW_SEGMENTS + 1);
+        let windows = Window::split(&t);
+        let times = windows.iter().map(|w| (w.time.from.as_secs(), w.time.to.as_secs())).collect::<Vec<_>>();
+        assert_eq!(times, vec![(0, 8), (4, 12), (8, 16), (12, 17)]);
+        assert_eq!(windows[0].text, "s0 s1 s2 s3 s4 s5 s6 s7");
+        // the last window is shorter, and ends with the transcript
+        assert_eq!(windows[3].text, "s12 s13 s14 s15 s16");
+        assert_eq!(windows[3].offsets.1, t.data.chars().count());
+        assert_eq!(windows[1].offsets.0, t.timestamps[WINDOW_STRIDE].offsets.0);
+    }
+
+    #[test]
+    fn short_transcripts_make_a_single_window() {
+        assert_eq!(Window::split(&transcript(3)).len(), 1);
+        assert_eq!(Window::split(&transcript(WINDOW_SEGMENTS)).len(), 1);
+        assert!(Window::split(&transcript(0)).is_empty());
+    }
+
+    #[test]
+    fn similarity_needs_the_same_dimensions() {
+        let a = normalize(vec![1.0, 1.0]);
+        assert!((similarity(&a, &a).unwrap() - 1.0).abs() < 1e-6);
+        assert!(matches!(similarity(&a, &[1.0, 0.0, 0.0]), Err(EmbedError::Dimensions { expected: 2, got: 3 })));
+    }
+}
@@ -0,0 +1,158 @@
+use std::{fmt::{Display, Write}, str::FromStr, time::Duration};
+
+use crate::spreaker::Episode;
+use super::data::{EpisodeTranscript, Segment, Transcript};
+
+/// Output formats a stored transcript can be rendered to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Srt,
+    WebVtt,
+    Markdown,
+    Text,
+    Json,
+}
+
+impl ExportFormat {
+    pub const ALL: [ExportFormat; 5] = [Self::Srt, Self::WebVtt, Self::Markdown, Self::Text, Self::Json];
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Srt => "srt",
+            Self::WebVtt => "vtt",
+            Self::Markdown => "md",
+            Self::Text => "txt",
+            Self::Json => "json",
+        }
+    }
+
+    /// Render `transcript` in this format. The episode, when given, is used for headers.
+    pub fn render(&self, transcript: &EpisodeTranscript, episode: Option<&Episode>) -> String {
+        // blank segments would make empty cues, which some players reject
+        let segments = transcript
+            .segments()
+            .into_iter()
+            .filter(|(_, text)| !text.trim().is_empty())
+            .collect::<Vec<_>>();
+        let mut out = String::new();
+        // unwraps safe: writing to a String never fails
+        match self {
+            Self::Srt => {
+                for (i, (time, text)) in segments.iter().enumerate() {
+                    writeln!(out, "{}\n{} --> {}\n{}\n", i + 1, format_timestamp(time.from, ','), format_timestamp(time.to, ','), text.trim()).unwrap();
+                }
+            }
+            Self::WebVtt => {
+                out.push_str("WEBVTT\n");
+                if let Some(e) = episode {
+                    writeln!(out, "NOTE {}", e.title).unwrap();
+                }
+                out.push('\n');
+                for (time, text) in segments.iter() {
+                    writeln!(out, "{} --> {}\n{}\n", format_timestamp(time.from, '.'), format_timestamp(time.to, '.'), text.trim()).unwrap();
+                }
+            }
+            Self::Markdown => {
+                match episode {
+                    Some(e) => writeln!(out, "# {}\n", e.title).unwrap(),
+                    None => writeln!(out, "# Episodio {}\n", transcript.episode_id).unwrap(),
+                }
+                for (time, text) in segments.iter() {
+                    writeln!(out, "**[{}]** {}\n", format_clock(time.from), text.trim()).unwrap();
+                }
+            }
+            Self::Text => {
+                for (_, text) in segments.iter() {
+                    writeln!(out, "{}", text.trim()).unwrap();
+                }
+            }
+            Self::Json => {
+                let transcript = Transcript {
+                    transcription: segments
+                        .into_iter()
+                        .map(|(time, text)| Segment { timestamps: time.clone(), text: text.to_owned(), words: vec![] })
+                        .collect(),
+                };
+                // unwrap safe: the transcript contains only strings and integers
+                out = serde_json::to_string_pretty(&transcript).unwrap();
+            }
+        }
+        out
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "srt" => Ok(Self::Srt),
+            "vtt" | "webvtt" => Ok(Self::WebVtt),
+            "md" | "markdown" => Ok(Self::Markdown),
+            "txt" | "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Display for ExportFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.extension())
+    }
+}
+
+/// `hh:mm:ss,mmm` as used by SRT (`sep = ','`) and WebVTT (`sep = '.'`).
+fn format_timestamp(d: Duration, sep: char) -> String {
+    format!("{}{}{:03}", format_clock(d), sep, d.subsec_millis())
+}
+
+fn format_clock(d: Duration) -> String {
+    let s = d.as_secs();
+    format!("{:02}:{:02}:{:02}", s / 3600, s / 60 % 60, s % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transcript::{FromTo, Timestamp};
+
+    fn transcript(segments: &[(u64, u64, &str)]) -> EpisodeTranscript {
+        let mut data = String::new();
+        let mut timestamps = vec![];
+        for (from, to, text) in segments {
+            let start = data.chars().count();
+            data.push_str(text);
+            let time = FromTo { from: Duration::from_millis(*from), to: Duration::from_millis(*to) };
+            timestamps.push(Timestamp { time, offsets: (start, data.chars().count()), speaker: None });
+        }
+        EpisodeTranscript { episode_id: 1, data, timestamps, words: vec![] }
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(format_timestamp(Duration::ZERO, ','), "00:00:00,000");
+        assert_eq!(format_timestamp(Duration::from_millis(59_999), '.'), "00:00:59.999");
+        assert_eq!(format_timestamp(Duration::from_millis(3_723_045), ','), "01:02:03,045");
+        assert_eq!(format_timestamp(Duration::from_millis(36_000_500), '.'), "10:00:00.500");
+        assert_eq!(format_clock(Duration::from_millis(3_599_999)), "00:59:59");
+    }
+
+    #[test]
+    fn srt_skips_blank_cues_and_numbers_the_others() {
+        let t = transcript(&[(0, 1500, " Ciao "), (1500, 2000, "  "), (2000, 3_723_045, "a tutti"), (3_723_045, 3_800_000, "")]);
+        assert_eq!(
+            ExportFormat::Srt.render(&t, None),
+            "1\n00:00:00,000 --> 00:00:01,500\nCiao\n\n2\n00:00:02,000 --> 01:02:03,045\na tutti\n\n",
+        );
+    }
+
+    #[test]
+    fn vtt_uses_dots() {
+        let t = transcript(&[(0, 1500, "Ciao"), (1500, 2000, " "), (3_600_000, 3_601_250, "a tutti")]);
+        assert_eq!(
+            ExportFormat::WebVtt.render(&t, None),
+            "WEBVTT\n\n00:00:00.000 --> 00:00:01.500\nCiao\n\n01:00:00.000 --> 01:00:01.250\na tutti\n\n",
+        );
+    }
+}
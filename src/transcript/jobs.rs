@@ -1,13 +1,15 @@
 use std::io::Write;
 use std::fmt::Display;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use log::debug;
 #[allow(unused_imports)]
 use log::{error, info, warn};
 use futures_util::stream::StreamExt;
-use reqwest::{RequestBuilder, Url};
 
 use crate::config::CONFIG;
+use crate::progress::ProgressReporter;
+use crate::throttle::Throttle;
 use crate::db::DB;
 use crate::spreaker::Episode;
 use crate::transcript::data::TranscriptAlt;
@@ -16,38 +18,76 @@ use tokio::sync::Semaphore;
 
 use tokio::task::JoinHandle;
 
+use super::diarize::{Diarization, Diarizer};
+use super::embed::{EmbedError, Embedder};
+use super::quality::{check_quality, TranscriptQuality};
 use super::data::{EpisodeTranscript, Transcript}; type JobContainer<T> = Mutex<Vec<JoinHandle<Result<T, JobManagerError>>>>;
 
 pub struct JobManager {
     cli: Arc<reqwest::Client>,
+    throttle: Arc<Throttle>,
+    progress: ProgressReporter,
     conv_sem: Arc<Semaphore>,
+    diar_sem: Arc<Semaphore>,
     tran_sem: Arc<Semaphore>,
     down_sem: Arc<Semaphore>,
     insd_sem: Arc<Semaphore>,
+    embd_sem: Arc<Semaphore>,
+    embedder: Option<Arc<Embedder>>,
+    diarizer: Option<Arc<Diarizer>>,
     conv_jobs: JobContainer<EpisodeTranscript>,
     tran_jobs: JobContainer<(u32, Transcript)>,
     down_jobs: JobContainer<u32>,
     insd_jobs: JobContainer<()>,
+    embd_jobs: JobContainer<()>,
 }
 
 impl JobManager {
     pub fn new(cli: Arc<reqwest::Client>) -> Self {
+        let progress = ProgressReporter::new("import");
+        progress.log_every(Duration::from_secs(CONFIG.download.progress_interval));
         Self {
+            embedder: CONFIG.import.embedder.as_ref().map(|c| Arc::new(Embedder::from_config(c, (*cli).clone()))),
+            diarizer: CONFIG.import.diarizer.as_ref().map(|c| Arc::new(Diarizer::from_config(c, (*cli).clone()))),
             cli,
+            throttle: Arc::new(Throttle::from_config(&CONFIG.download)),
+            progress,
             conv_sem: Arc::new(Semaphore::new(MAX_CONVERT_JOBS)),
+            diar_sem: Arc::new(Semaphore::new(MAX_DIARIZE_JOBS)),
             tran_sem: Arc::new(Semaphore::new(MAX_TRANSCRIBE_JOBS)),
             down_sem: Arc::new(Semaphore::new(MAX_DOWNLOAD_JOBS)),
             insd_sem: Arc::new(Semaphore::new(MAX_INSERT_DB_JOBS)),
+            embd_sem: Arc::new(Semaphore::new(MAX_EMBED_JOBS)),
             conv_jobs: Mutex::new(vec![]),
             tran_jobs: Mutex::new(vec![]),
             down_jobs: Mutex::new(vec![]),
             insd_jobs: Mutex::new(vec![]),
+            embd_jobs: Mutex::new(vec![]),
+        }
+    }
+
+    /// Embed a transcript already in the database, does nothing without an embedding backend.
+    pub fn run_embed(&self, id: u32) {
+        if let Some(embedder) = &self.embedder {
+            debug!("enqueuing embed job for episode {}", id);
+            let embedder = embedder.clone();
+            let sem = self.embd_sem.clone();
+            let job = async move {
+                match DB.get::<EpisodeTranscript>(id).await? {
+                    Some(t) => Self::_run_embed(&t, &embedder, sem).await,
+                    None => {
+                        warn!("transcript {} not found, can't embed it", id);
+                        Ok(())
+                    }
+                }
+            };
+            self.embd_jobs.lock().unwrap().push(tokio::spawn(job));
         }
     }
 
     pub fn run_convert(&self, id: u32, transcript: Transcript) {
         debug!("enqueuing convert job for episode {}", id);
-        let conv = Self::_run_convert(id, transcript, self.conv_sem.clone());
+        let conv = Self::_run_convert(id, transcript, self.diarizer.clone(), self.conv_sem.clone(), self.diar_sem.clone());
         let handle = tokio::spawn(conv);
         self.conv_jobs.lock().unwrap().push(handle);
     }
@@ -61,29 +101,59 @@ impl JobManager {
 
     pub fn run_download(&self, id: u32) {
         debug!("enqueuing download job for episode {}", id);
-        let down = Self::_run_download(id, self.cli.clone(), self.down_sem.clone());
+        self.progress.enqueue();
+        let down = Self::_run_download(id, self.cli.clone(), self.down_sem.clone(), self.throttle.clone(), self.progress.clone());
         let handle = tokio::spawn(down);
         self.down_jobs.lock().unwrap().push(handle);
     }
 
-    async fn _run_convert(id: u32, transcript: Transcript, sem: Arc<Semaphore>) -> Result<EpisodeTranscript, JobManagerError> {
+    async fn _run_convert(id: u32, transcript: Transcript, diarizer: Option<Arc<Diarizer>>, sem: Arc<Semaphore>, diar_sem: Arc<Semaphore>) -> Result<EpisodeTranscript, JobManagerError> {
         let _permit = sem.acquire().await.unwrap();
         info!("converting episode {}", id);
-        let transcript = (id, transcript).into();
+        let mut transcript: EpisodeTranscript = (id, transcript).into();
         drop(_permit);
+        if let Some(diarizer) = diarizer {
+            // diarization is optional: on failure the transcript is stored without speakers
+            match Self::_run_diarize(id, &diarizer, &transcript, diar_sem).await {
+                Ok(d) => d.assign(&mut transcript, &CONFIG.import.speakers),
+                Err(e) => warn!("couldn't diarize episode {}, storing it without speakers: {}", id, e),
+            }
+        }
         Ok(transcript)
     }
 
+    async fn _run_diarize(id: u32, diarizer: &Diarizer, transcript: &EpisodeTranscript, sem: Arc<Semaphore>) -> Result<Diarization, JobManagerError> {
+        let wav = format!("{}/{}.wav", CONFIG.import.wav_dir, id);
+        if !diarizer.cached() {
+            return diarizer.diarize(&wav, transcript).await;
+        }
+        let cache_f = format!("{}/speakers/{}.json", CONFIG.import.transcript_dir, id);
+        if let Ok(c) = std::fs::read_to_string(&cache_f) {
+            debug!("diarization cache found for episode {}", id);
+            return Ok(serde_json::from_str(&c)?);
+        }
+        let _permit = sem.acquire().await.unwrap();
+        info!("diarizing episode {}", id);
+        let d = diarizer.diarize(&wav, transcript).await?;
+        std::fs::create_dir_all(format!("{}/speakers", CONFIG.import.transcript_dir))?;
+        debug!("writing diarization cache: {}", cache_f);
+        serde_json::to_writer(std::fs::File::create(cache_f)?, &d)?;
+        drop(_permit);
+        Ok(d)
+    }
+
     async fn _run_transcribe(id: u32, cli: Arc<reqwest::Client>, sem: Arc<Semaphore>) -> Result<(u32, Transcript), JobManagerError> {
         let _permit = sem.acquire().await.unwrap();
         let f = format!("{}/{}.wav", CONFIG.import.wav_dir, id);
         // warn!("using file: {}", f);
-        info!("transcribing espisode {}", id);
+        // episodes transcribed before are transcribed again because of a poor transcript
+        let temperature = DB.get::<TranscriptQuality>(id).await?.map(|q| q.next_temperature()).unwrap_or(0.0);
+        info!("transcribing espisode {} at temperature {}", id, temperature);
         let t = loop {
             match cli
                 .post(CONFIG.import.transcriber_url.as_str())
                 .multipart(reqwest::multipart::Form::new()
-                    .text("temperature", "0.0")
+                    .text("temperature", temperature.to_string())
                     .text("temperature_inc", "0.0")
                     .text("response_format", "verbose_json")
                     .file("file", &f).await?
@@ -100,30 +170,43 @@ impl JobManager {
             }
         };
         let t: Transcript = t.into();
-        let cache_f = format!("{}/{}.json", CONFIG.import.transcript_dir, id);
-        debug!("writing transcript cache: {}", cache_f);
-        let cache = std::fs::File::create(cache_f)?;
-        serde_json::to_writer(cache, &t)?;
+        // the cached transcript is only replaced once this one passes `check_quality`, see `wait`
+        let attempt = attempt_cache(id);
+        let tmp = format!("{}.tmp", attempt);
+        debug!("writing transcript attempt: {}", attempt);
+        std::fs::write(&tmp, serde_json::to_vec(&t)?)?;
+        std::fs::rename(&tmp, &attempt)?;
         drop(_permit);
         Ok((id, t))
     }
     
-    async fn _run_download(id: u32, cli: Arc<reqwest::Client>, sem: Arc<Semaphore>) -> Result<u32, JobManagerError> {
+    async fn _run_download(id: u32, cli: Arc<reqwest::Client>, sem: Arc<Semaphore>, throttle: Arc<Throttle>, progress: ProgressReporter) -> Result<u32, JobManagerError> {
         let _permit = sem.acquire().await.unwrap();
+        let r = Self::_run_download_inner(id, cli, &throttle, &progress).await;
+        progress.finish(id, r.is_ok());
+        drop(_permit);
+        r
+    }
+
+    async fn _run_download_inner(id: u32, cli: Arc<reqwest::Client>, throttle: &Throttle, progress: &ProgressReporter) -> Result<u32, JobManagerError> {
         info!("downloading episode {}", id);
         let e = DB.get::<Episode>(id).await?.unwrap();
         let url = e.download_url;
+        throttle.request().await;
         let res = cli.get(&url).send().await?;
         let mp3 = format!("{}/{}.mp3", CONFIG.import.download_dir, e.id);
         debug!("download output: {}", mp3);
         let wav = format!("{}/{}.wav", CONFIG.import.wav_dir, e.id);
         debug!("wav output: {}", wav);
         if res.status().is_success() {
+            progress.start(id, 0, res.content_length());
             let mut file = std::fs::File::create(&mp3)?;
             let mut stream =  res.bytes_stream();
             while let Some(item) = stream.next().await {
                 let chunk = item?;
                 file.write_all(&chunk)?;
+                progress.advance(id, chunk.len() as u64);
+                throttle.consume(chunk.len()).await;
             }
         }
         match tokio::process::Command::new("ffmpeg")
@@ -137,15 +220,26 @@ impl JobManager {
                 error!("couldn't convert episode {} from mp3 to wav: {}", id, s);
             }
         }
-        drop(_permit);
         Ok(id)
     }
 
-    async fn _run_insert_db(e: EpisodeTranscript, sem: Arc<Semaphore>) -> Result<(), JobManagerError> {
+    async fn _run_insert_db(e: EpisodeTranscript, sem: Arc<Semaphore>, embed: Option<(Arc<Embedder>, Arc<Semaphore>)>) -> Result<(), JobManagerError> {
         let _permit = sem.acquire().await.unwrap();
         info!("inserting episode {} into database", e.episode_id);
-        DB.insert_stateless(&[e]).await?;
+        // replace the transcript of an episode transcribed again, see `check_quality`
+        DB.update_one_stateless(e.episode_id, &e).await?;
         drop(_permit);
+        if let Some((embedder, sem)) = embed {
+            Self::_run_embed(&e, &embedder, sem).await?;
+        }
+        Ok(())
+    }
+
+    async fn _run_embed(t: &EpisodeTranscript, embedder: &Embedder, sem: Arc<Semaphore>) -> Result<(), JobManagerError> {
+        let _permit = sem.acquire().await.unwrap();
+        info!("embedding episode {}", t.episode_id);
+        let e = embedder.embed_transcript(t).await?;
+        DB.update_one_stateless(t.episode_id, &e).await?;
         Ok(())
     }
 
@@ -158,13 +252,29 @@ impl JobManager {
 
         for j in self.tran_jobs.into_inner().unwrap().into_iter() {
             let (id, t) = j.await??;
-            let job = Self::_run_convert(id, t, self.conv_sem.clone());
+            let job = Self::_run_convert(id, t, self.diarizer.clone(), self.conv_sem.clone(), self.diar_sem.clone());
             self.conv_jobs.lock()?.push(tokio::spawn(job));
         }
 
+        #[cfg(feature = "index")]
+        let mut index = match &CONFIG.import.index_dir {
+            Some(dir) => Some(crate::index::TranscriptIndex::open_or_create(dir)?.writer()?),
+            None => None,
+        };
         for j in self.conv_jobs.into_inner().unwrap().into_iter() {
             let e = j.await??;
-            let job = Self::_run_insert_db(e, self.insd_sem.clone());
+            // a worse re-transcription is dropped before reaching the index, the database or the cache
+            let accepted = check_quality(&e).await?;
+            keep_attempt(e.episode_id, accepted)?;
+            if !accepted {
+                continue;
+            }
+            #[cfg(feature = "index")]
+            if let Some(index) = index.as_mut() {
+                index.update(&e)?;
+            }
+            let embed = self.embedder.clone().map(|e| (e, self.embd_sem.clone()));
+            let job = Self::_run_insert_db(e, self.insd_sem.clone(), embed);
             self.insd_jobs.lock()?.push(tokio::spawn(job));
         }
 
@@ -172,14 +282,52 @@ impl JobManager {
             j.await??;
         }
 
+        for j in self.embd_jobs.into_inner().unwrap().into_iter() {
+            j.await??;
+        }
+        #[cfg(feature = "index")]
+        if let Some(mut index) = index {
+            info!("committing transcript index");
+            index.commit()?;
+        }
+
         Ok(())
     }
 }
 
+/// Cache file of the transcript of episode `id`, read back by the importer instead of
+/// transcribing the episode again.
+fn transcript_cache(id: u32) -> String {
+    format!("{}/{}.json", CONFIG.import.transcript_dir, id)
+}
+
+/// Where a new transcript of episode `id` waits for `check_quality`: its extension keeps the
+/// importer from taking it for a cached transcript.
+fn attempt_cache(id: u32) -> String {
+    format!("{}.attempt", transcript_cache(id))
+}
+
+/// Make the transcript attempt of episode `id`, if any, its cached transcript if it was
+/// `accepted`, or drop it.
+fn keep_attempt(id: u32, accepted: bool) -> std::io::Result<()> {
+    let attempt = attempt_cache(id);
+    let r = match accepted {
+        true => std::fs::rename(&attempt, transcript_cache(id)),
+        false => std::fs::remove_file(&attempt),
+    };
+    match r {
+        // transcripts converted from the cache have no attempt
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        r => r,
+    }
+}
+
 static MAX_CONVERT_JOBS: usize = 4;
+static MAX_DIARIZE_JOBS: usize = 1;
 static MAX_TRANSCRIBE_JOBS: usize = 1;
 static MAX_DOWNLOAD_JOBS: usize = 4;
 static MAX_INSERT_DB_JOBS: usize = 4;
+static MAX_EMBED_JOBS: usize = 1;
 
 #[derive(Debug)]
 pub enum JobManagerError {
@@ -189,6 +337,9 @@ pub enum JobManagerError {
     Mongo(mongodb::error::Error),
     Mutex,
     Serde(serde_json::Error),
+    Embed(EmbedError),
+    #[cfg(feature = "index")]
+    Index(crate::index::IndexError),
 }
 
 impl Display for JobManagerError {
@@ -200,6 +351,9 @@ impl Display for JobManagerError {
             Self::Mutex => write!(f, "Mutex error"),
             Self::Mongo(e) => write!(f, "MongoDB error: {}", e),
             Self::Serde(e) => write!(f, "Serde error: {}", e),
+            Self::Embed(e) => write!(f, "Embedding error: {}", e),
+            #[cfg(feature = "index")]
+            Self::Index(e) => write!(f, "Index error: {}", e),
         }
     }
 
@@ -242,3 +396,16 @@ impl From<serde_json::Error> for JobManagerError {
         Self::Serde(e)
     }
 }
+
+impl From<EmbedError> for JobManagerError {
+    fn from(e: EmbedError) -> Self {
+        Self::Embed(e)
+    }
+}
+
+#[cfg(feature = "index")]
+impl From<crate::index::IndexError> for JobManagerError {
+    fn from(e: crate::index::IndexError) -> Self {
+        Self::Index(e)
+    }
+}
@@ -0,0 +1,260 @@
+use std::{collections::HashMap, fmt::Display, time::Duration};
+#[allow(unused_imports)]
+use log::{debug, info, warn};
+use mongodb::bson::{doc, Bson};
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DurationMilliSeconds};
+use unidecode::unidecode;
+
+use crate::{config::CONFIG, db::{PPPData, DB}};
+use super::data::{timestamp_at, EpisodeTranscript};
+
+/// Times kept for each title of an episode, the count goes on.
+const MAX_MENTION_TIMES: usize = 50;
+/// Shorter aliases would match inside too many unrelated phrases.
+const MIN_ALIAS_LENGTH: usize = 3;
+/// Longest chapter title taken as a title to look for, longer ones are sentences about a topic
+/// rather than its name.
+const MAX_CHAPTER_TITLE_WORDS: usize = 4;
+/// Chapter titles naming a part of the show rather than something talked about, normalized.
+const GENERIC_CHAPTER_TITLES: &[&str] = &[
+    "intro", "introduzione", "sigla", "apertura", "preambolo", "news", "notizie", "novita", "annunci",
+    "domande", "domande e risposte", "q&a", "posta", "mailbag", "saluti", "chiusura", "conclusione",
+    "outro", "ringraziamenti", "varie", "varie ed eventuali", "cosa abbiamo giocato", "giochi",
+    "videogiochi", "film", "serie", "libri", "consigli", "pubblicita", "sponsor",
+];
+
+/// Titles talked about in an episode, most mentioned first.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EpisodeMentions {
+    pub episode_id: u32,
+    pub mentions: Vec<Mention>,
+}
+
+impl PPPData for EpisodeMentions {
+    const ID_KEY: &'static str = "episode_id";
+    const COLLECTION: &'static str = "mentions";
+    type IdType = u32;
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Mention {
+    /// Name of the title as in the dictionary.
+    pub title: String,
+    pub count: usize,
+    /// Start of the segments the title is said in, at most `MAX_MENTION_TIMES`.
+    #[serde_as(as = "Vec<DurationMilliSeconds<u64>>")]
+    pub times: Vec<Duration>,
+}
+
+/// Game and media titles to look for in transcripts.
+///
+/// The dictionary file has a title per line, optionally followed by the ways it is transcribed,
+/// separated by `|`: `Undertale | under tale`. Lines starting with `#` are comments. The short
+/// chapter titles parsed from the descriptions are added to it, see `Episode::parse_chapters`, except the
+/// generic ones like "Intro" or "Saluti".
+pub struct MentionDictionary {
+    regex: Regex,
+    /// Title of each normalized alias.
+    titles: HashMap<String, String>,
+}
+
+impl MentionDictionary {
+    /// The dictionary in `CONFIG.import.mentions_file` plus the chapter titles in the database,
+    /// `None` if no file is configured.
+    pub async fn load() -> Result<Option<Self>, MentionsError> {
+        let path = match &CONFIG.import.mentions_file {
+            Some(p) => p,
+            None => return Ok(None),
+        };
+        let mut entries = std::fs::read_to_string(path)?
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(|l| l.split('|').map(|a| a.trim().to_owned()).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let chapters = DB.db
+            .collection::<()>("episodes")
+            .distinct("chapters.title", doc!{})
+            .await?;
+        entries.extend(chapters
+            .into_iter()
+            .filter_map(|t| match t {
+                Bson::String(t) if is_chapter_name(&t) => Some(vec![t]),
+                _ => None,
+            }));
+        Ok(Some(Self::new(entries)?))
+    }
+
+    /// `entries` are lists of a title followed by its aliases. The first entry naming an alias
+    /// gets it.
+    pub fn new(entries: Vec<Vec<String>>) -> Result<Self, MentionsError> {
+        let mut titles = HashMap::new();
+        for entry in entries {
+            let Some(title) = entry.first().filter(|t| !t.is_empty()) else {
+                continue;
+            };
+            for alias in entry.iter() {
+                let key = normalize(alias);
+                if key.len() >= MIN_ALIAS_LENGTH {
+                    titles.entry(key).or_insert_with(|| title.clone());
+                }
+            }
+        }
+        // longest first, so that "zelda breath of the wild" wins over "zelda"
+        let mut aliases = titles.keys().collect::<Vec<_>>();
+        aliases.sort_by_key(|a| std::cmp::Reverse(a.len()));
+        let pattern = aliases
+            .iter()
+            .map(|a| a.split(' ').map(regex::escape).collect::<Vec<_>>().join(r"\s+"))
+            .collect::<Vec<_>>()
+            .join("|");
+        debug!("mention dictionary of {} aliases", titles.len());
+        let regex = RegexBuilder::new(&format!(r"\b(?:{})\b", pattern))
+            .case_insensitive(true)
+            .size_limit(64 << 20)
+            .build()?;
+        Ok(Self { regex, titles })
+    }
+
+    pub fn find(&self, transcript: &EpisodeTranscript) -> EpisodeMentions {
+        let normalized = transcript.normalized();
+        let mut found: HashMap<&str, Mention> = HashMap::new();
+        // an empty dictionary has an empty regex, matching everywhere
+        if !self.titles.is_empty() {
+            for m in self.regex.find_iter(&normalized.text) {
+                let Some(title) = self.titles.get(&normalize(m.as_str())) else {
+                    continue;
+                };
+                let (start, _) = normalized.char_range(m.start(), m.end());
+                let Some(segment) = timestamp_at(&transcript.timestamps, start) else {
+                    continue;
+                };
+                let mention = found.entry(title).or_insert_with(|| Mention { title: title.clone(), count: 0, times: vec![] });
+                mention.count += 1;
+                if mention.times.len() < MAX_MENTION_TIMES && mention.times.last() != Some(&segment.time.from) {
+                    mention.times.push(segment.time.from);
+                }
+            }
+        }
+        let mut mentions = found.into_values().collect::<Vec<_>>();
+        mentions.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.title.cmp(&b.title)));
+        EpisodeMentions { episode_id: transcript.episode_id, mentions }
+    }
+}
+
+/// Whether a chapter title is the name of something talked about, worth looking for: short,
+/// capitalized and not one of `GENERIC_CHAPTER_TITLES`.
+fn is_chapter_name(title: &str) -> bool {
+    title.split_whitespace().count() <= MAX_CHAPTER_TITLE_WORDS
+        && title.chars().next().is_some_and(|c| c.is_uppercase() || c.is_ascii_digit())
+        && !GENERIC_CHAPTER_TITLES.contains(&normalize(title).trim_end_matches(['.', ':', '!', '?']))
+}
+
+/// Lowercase ASCII words separated by single spaces.
+fn normalize(s: &str) -> String {
+    unidecode(s).to_lowercase().split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Find the mentions of the transcripts of `ids` and store them.
+pub async fn update_mentions(dictionary: &MentionDictionary, ids: &[u32]) -> Result<(), MentionsError> {
+    info!("looking for mentions in {} transcripts", ids.len());
+    for id in ids {
+        match DB.get::<EpisodeTranscript>(*id).await? {
+            Some(t) => {
+                let m = dictionary.find(&t);
+                debug!("{} titles mentioned in episode {}", m.mentions.len(), id);
+                DB.update_one_stateless(*id, &m).await?;
+            }
+            None => warn!("transcript {} not found, can't look for mentions", id),
+        }
+    }
+    Ok(())
+}
+
+/// Ids of the transcripts whose mentions were never looked for.
+pub async fn missing_mentions() -> Result<Vec<u32>, MentionsError> {
+    let done = DB.get_ids::<EpisodeMentions>().await?;
+    let mut ids = DB.get_ids::<EpisodeTranscript>().await?;
+    ids.retain(|id| !done.contains(id));
+    Ok(ids)
+}
+
+#[derive(Debug)]
+pub enum MentionsError {
+    Io(std::io::Error),
+    Mongo(mongodb::error::Error),
+    Regex(regex::Error),
+}
+
+impl Display for MentionsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "IO error: {}", e),
+            Self::Mongo(e) => write!(f, "MongoDB error: {}", e),
+            Self::Regex(e) => write!(f, "Regex error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for MentionsError {}
+
+impl From<std::io::Error> for MentionsError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<mongodb::error::Error> for MentionsError {
+    fn from(e: mongodb::error::Error) -> Self {
+        Self::Mongo(e)
+    }
+}
+
+impl From<regex::Error> for MentionsError {
+    fn from(e: regex::Error) -> Self {
+        Self::Regex(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transcript::{FromTo, Timestamp};
+
+    #[test]
+    fn generic_chapters_are_no_names() {
+        for t in ["Hollow Knight", "Breath of the Wild", "Persona 5", "1917"] {
+            assert!(is_chapter_name(t), "{}", t);
+        }
+        for t in ["Intro", "Saluti", "Domande", "Domande e risposte", "Novità", "SALUTI!", "cosa abbiamo giocato", "Il gioco che vi consigliamo questa settimana"] {
+            assert!(!is_chapter_name(t), "{}", t);
+        }
+    }
+
+    #[test]
+    fn find_mentions() {
+        let dictionary = MentionDictionary::new(vec![
+            vec!["Undertale".to_owned(), "under tale".to_owned()],
+            vec!["Zelda".to_owned()],
+        ]).unwrap();
+        let segments = [(0, " Abbiamo giocato a Undertale."), (5, " Poi Zelda,"), (10, " e ancora Under  Tale.")];
+        let mut data = String::new();
+        let mut timestamps = vec![];
+        for (from, text) in segments {
+            let start = data.chars().count();
+            data.push_str(text);
+            let time = FromTo { from: Duration::from_secs(from), to: Duration::from_secs(from + 5) };
+            timestamps.push(Timestamp { time, offsets: (start, data.chars().count()), speaker: None });
+        }
+        let m = dictionary.find(&EpisodeTranscript { episode_id: 1, data, timestamps, words: vec![] });
+        assert_eq!(m.mentions.len(), 2);
+        assert_eq!(m.mentions[0].title, "Undertale");
+        assert_eq!(m.mentions[0].count, 2);
+        assert_eq!(m.mentions[0].times, vec![Duration::ZERO, Duration::from_secs(10)]);
+        assert_eq!(m.mentions[1].title, "Zelda");
+        assert_eq!(m.mentions[1].times, vec![Duration::from_secs(5)]);
+    }
+}
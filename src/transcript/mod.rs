@@ -1,6 +1,17 @@
 mod data;
 mod jobs;
+mod export;
+mod diarize;
+mod embed;
+mod mentions;
+mod summary;
+mod quality;
 
-pub use data::{EpisodeTranscript, Segment, Transcript, TranscriptAlt, Timestamp, FromTo};
+pub use data::{timestamp_at, EpisodeTranscript, NormalizedText, Segment, Transcript, TranscriptAlt, Timestamp, FromTo, Word};
 pub use jobs::{JobManager, JobManagerError};
-
+pub use export::ExportFormat;
+pub use diarize::{Diarization, Diarizer, SpeakerTurn};
+pub use embed::{similarity, EmbedError, EmbeddedWindow, Embedder, EpisodeEmbeddings, Window, WINDOW_SEGMENTS, WINDOW_STRIDE};
+pub use mentions::{missing_mentions, update_mentions, EpisodeMentions, Mention, MentionDictionary, MentionsError};
+pub use summary::{missing_summaries, update_summaries, EpisodeSummary, Provenance, SummaryChapter, SummaryError, Summarizer};
+pub use quality::{check_quality, missing_quality, retranscription_queue, update_quality, QualityIssue, TranscriptQuality};
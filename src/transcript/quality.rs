@@ -0,0 +1,389 @@
+use std::{collections::{HashMap, HashSet}, fmt::Display, time::Duration};
+use futures_util::TryStreamExt;
+#[allow(unused_imports)]
+use log::{debug, info, warn};
+use mongodb::bson::{doc, Document};
+use serde::{Deserialize, Serialize};
+use unidecode::unidecode;
+
+use crate::{config::CONFIG, db::{PPPData, DB}, spreaker::Episode};
+use super::data::{EpisodeTranscript, FromTo};
+
+/// Words of the sliding window loops are looked for in.
+const LOOP_WINDOW: usize = 30;
+/// Windows with fewer distinct words than this fraction of their length are loops: whisper
+/// repeating a phrase, within a segment or across them, rather than the hosts talking. Speech
+/// stays well above it.
+const MAX_LOOP_DISTINCT_RATIO: f32 = 0.3;
+/// Shortest loop reported, a few repeated short replies are just a conversation.
+const MIN_LOOP_DURATION: Duration = Duration::from_secs(20);
+/// Silence between segments worth reporting, shorter ones are just pauses.
+const MIN_GAP: Duration = Duration::from_secs(30);
+/// Below this rate the segments hold less text than was said. Italian speech is around 150.
+const MIN_WORDS_PER_MINUTE: f32 = 60.0;
+/// Fraction of the episode the transcript should reach, the rest is the closing music.
+const MIN_COVERAGE: f32 = 0.9;
+/// Transcriptions of an episode after which it isn't queued anymore, no matter the score.
+const MAX_TRANSCRIPTION_ATTEMPTS: u32 = 3;
+/// Temperature added to each re-transcription: sampling breaks the loops greedy decoding gets
+/// stuck in.
+const TEMPERATURE_STEP: f32 = 0.2;
+/// Chars of the looping text kept in the report.
+const MAX_LOOP_TEXT: usize = 80;
+
+/// How much a transcript can be trusted, see `TranscriptQuality::analyze`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TranscriptQuality {
+    pub episode_id: u32,
+    /// From 0, unusable, to 1.
+    pub score: f32,
+    pub words_per_minute: f32,
+    /// Fraction of the episode before the end of the last segment.
+    pub coverage: f32,
+    pub issues: Vec<QualityIssue>,
+    /// Transcriptions made of the episode, the first included.
+    pub attempts: u32,
+    /// Whether the episode waits to be transcribed again.
+    pub queued: bool,
+}
+
+impl PPPData for TranscriptQuality {
+    const ID_KEY: &'static str = "episode_id";
+    const COLLECTION: &'static str = "quality";
+    type IdType = u32;
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum QualityIssue {
+    /// `words` words with only `distinct` different ones, starting with `text`.
+    Loop { time: FromTo, words: usize, distinct: usize, text: String },
+    /// No segments in `time`.
+    Gap { time: FromTo },
+    LowWordRate,
+    ShortCoverage,
+}
+
+impl Display for QualityIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Loop { time, words, distinct, text } => write!(f, "{} words with {} distinct from {:?}: {:?}", words, distinct, time.from, text),
+            Self::Gap { time } => write!(f, "nothing from {:?} to {:?}", time.from, time.to),
+            Self::LowWordRate => write!(f, "too few words per minute"),
+            Self::ShortCoverage => write!(f, "ends too early"),
+        }
+    }
+}
+
+impl TranscriptQuality {
+    /// Look for the ways whisper fails: loops repeating the same few words, long stretches
+    /// without segments, segments with too little text for their length and transcripts ending
+    /// long before the episode, `duration` if known.
+    ///
+    /// The score starts from 1 and loses the fraction of the episode covered by loops, gaps and
+    /// the missing end, then is scaled down by the word rate when below `MIN_WORDS_PER_MINUTE`.
+    pub fn analyze(transcript: &EpisodeTranscript, duration: Option<Duration>) -> Self {
+        let segments = transcript.segments();
+        let end = segments.last().map(|(t, _)| t.to).unwrap_or_default();
+        let duration = duration.filter(|d| !d.is_zero()).unwrap_or(end).max(end);
+        let mut issues = vec![];
+
+        let texts = segments.iter().map(|(_, s)| normalize(s)).collect::<Vec<_>>();
+        // every word with the index of its segment
+        let words = texts
+            .iter()
+            .enumerate()
+            .flat_map(|(i, t)| t.split(' ').filter(|w| !w.is_empty()).map(move |w| (w, i)))
+            .collect::<Vec<_>>();
+        let mut looped = Duration::ZERO;
+        for (start, end) in loops(&words) {
+            let time = FromTo { from: segments[words[start].1].0.from, to: segments[words[end - 1].1].0.to };
+            if time.to.saturating_sub(time.from) < MIN_LOOP_DURATION {
+                continue;
+            }
+            looped += time.to.saturating_sub(time.from);
+            let loop_words = &words[start..end];
+            let distinct = loop_words.iter().map(|(w, _)| w).collect::<HashSet<_>>().len();
+            let mut text = loop_words.iter().map(|(w, _)| *w).collect::<Vec<_>>().join(" ");
+            text.truncate(MAX_LOOP_TEXT);
+            issues.push(QualityIssue::Loop { time, words: loop_words.len(), distinct, text });
+        }
+
+        let mut gaps = Duration::ZERO;
+        for w in segments.windows(2) {
+            let gap = w[1].0.from.saturating_sub(w[0].0.to);
+            if gap >= MIN_GAP {
+                gaps += gap;
+                issues.push(QualityIssue::Gap { time: FromTo { from: w[0].0.to, to: w[1].0.from } });
+            }
+        }
+
+        let words = words.len();
+        let speech = segments.iter().map(|(t, _)| t.to.saturating_sub(t.from)).sum::<Duration>();
+        let words_per_minute = match speech.is_zero() {
+            true => 0.0,
+            false => words as f32 / (speech.as_secs_f32() / 60.0),
+        };
+        if words_per_minute < MIN_WORDS_PER_MINUTE {
+            issues.push(QualityIssue::LowWordRate);
+        }
+
+        let (coverage, score) = match duration.is_zero() {
+            true => (0.0, 0.0),
+            false => {
+                let coverage = end.as_secs_f32() / duration.as_secs_f32();
+                let lost = (looped + gaps).as_secs_f32() / duration.as_secs_f32() + (1.0 - coverage);
+                let rate = (words_per_minute / MIN_WORDS_PER_MINUTE).min(1.0);
+                (coverage, ((1.0 - lost) * rate).clamp(0.0, 1.0))
+            }
+        };
+        if coverage < MIN_COVERAGE {
+            issues.push(QualityIssue::ShortCoverage);
+        }
+
+        Self { episode_id: transcript.episode_id, score, words_per_minute, coverage, issues, attempts: 1, queued: false }
+    }
+
+    /// Queue the episode for re-transcription if the score is below
+    /// `CONFIG.import.min_transcript_quality` and attempts are left.
+    fn update_queue(&mut self) {
+        self.queued = self.needs_retry(CONFIG.import.min_transcript_quality);
+    }
+
+    fn needs_retry(&self, min_score: Option<f32>) -> bool {
+        min_score.is_some_and(|m| self.score < m) && self.attempts < MAX_TRANSCRIPTION_ATTEMPTS
+    }
+
+    /// Whether a transcript scored as `self` should replace the one scored as `stored`.
+    fn replaces(&self, stored: Option<&TranscriptQuality>) -> bool {
+        stored.is_none_or(|s| self.score > s.score)
+    }
+
+    /// Temperature of the next transcription of the episode.
+    pub fn next_temperature(&self) -> f32 {
+        TEMPERATURE_STEP * self.attempts as f32
+    }
+}
+
+/// Word ranges of `words` where every window of `LOOP_WINDOW` words has too few distinct ones,
+/// see `MAX_LOOP_DISTINCT_RATIO`. Overlapping windows are merged.
+fn loops(words: &[(&str, usize)]) -> Vec<(usize, usize)> {
+    let mut ranges: Vec<(usize, usize)> = vec![];
+    if words.len() < LOOP_WINDOW {
+        return ranges;
+    }
+    let max_distinct = (LOOP_WINDOW as f32 * MAX_LOOP_DISTINCT_RATIO) as usize;
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for (i, (w, _)) in words.iter().enumerate() {
+        *counts.entry(w).or_default() += 1;
+        if i >= LOOP_WINDOW {
+            let out = words[i - LOOP_WINDOW].0;
+            match counts.get_mut(out) {
+                Some(1) => {
+                    counts.remove(out);
+                }
+                Some(n) => *n -= 1,
+                None => {}
+            }
+        }
+        if i + 1 >= LOOP_WINDOW && counts.len() < max_distinct {
+            let start = i + 1 - LOOP_WINDOW;
+            match ranges.last_mut() {
+                Some(r) if r.1 >= start => r.1 = i + 1,
+                _ => ranges.push((start, i + 1)),
+            }
+        }
+    }
+    ranges
+}
+
+/// Lowercase ASCII words separated by single spaces.
+fn normalize(s: &str) -> String {
+    unidecode(s)
+        .to_lowercase()
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Score `transcript` against the duration of its episode.
+async fn score(transcript: &EpisodeTranscript, attempts: u32) -> Result<TranscriptQuality, mongodb::error::Error> {
+    let duration = DB.get::<Episode>(transcript.episode_id).await?.map(|e| Duration::from_millis(e.duration as u64));
+    let mut q = TranscriptQuality::analyze(transcript, duration);
+    q.attempts = attempts;
+    q.update_queue();
+    Ok(q)
+}
+
+async fn store_quality(q: &TranscriptQuality) -> Result<(), mongodb::error::Error> {
+    match q.queued {
+        true => warn!("transcript {} scored {:.2}, queued for re-transcription: {}", q.episode_id, q.score, q.issues.iter().map(|i| i.to_string()).collect::<Vec<_>>().join(", ")),
+        false => debug!("transcript {} scored {:.2} with {} issues", q.episode_id, q.score, q.issues.len()),
+    }
+    DB.update_one_stateless(q.episode_id, q).await
+}
+
+/// Score a transcript just made, counting a new transcription attempt, and tell whether to store
+/// it: a re-transcription only replaces the stored transcript if it scores better, otherwise the
+/// stored score is kept along with the attempt.
+pub async fn check_quality(transcript: &EpisodeTranscript) -> Result<bool, mongodb::error::Error> {
+    let old = DB.get::<TranscriptQuality>(transcript.episode_id).await?;
+    let attempts = old.as_ref().map(|q| q.attempts).unwrap_or(0) + 1;
+    let new = score(transcript, attempts).await?;
+    let replace = new.replaces(old.as_ref());
+    let q = match old {
+        Some(mut old) if !replace => {
+            info!("new transcript {} scored {:.2}, keeping the stored one scoring {:.2}", new.episode_id, new.score, old.score);
+            old.attempts = attempts;
+            old.update_queue();
+            old
+        }
+        _ => new,
+    };
+    store_quality(&q).await?;
+    Ok(replace)
+}
+
+/// Score the stored transcripts of `ids` again, keeping their attempts.
+pub async fn update_quality(ids: &[u32]) -> Result<(), mongodb::error::Error> {
+    info!("checking the quality of {} transcripts", ids.len());
+    for id in ids {
+        let Some(t) = DB.get::<EpisodeTranscript>(*id).await? else {
+            warn!("transcript {} not found, can't check its quality", id);
+            continue;
+        };
+        let attempts = DB.get::<TranscriptQuality>(*id).await?.map(|q| q.attempts).unwrap_or(1);
+        store_quality(&score(&t, attempts).await?).await?;
+    }
+    Ok(())
+}
+
+/// Ids of the transcripts never scored.
+pub async fn missing_quality() -> Result<Vec<u32>, mongodb::error::Error> {
+    let done = DB.get_ids::<TranscriptQuality>().await?;
+    let mut ids = DB.get_ids::<EpisodeTranscript>().await?;
+    ids.retain(|id| !done.contains(id));
+    Ok(ids)
+}
+
+/// Ids of the episodes queued for re-transcription.
+pub async fn retranscription_queue() -> Result<Vec<u32>, mongodb::error::Error> {
+    let ids = DB.db
+        .collection::<Document>(TranscriptQuality::COLLECTION)
+        .find(doc!{"queued": true})
+        .projection(doc!{"_id": 0, "episode_id": 1})
+        .await?
+        .try_collect::<Vec<_>>()
+        .await?
+        .into_iter()
+        .filter_map(|d| d.get_i64("episode_id").ok().map(|id| id as u32))
+        .collect();
+    Ok(ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transcript::Timestamp;
+
+    /// A transcript of `(from, to, text)` segments, times in seconds.
+    fn transcript(segments: &[(u64, u64, String)]) -> EpisodeTranscript {
+        let mut data = String::new();
+        let mut timestamps = vec![];
+        for (from, to, text) in segments {
+            let start = data.chars().count();
+            data.push_str(text);
+            let time = FromTo { from: Duration::from_secs(*from), to: Duration::from_secs(*to) };
+            timestamps.push(Timestamp { time, offsets: (start, data.chars().count()), speaker: None });
+        }
+        EpisodeTranscript { episode_id: 1, data, timestamps, words: vec![] }
+    }
+
+    /// 5 second segments of 12 different words each, 144 words per minute.
+    fn speech(from: u64, to: u64) -> Vec<(u64, u64, String)> {
+        (from / 5..to / 5)
+            .map(|i| (i * 5, i * 5 + 5, (0..12).map(|w| format!(" parola{}", i * 12 + w)).collect()))
+            .collect()
+    }
+
+    fn loop_count(q: &TranscriptQuality) -> usize {
+        q.issues.iter().filter(|i| matches!(i, QualityIssue::Loop { .. })).count()
+    }
+
+    #[test]
+    fn clean_transcript() {
+        let q = TranscriptQuality::analyze(&transcript(&speech(0, 600)), Some(Duration::from_secs(600)));
+        assert!(q.issues.is_empty(), "{:?}", q.issues);
+        assert!(q.score > 0.99);
+    }
+
+    #[test]
+    fn alternating_loop() {
+        let mut s = speech(0, 300);
+        s.extend((60..84).map(|i| (i * 5, i * 5 + 5, if i % 2 == 0 { " Grazie.".to_owned() } else { " Grazie a tutti.".to_owned() })));
+        s.extend(speech(420, 600));
+        let q = TranscriptQuality::analyze(&transcript(&s), Some(Duration::from_secs(600)));
+        assert_eq!(loop_count(&q), 1, "{:?}", q.issues);
+        assert!(q.score < 0.85);
+    }
+
+    #[test]
+    fn loop_inside_a_segment() {
+        let mut s = speech(0, 300);
+        s.push((300, 330, " e poi".repeat(40)));
+        s.extend(speech(330, 600));
+        let q = TranscriptQuality::analyze(&transcript(&s), Some(Duration::from_secs(600)));
+        assert_eq!(loop_count(&q), 1, "{:?}", q.issues);
+    }
+
+    #[test]
+    fn short_replies_are_no_loop() {
+        let mut s = speech(0, 300);
+        s.extend([(300, 301, " Sì.".to_owned()), (301, 302, " Sì.".to_owned()), (302, 303, " Sì.".to_owned())]);
+        s.extend(speech(305, 600));
+        let q = TranscriptQuality::analyze(&transcript(&s), Some(Duration::from_secs(600)));
+        assert_eq!(loop_count(&q), 0, "{:?}", q.issues);
+    }
+
+    #[test]
+    fn gap() {
+        let mut s = speech(0, 300);
+        s.extend(speech(400, 600));
+        let q = TranscriptQuality::analyze(&transcript(&s), Some(Duration::from_secs(600)));
+        assert!(matches!(q.issues[..], [QualityIssue::Gap { ref time }] if time.from.as_secs() == 300 && time.to.as_secs() == 400), "{:?}", q.issues);
+        assert!((q.score - (1.0 - 100.0 / 600.0)).abs() < 0.01);
+    }
+
+    #[test]
+    fn low_word_rate() {
+        let s = (0..120).map(|i| (i * 5, i * 5 + 5, format!(" parola{}", i))).collect::<Vec<_>>();
+        let q = TranscriptQuality::analyze(&transcript(&s), Some(Duration::from_secs(600)));
+        assert!(q.issues.iter().any(|i| matches!(i, QualityIssue::LowWordRate)), "{:?}", q.issues);
+        assert!(q.score < 0.25);
+    }
+
+    #[test]
+    fn short_coverage() {
+        let q = TranscriptQuality::analyze(&transcript(&speech(0, 300)), Some(Duration::from_secs(600)));
+        assert!(matches!(q.issues[..], [QualityIssue::ShortCoverage]), "{:?}", q.issues);
+        assert!((q.coverage - 0.5).abs() < 0.01);
+        assert!((q.score - 0.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn queue_and_replace() {
+        let mut q = TranscriptQuality::analyze(&transcript(&speech(0, 300)), Some(Duration::from_secs(600)));
+        assert!(!q.needs_retry(None));
+        assert!(q.needs_retry(Some(0.8)));
+        assert!(!q.needs_retry(Some(0.4)));
+        q.attempts = MAX_TRANSCRIPTION_ATTEMPTS;
+        assert!(!q.needs_retry(Some(0.8)));
+
+        let better = TranscriptQuality::analyze(&transcript(&speech(0, 600)), Some(Duration::from_secs(600)));
+        assert!(better.replaces(None));
+        assert!(better.replaces(Some(&q)));
+        assert!(!q.replaces(Some(&better)));
+        assert!(!q.replaces(Some(&q)));
+    }
+}
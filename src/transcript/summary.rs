@@ -0,0 +1,375 @@
+use std::{collections::HashMap, fmt::Display, time::Duration};
+use chrono::{DateTime, Utc};
+#[allow(unused_imports)]
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use unidecode::unidecode;
+
+use crate::{config::SummarizerConfig, db::{PPPData, DB}};
+use super::data::{EpisodeTranscript, FromTo};
+
+/// Blocks of transcript are cut after about this long, at the longest pause of their last
+/// `CUT_WINDOW`: hosts tend to pause when they change topic.
+const BLOCK_DURATION: Duration = Duration::from_secs(5 * 60);
+const CUT_WINDOW: Duration = Duration::from_secs(60);
+/// Chars of each block sent to the backend, from its start.
+const MAX_BLOCK_TEXT: usize = 6000;
+/// Chars of each chapter sent to the backend to write the summary, from its start.
+const MAX_CHAPTER_TEXT: usize = 500;
+
+const CHAPTER_INSTRUCTION: &str = "Questo è un pezzo della trascrizione automatica di una puntata di un podcast di videogiochi. \
+    Rispondi solo con un titolo di al massimo sei parole che dica di cosa si parla.";
+const CONTINUATION_INSTRUCTION: &str = "Questi sono il titolo di un capitolo della trascrizione automatica di una puntata di un \
+    podcast di videogiochi e il pezzo di trascrizione che lo segue. Rispondi solo sì se il pezzo continua a parlare \
+    dello stesso argomento del capitolo, altrimenti no.";
+const SUMMARY_INSTRUCTION: &str = "Questi sono i capitoli della trascrizione automatica di una puntata di un podcast di videogiochi, \
+    ognuno con il suo inizio. Rispondi solo con un riassunto della puntata di al massimo tre frasi.";
+
+/// Summary and chapters of an episode, written by a language model from the transcript.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EpisodeSummary {
+    pub episode_id: u32,
+    pub summary: String,
+    pub chapters: Vec<SummaryChapter>,
+    pub provenance: Provenance,
+}
+
+impl PPPData for EpisodeSummary {
+    const ID_KEY: &'static str = "episode_id";
+    const COLLECTION: &'static str = "summaries";
+    type IdType = u32;
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SummaryChapter {
+    pub title: String,
+    pub time: FromTo,
+}
+
+/// Where a summary comes from, generated text should never pass for the hosts' own words.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Provenance {
+    /// `http` or `stub`.
+    pub backend: String,
+    pub model: Option<String>,
+    #[serde(with = "crate::serde::naive_datetime")]
+    pub created_at: DateTime<Utc>,
+    /// Segments of the transcript the summary was written from.
+    pub segments: usize,
+}
+
+/// Writes summaries and chapter titles.
+///
+/// The HTTP backend receives `{"model": "...", "instruction": "...", "input": "..."}`, `model`
+/// being omitted when not configured, and must answer with `{"output": "..."}`. The stub needs
+/// no backend: chapters are titled with their most frequent words, a block continues the
+/// previous chapter when it gets the same title, the summary lists them.
+pub enum Summarizer {
+    Http { cli: reqwest::Client, url: String, model: Option<String> },
+    Stub,
+}
+
+#[derive(Serialize)]
+struct SummarizeRequest<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<&'a str>,
+    instruction: &'a str,
+    input: &'a str,
+}
+
+#[derive(Deserialize)]
+struct SummarizeResponse {
+    output: String,
+}
+
+/// A part of the transcript about a single topic, hopefully.
+struct Block {
+    time: FromTo,
+    text: String,
+    segments: usize,
+}
+
+impl Summarizer {
+    pub fn from_config(config: &SummarizerConfig, cli: reqwest::Client) -> Self {
+        match config {
+            SummarizerConfig::Http { url, model } => Self::Http { cli, url: url.clone(), model: model.clone() },
+            SummarizerConfig::Stub => Self::Stub,
+        }
+    }
+
+    pub async fn summarize(&self, transcript: &EpisodeTranscript) -> Result<EpisodeSummary, SummaryError> {
+        let blocks = split_blocks(transcript);
+        if blocks.is_empty() {
+            return Err(SummaryError::Empty);
+        }
+        let mut chapters: Vec<SummaryChapter> = vec![];
+        for b in blocks.iter() {
+            // blocks are cut by time, a topic can go on for several of them
+            if let Some(c) = chapters.last_mut() {
+                if self.continues(c, b).await? {
+                    c.time.to = b.time.to;
+                    continue;
+                }
+            }
+            let title = match self {
+                Self::Http { .. } => self.complete(CHAPTER_INSTRUCTION, &truncate(&b.text, MAX_BLOCK_TEXT)).await?,
+                Self::Stub => stub_title(&b.text),
+            };
+            let title = title.trim().trim_matches('"').to_owned();
+            match chapters.last_mut() {
+                Some(c) if c.title.eq_ignore_ascii_case(&title) => c.time.to = b.time.to,
+                _ => chapters.push(SummaryChapter { title, time: b.time.clone() }),
+            }
+        }
+        let summary = match self {
+            Self::Http { .. } => {
+                let input = chapters
+                    .iter()
+                    .map(|c| {
+                        let text = blocks
+                            .iter()
+                            .filter(|b| b.time.from >= c.time.from && b.time.to <= c.time.to)
+                            .map(|b| b.text.as_str())
+                            .collect::<Vec<_>>()
+                            .join(" ");
+                        format!("[{}s] {}: {}", c.time.from.as_secs(), c.title, truncate(&text, MAX_CHAPTER_TEXT))
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                self.complete(SUMMARY_INSTRUCTION, &input).await?.trim().to_owned()
+            }
+            Self::Stub => format!("Si parla di: {}.", chapters.iter().map(|c| c.title.as_str()).collect::<Vec<_>>().join("; ")),
+        };
+        debug!("summarized episode {} in {} chapters", transcript.episode_id, chapters.len());
+        Ok(EpisodeSummary {
+            episode_id: transcript.episode_id,
+            summary,
+            chapters,
+            provenance: self.provenance(blocks.iter().map(|b| b.segments).sum()),
+        })
+    }
+
+    /// Whether `block` is still about the topic of `chapter`, the one before it.
+    async fn continues(&self, chapter: &SummaryChapter, block: &Block) -> Result<bool, SummaryError> {
+        match self {
+            Self::Http { .. } => {
+                let input = format!("Capitolo: {}\n\nPezzo: {}", chapter.title, truncate(&block.text, MAX_BLOCK_TEXT));
+                let answer = self.complete(CONTINUATION_INSTRUCTION, &input).await?;
+                Ok(unidecode(answer.trim()).to_lowercase().starts_with("si"))
+            }
+            Self::Stub => Ok(stub_title(&block.text).eq_ignore_ascii_case(&chapter.title)),
+        }
+    }
+
+    async fn complete(&self, instruction: &str, input: &str) -> Result<String, SummaryError> {
+        match self {
+            Self::Http { cli, url, model } => Ok(cli
+                .post(url)
+                .json(&SummarizeRequest { model: model.as_deref(), instruction, input })
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<SummarizeResponse>()
+                .await?
+                .output),
+            Self::Stub => Ok(stub_title(input)),
+        }
+    }
+
+    fn provenance(&self, segments: usize) -> Provenance {
+        let (backend, model) = match self {
+            Self::Http { model, .. } => ("http", model.clone()),
+            Self::Stub => ("stub", None),
+        };
+        Provenance { backend: backend.to_owned(), model, created_at: Utc::now(), segments }
+    }
+}
+
+/// Blocks of about `BLOCK_DURATION`, see `CUT_WINDOW`.
+fn split_blocks(transcript: &EpisodeTranscript) -> Vec<Block> {
+    let segments = transcript.segments();
+    let mut blocks = vec![];
+    let mut start = 0;
+    while start < segments.len() {
+        let block_start = segments[start].0.from;
+        // the first segment ending past the block duration, or the last one
+        let mut end = start;
+        while end + 1 < segments.len() && segments[end].0.to < block_start + BLOCK_DURATION {
+            end += 1;
+        }
+        // cut after the segment followed by the longest pause in the window
+        if end + 1 < segments.len() {
+            let window_start = segments[end].0.to.saturating_sub(CUT_WINDOW);
+            end = (start..=end)
+                .filter(|i| segments[*i].0.to >= window_start)
+                .max_by_key(|i| segments[i + 1].0.from.saturating_sub(segments[*i].0.to))
+                .unwrap_or(end);
+        }
+        blocks.push(Block {
+            time: FromTo { from: block_start, to: segments[end].0.to },
+            text: segments[start..=end].iter().map(|(_, s)| s.trim()).collect::<Vec<_>>().join(" "),
+            segments: end + 1 - start,
+        });
+        start = end + 1;
+    }
+    blocks
+}
+
+/// Summarize the transcripts of `ids` and store the summaries. Failures are logged and skipped,
+/// the summary is tried again on the next run.
+pub async fn update_summaries(summarizer: &Summarizer, ids: &[u32]) -> Result<(), SummaryError> {
+    info!("summarizing {} transcripts", ids.len());
+    for id in ids {
+        let Some(t) = DB.get::<EpisodeTranscript>(*id).await? else {
+            warn!("transcript {} not found, can't summarize it", id);
+            continue;
+        };
+        match summarizer.summarize(&t).await {
+            Ok(s) => DB.update_one_stateless(*id, &s).await?,
+            Err(e) => warn!("can't summarize episode {}: {}", id, e),
+        }
+    }
+    Ok(())
+}
+
+/// Ids of the transcripts never summarized.
+pub async fn missing_summaries() -> Result<Vec<u32>, SummaryError> {
+    let done = DB.get_ids::<EpisodeSummary>().await?;
+    let mut ids = DB.get_ids::<EpisodeTranscript>().await?;
+    ids.retain(|id| !done.contains(id));
+    Ok(ids)
+}
+
+fn truncate(s: &str, chars: usize) -> String {
+    s.chars().take(chars).collect()
+}
+
+/// The three most frequent long words of `text`, capitalized.
+fn stub_title(text: &str) -> String {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for w in unidecode(text).to_lowercase().split(|c: char| !c.is_ascii_alphanumeric()).filter(|w| w.len() > 5) {
+        *counts.entry(w.to_owned()).or_default() += 1;
+    }
+    let mut words = counts.into_iter().collect::<Vec<_>>();
+    words.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    let title = words
+        .into_iter()
+        .take(3)
+        .map(|(w, _)| w[..1].to_uppercase() + &w[1..])
+        .collect::<Vec<_>>()
+        .join(", ");
+    match title.is_empty() {
+        true => "Chiacchiere".to_owned(),
+        false => title,
+    }
+}
+
+#[derive(Debug)]
+pub enum SummaryError {
+    Reqwest(reqwest::Error),
+    Mongo(mongodb::error::Error),
+    /// The transcript has no segments.
+    Empty,
+}
+
+impl Display for SummaryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Reqwest(e) => write!(f, "Reqwest error: {}", e),
+            Self::Mongo(e) => write!(f, "MongoDB error: {}", e),
+            Self::Empty => write!(f, "empty transcript"),
+        }
+    }
+}
+
+impl std::error::Error for SummaryError {}
+
+impl From<reqwest::Error> for SummaryError {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Reqwest(e)
+    }
+}
+
+impl From<mongodb::error::Error> for SummaryError {
+    fn from(e: mongodb::error::Error) -> Self {
+        Self::Mongo(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transcript::Timestamp;
+
+    /// A transcript of `(from, to, text)` segments, times in seconds.
+    fn transcript(segments: &[(u64, u64, &str)]) -> EpisodeTranscript {
+        let mut data = String::new();
+        let mut timestamps = vec![];
+        for (from, to, text) in segments {
+            let start = data.chars().count();
+            data.push_str(text);
+            let time = FromTo { from: Duration::from_secs(*from), to: Duration::from_secs(*to) };
+            timestamps.push(Timestamp { time, offsets: (start, data.chars().count()), speaker: None });
+        }
+        EpisodeTranscript { episode_id: 1, data, timestamps, words: vec![] }
+    }
+
+    /// 10 second segments from `from` to `to` about `topic`, with a 1 second pause after each.
+    fn talk(from: u64, to: u64, topic: &str) -> Vec<(u64, u64, String)> {
+        (from / 10..to / 10).map(|i| (i * 10, i * 10 + 9, format!(" Parliamo di {} e ancora {}.", topic, topic))).collect()
+    }
+
+    fn segments(s: &[(u64, u64, String)]) -> Vec<(u64, u64, &str)> {
+        s.iter().map(|(from, to, text)| (*from, *to, text.as_str())).collect()
+    }
+
+    #[test]
+    fn blocks_cut_at_longest_pause() {
+        // a 5 second pause at 4:40, inside the window before 5:00
+        let mut s = talk(0, 280, "metroid");
+        s.extend(talk(285, 900, "castlevania").into_iter().map(|(from, to, t)| (from + 5, to + 5, t)));
+        let t = transcript(&segments(&s));
+        let blocks = split_blocks(&t);
+        assert_eq!(blocks[0].time.to, Duration::from_secs(279));
+        assert_eq!(blocks[1].time.from, Duration::from_secs(285));
+        assert_eq!(blocks.iter().map(|b| b.segments).sum::<usize>(), t.timestamps.len());
+        for b in blocks.iter() {
+            assert!(b.segments > 0 && !b.text.is_empty());
+            assert!(b.time.to - b.time.from <= BLOCK_DURATION + Duration::from_secs(10));
+        }
+        for w in blocks.windows(2) {
+            assert!(w[0].time.to < w[1].time.from);
+        }
+    }
+
+    #[test]
+    fn short_transcript_is_one_block() {
+        let t = transcript(&[(0, 5, " Ciao a tutti."), (6, 10, " Benvenuti.")]);
+        let blocks = split_blocks(&t);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].segments, 2);
+        assert_eq!(blocks[0].text, "Ciao a tutti. Benvenuti.");
+        assert!(split_blocks(&transcript(&[])).is_empty());
+    }
+
+    #[tokio::test]
+    async fn stub_summary() {
+        let mut s = talk(0, 900, "metroid");
+        s.extend(talk(900, 1200, "castlevania"));
+        let summary = Summarizer::Stub.summarize(&transcript(&segments(&s))).await.unwrap();
+        // three blocks about metroid are one chapter
+        assert_eq!(summary.chapters.len(), 2, "{:?}", summary.chapters);
+        assert_eq!(summary.chapters[0].title, "Metroid, Ancora, Parliamo");
+        assert_eq!(summary.chapters[0].time.from, Duration::ZERO);
+        assert_eq!(summary.chapters[1].time.from, summary.chapters[0].time.to + Duration::from_secs(1));
+        assert_eq!(summary.chapters[1].time.to, Duration::from_secs(1199));
+        assert_eq!(summary.summary, "Si parla di: Metroid, Ancora, Parliamo; Castlevania, Ancora, Parliamo.");
+        assert_eq!(summary.provenance.backend, "stub");
+        assert_eq!(summary.provenance.segments, s.len());
+    }
+
+    #[tokio::test]
+    async fn empty_transcript() {
+        assert!(matches!(Summarizer::Stub.summarize(&transcript(&[])).await, Err(SummaryError::Empty)));
+    }
+}